@@ -9,6 +9,11 @@ use acpica_sys::*;
 pub use ty::*;
 use core::sync::atomic::Ordering::SeqCst;
 
+use crate::mem::{PhysAddr, PhysRange, VirtAddr};
+use crate::mem::kernel_mem_map::{direct_map_to_phys, is_direct_mapped, is_kernel_mem_map_ready, vmalloc_range, with_kernel_mem_map};
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+use crate::mem::virt::{CacheMode, ioremap, iounmap};
+
 //acpica_sys::gen_osl!(crate::acpi::ca::osl::ty);
 
 pub mod ty {
@@ -43,12 +48,12 @@ pub extern "C" fn AcpiOsTerminate() -> ACPI_STATUS {
  */
 #[no_mangle]
 pub extern "C" fn AcpiOsGetRootPointer() -> ACPI_PHYSICAL_ADDRESS {
-	let root_ptr = crate::acpi::ACPI_ROOT_PTR.load(SeqCst).ptr();
+	let root_addr = crate::acpi::ACPI_ROOT_PTR.load(SeqCst);
 	
-	if root_ptr.is_null() {
+	if root_addr == PhysAddr::ZERO {
 		panic!("ACPI root pointer is null");
 	} else {
-		root_ptr as ACPI_PHYSICAL_ADDRESS
+		root_addr.as_u64()
 	}
 }
 
@@ -167,20 +172,64 @@ pub extern "C" fn AcpiOsFree(memory: *mut c_void) {
 
 #[no_mangle]
 pub extern "C" fn AcpiOsMapMemory(phys_addr: ACPI_PHYSICAL_ADDRESS, length: ACPI_SIZE) -> *mut c_void {
-//	unimplemented!()
-	// TODO: THIS IS VERY WRONG AND OBVIOUSLY WRONG REMOVE THIS
-	phys_addr as _
+	let phys_addr = PhysAddr::new(phys_addr as usize);
+	
+	// ACPI tables and NVS are in the direct map, anything else is device memory
+	if !is_kernel_mem_map_ready() || is_range_direct_mapped(phys_addr, length as usize) {
+		return phys_addr.to_virt().as_mut_ptr();
+	}
+	
+	match ioremap(phys_addr, length as usize, CacheMode::Uncached) {
+		Ok(mapping) => mapping.leak().as_ptr() as *mut c_void,
+		Err(_) => ptr::null_mut(),
+	}
 }
 
 #[no_mangle]
 pub extern "C" fn AcpiOsUnmapMemory(logical_addr: *mut c_void, size: ACPI_SIZE) {
-//	unimplemented!()
-	// TODO: THIS IS VERY WRONG AND OBVIOUSLY WRONG REMOVE THIS
+	// The direct map is never torn down
+	let is_io = VirtAddr::try_new(logical_addr as usize)
+		.map_or(false, |addr| vmalloc_range().contains(addr));
+	if is_io {
+		unsafe {
+			iounmap(ptr::NonNull::new_unchecked(logical_addr as *mut u8));
+		}
+	}
 }
 
 #[no_mangle]
-pub extern "C" fn AcpiOsGetPhysicalAddress(logical_addr: *mut c_void, physical_addr: ACPI_PHYSICAL_ADDRESS) -> ACPI_STATUS {
-	unimplemented!()
+pub extern "C" fn AcpiOsGetPhysicalAddress(logical_addr: *mut c_void, physical_addr: &mut ACPI_PHYSICAL_ADDRESS) -> ACPI_STATUS {
+	// Only addresses handed out by AcpiOsMapMemory can be translated
+	let phys = VirtAddr::try_new(logical_addr as usize).and_then(|addr| {
+		if vmalloc_range().contains(addr) {
+			with_kernel_mem_map(|map| map.page_table().translate(addr.as_usize()))
+				.map(|translation| PhysAddr::new(translation.phys_addr))
+		} else {
+			direct_map_to_phys(addr)
+		}
+	});
+	
+	match phys {
+		Some(phys) => {
+			*physical_addr = phys.as_u64();
+			AE_OK
+		},
+		None => AE_BAD_PARAMETER,
+	}
+}
+
+/// Whether every page of the range is mapped in the direct map
+fn is_range_direct_mapped(phys_addr: PhysAddr, size: usize) -> bool {
+	let range = PhysRange::with_size(phys_addr, size.max(1)).align_outward(BASE_PAGE_SIZE);
+	
+	let mut page = range.start;
+	while page < range.end {
+		if !is_direct_mapped(page) {
+			return false;
+		}
+		page += BASE_PAGE_SIZE;
+	}
+	true
 }
 
 /*
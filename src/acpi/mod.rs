@@ -1,14 +1,14 @@
-use core::ptr;
 use core::ptr::NonNull;
 use core::sync::atomic::AtomicUsize;
 
 use atomic::Atomic;
 use static_assertions::*;
 
-use crate::mem::Phys;
+use crate::mem::{Phys, PhysAddr};
 
 pub mod ca;
 
-pub static ACPI_ROOT_PTR: Atomic<Phys<*const cty::c_void>> = Atomic::new(Phys::new(ptr::null()));
+/// Physical address of the RSDP, as found in the UEFI configuration table
+pub static ACPI_ROOT_PTR: Atomic<PhysAddr> = Atomic::new(PhysAddr::ZERO);
 
 assert_eq_size!(Option<Phys<NonNull<*const cty::c_void>>>, usize);
@@ -0,0 +1,65 @@
+//! Local APIC in xAPIC mode, i.e. accessed through its mmio registers
+
+use core::ptr;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering::*;
+
+use crate::arch::x86_64::msr::IA32_APIC_BASE;
+use crate::mem::PhysAddr;
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+use crate::mem::virt::{CacheMode, ioremap, IoRemapErr};
+
+pub const LAPIC_ID_REG: usize = 0x20;
+pub const LAPIC_VERSION_REG: usize = 0x30;
+pub const LAPIC_EOI_REG: usize = 0xb0;
+pub const LAPIC_SPURIOUS_REG: usize = 0xf0;
+
+/// Size of the register page
+const LAPIC_REGS_SIZE: usize = 0x400;
+
+/// The uncached mapping of the local APIC registers, see [`map_lapic_regs`]
+static LAPIC_REGS: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+/// Physical address of the registers of this cpu's local APIC
+#[inline]
+pub unsafe fn lapic_base() -> PhysAddr {
+	// The low bits of IA32_APIC_BASE are flags
+	PhysAddr::new_truncate(IA32_APIC_BASE.read_raw() as usize).align_down(BASE_PAGE_SIZE)
+}
+
+/// Maps the local APIC registers uncached. Every cpu sees its own local
+/// APIC at the same address, so this is only done once on the bootstrap processor.
+pub unsafe fn map_lapic_regs() -> Result<(), IoRemapErr> {
+	let regs = ioremap(lapic_base(), LAPIC_REGS_SIZE, CacheMode::Uncached)?;
+	LAPIC_REGS.store(regs.leak().as_ptr(), Release);
+	Ok(())
+}
+
+#[inline]
+pub unsafe fn read_lapic_reg(reg: usize) -> u32 {
+	lapic_reg_ptr(reg).read_volatile()
+}
+
+#[inline]
+pub unsafe fn write_lapic_reg(reg: usize, val: u32) {
+	lapic_reg_ptr(reg).write_volatile(val);
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+/// Does nothing before [`map_lapic_regs`], until then interrupts are disabled
+/// and only exceptions, which need no EOI, can arrive.
+#[inline]
+pub unsafe fn lapic_eoi() {
+	if !LAPIC_REGS.load(Acquire).is_null() {
+		write_lapic_reg(LAPIC_EOI_REG, 0x00);
+	}
+}
+
+#[inline]
+unsafe fn lapic_reg_ptr(reg: usize) -> *mut u32 {
+	debug_assert!(reg < LAPIC_REGS_SIZE, "Invalid local APIC register {:#x}", reg);
+	
+	let regs = LAPIC_REGS.load(Acquire);
+	assert!(!regs.is_null(), "Local APIC registers are not mapped yet");
+	regs.add(reg) as *mut u32
+}
@@ -1 +1,43 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
 
+/// Whether the cpu supports 1 GiB pages
+#[inline]
+pub fn has_1gib_pages() -> bool {
+	let ext_feature_cpuid = unsafe { __cpuid(0x8000_0001) };
+	(ext_feature_cpuid.edx & (0x1 << 26)) != 0
+}
+
+/// Whether the cpu supports five-level paging (57 bit virtual addresses)
+#[inline]
+pub fn has_la57() -> bool {
+	let ext_feature_cpuid = unsafe { __cpuid_count(7, 0) };
+	(ext_feature_cpuid.ecx & (0x1 << 16)) != 0
+}
+
+/// Whether the cpu supports supervisor mode execution prevention
+#[inline]
+pub fn has_smep() -> bool {
+	let ext_feature_cpuid = unsafe { __cpuid_count(7, 0) };
+	(ext_feature_cpuid.ebx & (0x1 << 7)) != 0
+}
+
+/// Whether the cpu supports supervisor mode access prevention
+#[inline]
+pub fn has_smap() -> bool {
+	let ext_feature_cpuid = unsafe { __cpuid_count(7, 0) };
+	(ext_feature_cpuid.ebx & (0x1 << 20)) != 0
+}
+
+/// Whether the cpu has the RDRAND instruction
+#[inline]
+pub fn has_rdrand() -> bool {
+	let feature_cpuid = unsafe { __cpuid(1) };
+	(feature_cpuid.ecx & (0x1 << 30)) != 0
+}
+
+/// Whether the cpu has the RDSEED instruction
+#[inline]
+pub fn has_rdseed() -> bool {
+	let ext_feature_cpuid = unsafe { __cpuid_count(7, 0) };
+	(ext_feature_cpuid.ebx & (0x1 << 18)) != 0
+}
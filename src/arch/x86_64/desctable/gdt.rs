@@ -17,6 +17,14 @@ impl LongCodeDataSegmentDesc {
 		])
 	}
 	
+	/// A flat 4 GiB 32-bit code segment for compatibility mode
+	pub const fn new_compat_code(p: u8, dpl: u8) -> Self {
+		Self([
+			0x0000_ffff,
+			(0b1 << 23) | (0b1 << 22) | (0b0 << 21) | (0b1111 << 16) | (p as u32 & 0b1) << 15 | (dpl as u32 & 0b11) << 13 | (0b11 << 11) | (0b10 << 8),
+		])
+	}
+	
 	/// Note that dpl is ignored in long mode and thus kinda
 	/// unncessary but we just set it out of principle.
 	/// Plus compat mode does actually require it IIRC.
@@ -43,13 +51,66 @@ impl LongNullSegmentDesc {
 pub struct LongSystemSegmentDesc([u32; 4]);
 
 impl LongSystemSegmentDesc {
-	/// Note: `base_addr` must be in canonical form
-	pub const fn new(base_addr: u64, limit: u32, g: u8, avl: u8, p: u8, dpl: u8, desc_type: u8) -> Self {
+	/// Note: `base_addr` must be in canonical form for the current
+	/// paging mode (see [`crate::mem::virt::is_canonical`])
+	pub fn new(base_addr: u64, limit: u32, g: u8, avl: u8, p: u8, dpl: u8, desc_type: u8) -> Self {
+		assert!(crate::mem::virt::is_canonical(base_addr as usize), "system segment base {:#x} is not canonical", base_addr);
+		
 		Self([
 			((base_addr & 0xffff) as u32) << 16 | (limit as u32 & 0xffff),
-			(base_addr & 0xff00_0000) as u32 | (g as u32 & 0b1) << 23 | (avl as u32 & 0b1) << 20 | (limit as u32 & 0x000f_0000) | (p as u32 & 0b1) << 15 | (dpl as u32 & 0b11) << 13 | (0b0 << 12) | (desc_type as u32 & 0b1111) << 8 | ((base_addr >> 8) as u32 & 0xffff),
+			(base_addr & 0xff00_0000) as u32 | (g as u32 & 0b1) << 23 | (avl as u32 & 0b1) << 20 | (limit as u32 & 0x000f_0000) | (p as u32 & 0b1) << 15 | (dpl as u32 & 0b11) << 13 | (0b0 << 12) | (desc_type as u32 & 0b1111) << 8 | ((base_addr >> 16) as u32 & 0xff),
 			(base_addr >> 32) as u32,
 			0x0000_0000,
 		])
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	/// Reassembles the base address the cpu loads from the descriptor
+	fn decode_base(desc: &LongSystemSegmentDesc) -> u64 {
+		let [lo, hi, upper, _] = desc.0;
+		(upper as u64) << 32 | (hi & 0xff00_0000) as u64 | ((hi & 0xff) as u64) << 16 | (lo >> 16) as u64
+	}
+	
+	fn decode_type(desc: &LongSystemSegmentDesc) -> u8 {
+		((desc.0[1] >> 8) & 0b1111) as u8
+	}
+	
+	fn decode_p(desc: &LongSystemSegmentDesc) -> u8 {
+		((desc.0[1] >> 15) & 0b1) as u8
+	}
+	
+	#[test]
+	fn system_segment_fields_roundtrip() {
+		let bases = [0x0, 0x1234_5678, 0x00ab_cdef, 0x7fff_ffff_f000, 0xffff_8000_0000_0000, 0xffff_ffff_fedc_ba98];
+		for &base in bases.iter() {
+			for &(p, desc_type) in [(0b1, 0x9), (0b1, 0xb), (0b0, 0x2)].iter() {
+				let desc = LongSystemSegmentDesc::new(base, 0x67, 0b0, 0b0, p, 0x0, desc_type);
+				
+				assert_eq!(decode_base(&desc), base, "base of {:x?}", desc);
+				assert_eq!(decode_type(&desc), desc_type, "type of {:x?}", desc);
+				assert_eq!(decode_p(&desc), p, "P of {:x?}", desc);
+			}
+		}
+	}
+	
+	#[test]
+	fn system_segment_limit_and_reserved_bits() {
+		let desc = LongSystemSegmentDesc::new(0xffff_8000_dead_b000, 0xa_bcde, 0b1, 0b1, 0b1, 0x3, 0x9);
+		
+		assert_eq!(desc.0[0] & 0xffff, 0xbcde);
+		assert_eq!((desc.0[1] >> 16) & 0xf, 0xa);
+		// The S bit must be clear for system segments and the upper type field of the 16 byte form must be zero
+		assert_eq!((desc.0[1] >> 12) & 0b1, 0);
+		assert_eq!(desc.0[3], 0);
+	}
+	
+	#[test]
+	#[should_panic]
+	fn system_segment_rejects_non_canonical_base() {
+		LongSystemSegmentDesc::new(0x8000_0000_0000, 0x67, 0b0, 0b0, 0b1, 0x0, 0x9);
+	}
+}
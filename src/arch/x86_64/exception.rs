@@ -0,0 +1,290 @@
+//! Exception handlers that need the full register state of the interrupted code
+
+use core::arch::asm;
+use core::fmt;
+use core::fmt::Write;
+
+use crate::arch::x86_64::paging::{read_cr2, read_cr3};
+use crate::mem::virt::{FaultErr, handle_page_fault, PageFault, SMAP_ENABLED, user_copy_fixup};
+use crate::proc::overflowed_stack_owner;
+use crate::tty_writer;
+
+/// General purpose registers as saved by the exception entry stubs
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct SavedRegs {
+	pub r15: u64,
+	pub r14: u64,
+	pub r13: u64,
+	pub r12: u64,
+	pub r11: u64,
+	pub r10: u64,
+	pub r9: u64,
+	pub r8: u64,
+	pub rbp: u64,
+	pub rdi: u64,
+	pub rsi: u64,
+	pub rdx: u64,
+	pub rcx: u64,
+	pub rbx: u64,
+	pub rax: u64,
+}
+
+/// Stack contents of an exception with an error code after the entry stub saved all registers
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct ExceptionFrame {
+	pub regs: SavedRegs,
+	pub error_code: u64,
+	pub rip: u64,
+	pub cs: u64,
+	pub rflags: u64,
+	pub rsp: u64,
+	pub ss: u64,
+}
+
+impl ExceptionFrame {
+	/// Whether the exception happened in user mode
+	#[inline]
+	pub fn is_user(&self) -> bool {
+		(self.cs & 0b11) == 0b11
+	}
+}
+
+impl fmt::Display for ExceptionFrame {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let r = &self.regs;
+		writeln!(f, "rax {:016x} rbx {:016x} rcx {:016x} rdx {:016x}", r.rax, r.rbx, r.rcx, r.rdx)?;
+		writeln!(f, "rsi {:016x} rdi {:016x} rbp {:016x} rsp {:016x}", r.rsi, r.rdi, r.rbp, self.rsp)?;
+		writeln!(f, " r8 {:016x}  r9 {:016x} r10 {:016x} r11 {:016x}", r.r8, r.r9, r.r10, r.r11)?;
+		writeln!(f, "r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}", r.r12, r.r13, r.r14, r.r15)?;
+		writeln!(f, "rip {:016x} rflags {:08x} cs {:04x} ss {:04x} err {:x}", self.rip, self.rflags, self.cs, self.ss, self.error_code)?;
+		write!(f, "cr2 {:016x} cr3 {:016x}", read_cr2(), read_cr3())
+	}
+}
+
+/// Error code of a page fault
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct PageFaultErrCode(pub u64);
+
+impl PageFaultErrCode {
+	/// The page was present, so this is a protection violation
+	pub const PRESENT: Self = Self(0x1 << 0);
+	/// Caused by a write (otherwise a read)
+	pub const WRITE: Self = Self(0x1 << 1);
+	/// Caused by user mode code
+	pub const USER: Self = Self(0x1 << 2);
+	/// A reserved bit was set in a paging structure
+	pub const RESERVED_BIT: Self = Self(0x1 << 3);
+	/// Caused by an instruction fetch
+	pub const INSTRUCTION_FETCH: Self = Self(0x1 << 4);
+	/// Protection key violation
+	pub const PROTECTION_KEY: Self = Self(0x1 << 5);
+	/// Shadow stack access
+	pub const SHADOW_STACK: Self = Self(0x1 << 6);
+	
+	#[inline]
+	pub const fn contains(self, other: Self) -> bool {
+		(self.0 & other.0) == other.0
+	}
+}
+
+impl fmt::Debug for PageFaultErrCode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		const NAMES: [(PageFaultErrCode, &str); 7] = [
+			(PageFaultErrCode::PRESENT, "P"),
+			(PageFaultErrCode::WRITE, "W"),
+			(PageFaultErrCode::USER, "U"),
+			(PageFaultErrCode::RESERVED_BIT, "RSVD"),
+			(PageFaultErrCode::INSTRUCTION_FETCH, "I"),
+			(PageFaultErrCode::PROTECTION_KEY, "PK"),
+			(PageFaultErrCode::SHADOW_STACK, "SS"),
+		];
+		
+		write!(f, "{:#x} [", self.0)?;
+		let mut first = true;
+		for (flag, name) in NAMES {
+			if self.contains(flag) {
+				write!(f, "{}{}", if first { "" } else { "|" }, name)?;
+				first = false;
+			}
+		}
+		write!(f, "]")
+	}
+}
+
+impl fmt::Display for PageFaultErrCode {
+	/// e.g. "user write to present page"
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mode = if self.contains(Self::USER) { "user" } else { "kernel" };
+		let access = if self.contains(Self::INSTRUCTION_FETCH) {
+			"instruction fetch from"
+		} else if self.contains(Self::WRITE) {
+			"write to"
+		} else {
+			"read from"
+		};
+		let page = if self.contains(Self::PRESENT) { "present" } else { "non-present" };
+		
+		write!(f, "{} {} {} page", mode, access, page)?;
+		if self.contains(Self::RESERVED_BIT) {
+			write!(f, " (reserved bit set)")?;
+		}
+		if self.contains(Self::PROTECTION_KEY) {
+			write!(f, " (protection key)")?;
+		}
+		if self.contains(Self::SHADOW_STACK) {
+			write!(f, " (shadow stack)")?;
+		}
+		Ok(())
+	}
+}
+
+/// #PF entry, saves all registers and calls [`page_fault_handler`]
+#[naked]
+pub unsafe extern "sysv64" fn isr_pf() {
+	asm!(
+		"push rax",
+		"push rbx",
+		"push rcx",
+		"push rdx",
+		"push rsi",
+		"push rdi",
+		"push rbp",
+		"push  r8",
+		"push  r9",
+		"push r10",
+		"push r11",
+		"push r12",
+		"push r13",
+		"push r14",
+		"push r15",
+		
+		// The cpu pushed six qwords (with the error code) onto a 16 byte aligned
+		// stack and we pushed fifteen, so realign for the call
+		"mov rdi, rsp",
+		"sub rsp, 8",
+		"cld",
+		
+		// Faults in the user copy come with RFLAGS.AC set, don't let the handler touch
+		// user memory because of that (iretq restores it for the resumed copy)
+		"cmp byte ptr [rip + {smap_enabled}], 0",
+		"je 2f",
+		"clac",
+		"2:",
+		
+		"call {handler}",
+		"add rsp, 8",
+		
+		"pop r15",
+		"pop r14",
+		"pop r13",
+		"pop r12",
+		"pop r11",
+		"pop r10",
+		"pop  r9",
+		"pop  r8",
+		"pop rbp",
+		"pop rdi",
+		"pop rsi",
+		"pop rdx",
+		"pop rcx",
+		"pop rbx",
+		"pop rax",
+		
+		// Pop the error code
+		"add rsp, 8",
+		"iretq",
+		
+		handler = sym page_fault_handler,
+		smap_enabled = sym SMAP_ENABLED,
+		options(noreturn),
+	);
+}
+
+extern "sysv64" fn page_fault_handler(frame: &mut ExceptionFrame) {
+	let fault = PageFault {
+		addr: read_cr2(),
+		err: PageFaultErrCode(frame.error_code),
+		rip: frame.rip as usize,
+	};
+	
+	if let Err(err) = handle_page_fault(&fault) {
+		// Failed user copies are reported to their caller instead
+		if fault.is_user_addr() && !frame.is_user() {
+			if let Some(fixup) = user_copy_fixup(fault.rip) {
+				frame.rip = fixup as u64;
+				return;
+			}
+		}
+		
+		let _ = writeln!(tty_writer(), "Unhandled page fault at {:#018x}: {} ({:?}), {:?}", fault.addr, fault.err, fault.err, err);
+		if let FaultErr::KernelImage(section) = err {
+			let _ = writeln!(tty_writer(), "The access violates the permissions of the kernel's {:?} section", section);
+		}
+		if let Some(owner) = overflowed_stack_owner(fault.addr) {
+			let _ = writeln!(tty_writer(), "Kernel stack overflow of {}", owner);
+		}
+		let _ = writeln!(tty_writer(), "{}", frame);
+		
+		// TODO: Kill the faulting process instead once there are processes
+		if frame.is_user() {
+			panic!("Unhandled user page fault at {:#x} (rip {:#x}): {:?}", fault.addr, fault.rip, err);
+		} else {
+			panic!("Unhandled kernel page fault at {:#x} (rip {:#x}): {:?}", fault.addr, fault.rip, err);
+		}
+	}
+}
+
+/// #DF entry, runs on its own IST stack (see [`crate::arch::x86_64::interrupt::init_double_fault_stack`]),
+/// saves all registers and calls [`double_fault_handler`]. A #DF is an abort, so this never returns.
+#[naked]
+pub unsafe extern "sysv64" fn isr_df() {
+	asm!(
+		"push rax",
+		"push rbx",
+		"push rcx",
+		"push rdx",
+		"push rsi",
+		"push rdi",
+		"push rbp",
+		"push  r8",
+		"push  r9",
+		"push r10",
+		"push r11",
+		"push r12",
+		"push r13",
+		"push r14",
+		"push r15",
+		
+		// Same alignment as in isr_pf, the error code is always 0
+		"mov rdi, rsp",
+		"sub rsp, 8",
+		"cld",
+		"call {handler}",
+		"ud2",
+		
+		handler = sym double_fault_handler,
+		options(noreturn),
+	);
+}
+
+extern "sysv64" fn double_fault_handler(frame: &ExceptionFrame) -> ! {
+	// A kernel stack overflow faults on the guard page, and as the #PF frame
+	// can't be pushed onto the overflowed stack either that ends up here
+	let cr2 = read_cr2();
+	let overflowed = overflowed_stack_owner(cr2)
+		.or_else(|| overflowed_stack_owner((frame.rsp as usize).wrapping_sub(8)));
+	
+	let _ = writeln!(tty_writer(), "Double fault");
+	if let Some(owner) = overflowed {
+		let _ = writeln!(tty_writer(), "Kernel stack overflow of {}", owner);
+	}
+	let _ = writeln!(tty_writer(), "{}", frame);
+	
+	match overflowed {
+		Some(owner) => panic!("Kernel stack overflow of {} (rip {:#x}, cr2 {:#x})", owner, frame.rip, cr2),
+		None => panic!("Double fault (rip {:#x})", frame.rip),
+	}
+}
@@ -4,6 +4,24 @@ use core::mem::MaybeUninit;
 
 use crate::{LongIdtDesc, PseudoDesc, SegmentSel, SegmentSelTI, tty, tty_writer};
 use crate::arch::x86_64::desctable::LongSegmentDescType;
+use crate::arch::x86_64::exception::{isr_df, isr_pf};
+use crate::cpu::current_cpu_uid;
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+use crate::mem::virt::VmallocErr;
+use crate::proc::{KernelStack, StackOwner};
+
+/// GDT index of the long mode TSS descriptor
+pub const TSS_GDT_IDX: u16 = 5;
+
+/// IST entry (1-based) the #DF handler runs on, so it still works after a kernel stack overflow
+const DOUBLE_FAULT_IST: u8 = 1;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4 * BASE_PAGE_SIZE;
+
+/// What the #DF handler runs on until [`init_double_fault_stack`] (only on the bootstrap processor)
+static mut EARLY_DOUBLE_FAULT_STACK: EarlyStack = EarlyStack([0; BASE_PAGE_SIZE]);
+
+#[repr(C, align(16))]
+struct EarlyStack([u8; BASE_PAGE_SIZE]);
 
 pub static mut IDT_BUF: [LongIdtDesc; 256] = [LongIdtDesc::null(); 256];
 pub static mut TSS_BUF: [u32; 68] = [0; 68];
@@ -17,10 +35,12 @@ pub unsafe fn setup_idt() {
 			0x08 => isr_df as u64,
 			0x0c => isr_ss as u64,
 			0x0d => isr_gp as u64,
+			0x0e => isr_pf as u64,
 			0x42 => isr_serial_com13 as u64,
 			_ => isr_other as u64,
 		};
-		let desc = LongIdtDesc::new(isr_entry_ptr, gdt_cs_sel, 0, LongSegmentDescType::InterruptGate64, 0x0, true);
+		let ist = if i == 0x08 { DOUBLE_FAULT_IST } else { 0 };
+		let desc = LongIdtDesc::new(isr_entry_ptr, gdt_cs_sel, ist, LongSegmentDescType::InterruptGate64, 0x0, true);
 		IDT_BUF[i] = desc;
 	}
 	
@@ -48,22 +68,56 @@ pub unsafe fn setup_interrupt_tss() {
 	TSS_BUF[22] = 0x0;
 	TSS_BUF[23] = 0x0;
 	
-	// Zero out rsp1/rsp2
+	// Zero out rsp0/rsp1/rsp2, rsp0 is set by set_kernel_entry_stack
+	TSS_BUF[1] = 0x0;
+	TSS_BUF[2] = 0x0;
 	TSS_BUF[3] = 0x0;
 	TSS_BUF[4] = 0x0;
 	TSS_BUF[5] = 0x0;
 	TSS_BUF[6] = 0x0;
 	
 	// Zero out IST entries
-	// TODO: Maybe make use of ISTs in the future
 	for ist in TSS_BUF[8..22].array_chunks_mut::<2>() {
 		*ist = [0x0, 0x0];
 	}
 	
+	// The IDT already sends #DF to its IST entry, init_double_fault_stack replaces this one
+	let early_stack_top = EARLY_DOUBLE_FAULT_STACK.0.as_ptr_range().end;
+	write_tss_u64(9 + 2 * (DOUBLE_FAULT_IST as usize - 1), early_stack_top as u64);
+	
 	// Disable IO Permission Bitmap by setting the IOPB address offset
 	// to u16::MAX, causing it to *definitely* fall outside the TSS segment limit
 	// (https://stackoverflow.com/questions/54876039/creating-a-proper-task-state-segment-tss-structure-with-and-without-an-io-bitm/54876040#54876040)
 	TSS_BUF[24] = (0xffff_ffff << 16) | 0x0000_0000;
+	
+	// Load the TSS, which marks its (available) descriptor busy
+	asm!(
+		"ltr {:x}",
+		in(reg) SegmentSel::new(0, SegmentSelTI::GDT, TSS_GDT_IDX).to_raw(),
+		options(nostack),
+	);
+}
+
+/// Allocates the stack the #DF handler of the current cpu runs on.
+/// Needs the kernel address space as the stack is allocated with vmalloc.
+pub unsafe fn init_double_fault_stack() -> Result<(), VmallocErr> {
+	let stack = KernelStack::new(DOUBLE_FAULT_STACK_SIZE, StackOwner::DoubleFault(current_cpu_uid()))?;
+	write_tss_u64(9 + 2 * (DOUBLE_FAULT_IST as usize - 1), stack.leak() as u64);
+	Ok(())
+}
+
+/// Makes interrupts and exceptions from user mode run on the stack with the given top,
+/// which is the kernel stack of the thread about to run user code
+pub unsafe fn set_kernel_entry_stack(stack_top: *const u8) {
+	// rsp0
+	write_tss_u64(1, stack_top as u64);
+}
+
+/// Writes a 64 bit field of the TSS, which are only 4 byte aligned
+#[inline]
+unsafe fn write_tss_u64(idx: usize, val: u64) {
+	TSS_BUF[idx] = val as u32;
+	TSS_BUF[idx + 1] = (val >> 32) as u32;
 }
 
 #[inline(always)]
@@ -119,11 +173,10 @@ macro_rules! isr_entry {
 			);
 			
 			unsafe extern "sysv64" fn _inner() {
-				use crate::arch::x86_64::msr::Msr;
+				use crate::arch::x86_64::apic::lapic_eoi;
 				
 				// Signal EOI to lapic
-				let lapic_base = Msr::<u64>::from_nr(0x0000_001b).read() & 0xf_ffff_ffff_f000;
-				((lapic_base+0xb0) as *mut u32).write_volatile(0x00);
+				lapic_eoi();
 				
 //				let _ = writeln!(tty_writer(), "> IN ISR: {}", $id);
 				if $ec {
@@ -155,7 +208,6 @@ macro_rules! isr_entry {
 
 isr_entry!(isr_other => echo_handler("#<other>"); false);
 isr_entry!(isr_bp => echo_handler("#bp"); false);
-isr_entry!(isr_df => echo_handler("#df"); false);
 isr_entry!(isr_ss => echo_handler("#ss"); false);
 isr_entry!(isr_gp => echo_handler("#gp"); true);
 
@@ -3,7 +3,8 @@ use core::mem::MaybeUninit;
 
 use bitfield::bitfield;
 
-use crate::mem::Phys;
+use crate::mem::PhysAddr;
+use crate::mem::virt::{CacheMode, ioremap, IoMapping, IoRemapErr};
 
 // TODO: This whole architecture is still kinda bad
 //  since R/W is not specified per IO APIC register
@@ -31,8 +32,7 @@ pub struct IoApicDesc {
 	/// 
 	/// Must be 16 byte aligned and be somwhere in
 	/// the range of `0xfec0_0000` to `0xfec1_0000` (excl.)
-	pub regs: Phys<*mut u128>,
-	//pub regs: IoApicRegs,
+	pub base: PhysAddr,
 	
 	/// The nr of the base Global System Interrupt that
 	/// is mapped to this IO APICs first interrupt
@@ -40,27 +40,37 @@ pub struct IoApicDesc {
 	pub base_gsi: u32,
 }
 
-impl IoApicDesc {
+/// Size of the IOREGSEL and IOWIN register window
+const IO_APIC_REGS_SIZE: usize = 0x20;
+
+/// An IO APIC with its registers mapped uncached
+pub struct IoApic {
+	pub desc: IoApicDesc,
+	regs: IoMapping,
+}
+
+impl IoApic {
+	pub fn new(desc: IoApicDesc) -> Result<Self, IoRemapErr> {
+		let regs = ioremap(desc.base, IO_APIC_REGS_SIZE, CacheMode::Uncached)?;
+		Ok(Self {desc, regs})
+	}
+	
 	#[inline]
 	pub unsafe fn write_reg<V: IoRegVal>(&self, reg: IoReg<V, impl IoWritable>, val: V) {
-		let base = self.regs.ptr() as *mut u32;
-		
 		// Write IOREGSEL
-		base.write_volatile(reg.offset() as u32);
+		self.regs.write::<u32>(0x00, reg.offset() as u32);
 		
 		// Write IOWIN
-		base.byte_offset(0x10).write_volatile(val.into_raw());
+		self.regs.write::<u32>(0x10, val.into_raw());
 	}
 	
 	#[inline]
 	pub unsafe fn read_reg<V: IoRegVal>(&self, reg: IoReg<V, impl IoReadable>) -> V {
-		let base = self.regs.ptr() as *mut u32;
-		
 		// Write IOREGSEL
-		base.write_volatile(reg.offset() as u32);
+		self.regs.write::<u32>(0x00, reg.offset() as u32);
 		
-		// Write IOWIN
-		V::from_raw(base.byte_offset(0x10).read_volatile())
+		// Read IOWIN
+		V::from_raw(self.regs.read::<u32>(0x10))
 	}
 	
 	pub unsafe fn write_redir(&self, idx: u8, entry: IoApicRedTblVal) {
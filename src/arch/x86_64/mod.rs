@@ -6,3 +6,6 @@ pub mod apic;
 pub mod ioapic;
 pub mod pic;
 pub mod cpuid;
+pub mod paging;
+pub mod exception;
+pub mod rng;
@@ -6,8 +6,11 @@ pub const STAR: Msr = Msr::from_nr(0xC000_0081);
 pub const LSTAR: Msr = Msr::from_nr(0xC000_0082);
 pub const CSTAR: Msr = Msr::from_nr(0xC000_0083);
 pub const SFMASK: Msr = Msr::from_nr(0xC000_0084);
+/// GS base swapped in by `swapgs`
+pub const KERNEL_GS_BASE: Msr = Msr::from_nr(0xC000_0102);
 
 pub const IA32_APIC_BASE: Msr = Msr::from_nr(0x0000_001b); // TODO: Is this the right addr?
+pub const IA32_PAT: Msr = Msr::from_nr(0x0000_0277);
 
 #[derive(Copy, Clone)]
 pub struct Msr<T: MsrData = u64>(u32, PhantomData<*const T>);
@@ -0,0 +1,206 @@
+use core::arch::asm;
+
+use crate::arch::x86_64::cpuid::{has_smap, has_smep};
+use crate::arch::x86_64::msr::IA32_PAT;
+
+/// GDT index of the 32-bit kernel code segment, only used to
+/// pass through compatibility mode in [`switch_paging_mode`]
+pub const KERNEL_COMPAT_CS_GDT_IDX: u16 = 7;
+
+const KERNEL_CS_SEL: u16 = 1 << 3;
+const KERNEL_COMPAT_CS_SEL: u16 = KERNEL_COMPAT_CS_GDT_IDX << 3;
+
+const CR0_WP: usize = 0x1 << 16;
+
+const CR4_LA57: usize = 0x1 << 12;
+const CR4_PCIDE: usize = 0x1 << 17;
+const CR4_SMEP: usize = 0x1 << 20;
+const CR4_SMAP: usize = 0x1 << 21;
+
+/// PAT entries PA0 to PA7: WB, WT, UC-, UC, WC, WP, UC-, UC.
+/// The first four are the power-on defaults, so mappings selecting their type with
+/// PWT and PCD only mean the same before and after [`init_pat`].
+const PAT_VALUE: u64 = 0x00_07_05_01_00_07_04_06;
+
+static mut SWITCH_SAVED_RSP: u64 = 0;
+/// Far pointer (m16:32) back to long mode in [`switch_paging_mode`]
+static mut SWITCH_FAR_PTR: u64 = 0;
+
+/// The linear address that caused the last page fault
+#[inline(always)]
+pub fn read_cr2() -> usize {
+	let cr2: usize;
+	unsafe {
+		asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+	}
+	cr2
+}
+
+/// Programs the PAT with the memory types [`crate::mem::virt::CacheMode`] relies on.
+/// Must be called on every cpu before it uses a write combining mapping.
+pub unsafe fn init_pat() {
+	// Nothing maps with the PAT bit yet, so the entries
+	// being changed can't be cached with their old type
+	asm!("wbinvd", options(nostack, preserves_flags));
+	IA32_PAT.write(PAT_VALUE);
+	asm!("wbinvd", options(nostack, preserves_flags));
+	write_cr3(read_cr3());
+}
+
+#[inline(always)]
+pub fn read_cr0() -> usize {
+	let cr0: usize;
+	unsafe {
+		asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+	}
+	cr0
+}
+
+#[inline(always)]
+pub unsafe fn write_cr0(cr0: usize) {
+	asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+}
+
+/// Makes the cpu honour read only pages in supervisor mode too (CR0.WP)
+#[inline]
+pub unsafe fn enable_write_protect() {
+	write_cr0(read_cr0() | CR0_WP);
+}
+
+#[inline(always)]
+pub fn read_cr3() -> usize {
+	let cr3: usize;
+	unsafe {
+		asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+	}
+	cr3
+}
+
+#[inline(always)]
+pub unsafe fn write_cr3(cr3: usize) {
+	asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+}
+
+#[inline(always)]
+pub fn read_cr4() -> usize {
+	let cr4: usize;
+	unsafe {
+		asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+	}
+	cr4
+}
+
+#[inline(always)]
+pub unsafe fn write_cr4(cr4: usize) {
+	asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+}
+
+/// Enables SMEP and SMAP as far as the cpu supports them, returns whether SMAP is enabled.
+/// 
+/// Afterwards the kernel faults when executing user pages, and when accessing
+/// them while RFLAGS.AC is clear.
+pub unsafe fn enable_smep_smap() -> bool {
+	let mut cr4 = read_cr4();
+	if has_smep() {
+		cr4 |= CR4_SMEP;
+	}
+	if has_smap() {
+		cr4 |= CR4_SMAP;
+	}
+	write_cr4(cr4);
+	
+	(cr4 & CR4_SMAP) != 0
+}
+
+/// Whether five-level paging is currently active on this cpu
+#[inline]
+pub fn is_la57_active() -> bool {
+	(read_cr4() & CR4_LA57) != 0
+}
+
+/// Switches between four- and five-level paging and loads the new root table.
+/// 
+/// CR4.LA57 can only be changed while paging is disabled, which in turn is only
+/// possible outside of 64-bit mode. So this briefly drops to compatibility mode,
+/// disables paging, flips LA57, loads cr3 and enables paging again.
+/// 
+/// This function must be called through its identity mapping (see
+/// [`crate::mem::kaslr::image_virt_to_phys`]), which has to exist in both the old and
+/// the new page table. `root_addr` must lie below 4 GiB and the kernel GDT (with the
+/// segment at [`KERNEL_COMPAT_CS_GDT_IDX`]) must be loaded. PCIDs are disabled by this.
+#[naked]
+pub unsafe extern "sysv64" fn switch_paging_mode(root_addr: u64, enable_la57: u64) {
+	asm!(
+		// Save callee-saved registers, the upper halves of all
+		// registers are undefined after returning from compat mode
+		"push %rbx",
+		"push %rbp",
+		"push %r12",
+		"push %r13",
+		"push %r14",
+		"push %r15",
+		"pushfq",
+		"cli",
+		"mov %rsp, {saved_rsp}(%rip)",
+		
+		// Paging can't be disabled with PCIDs enabled
+		"mov %cr4, %rax",
+		"btr ${pcide_bit}, %rax",
+		"mov %rax, %cr4",
+		
+		// Far pointer for the way back, built here as compat mode has
+		// no rip relative addressing and the image is position independent
+		"lea 3f(%rip), %rax",
+		"lea {far_ptr}(%rip), %rbx",
+		"movl %eax, (%rbx)",
+		"movw ${kernel_cs}, 4(%rbx)",
+		
+		// Far jump to compat mode (jmp m16:32)
+		"lea 2f(%rip), %rax",
+		"sub $8, %rsp",
+		"movl %eax, (%rsp)",
+		"movw ${compat_cs}, 4(%rsp)",
+		"ljmpl *(%rsp)",
+		
+		".code32",
+		"2:",
+		// Disable paging, which also deactivates long mode
+		"mov %cr0, %eax",
+		"and $0x7fffffff, %eax",
+		"mov %eax, %cr0",
+		
+		// Flip LA57 and load the new root table
+		"mov %cr4, %eax",
+		"and ${not_la57}, %eax",
+		"shl $12, %esi",
+		"or %esi, %eax",
+		"mov %eax, %cr4",
+		"mov %edi, %cr3",
+		
+		// Enable paging again, reactivating long mode (EFER.LME is still set)
+		"mov %cr0, %eax",
+		"or $0x80000000, %eax",
+		"mov %eax, %cr0",
+		"ljmpl *(%ebx)",
+		
+		".code64",
+		"3:",
+		"mov {saved_rsp}(%rip), %rsp",
+		"popfq",
+		"pop %r15",
+		"pop %r14",
+		"pop %r13",
+		"pop %r12",
+		"pop %rbp",
+		"pop %rbx",
+		"ret",
+		
+		saved_rsp = sym SWITCH_SAVED_RSP,
+		far_ptr = sym SWITCH_FAR_PTR,
+		compat_cs = const KERNEL_COMPAT_CS_SEL,
+		kernel_cs = const KERNEL_CS_SEL,
+		pcide_bit = const CR4_PCIDE.trailing_zeros(),
+		not_la57 = const !CR4_LA57 as u32,
+		options(att_syntax, noreturn),
+	);
+}
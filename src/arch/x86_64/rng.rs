@@ -0,0 +1,54 @@
+//! Hardware random number generators
+
+use core::arch::asm;
+
+use crate::arch::x86_64::cpuid::{has_rdrand, has_rdseed};
+
+/// How often an instruction is retried when it runs out of entropy
+const RETRIES: usize = 10;
+
+/// A random number from RDSEED, or RDRAND if that is not available.
+/// `None` if the cpu has neither or they kept running out of entropy.
+pub fn hw_random_u64() -> Option<u64> {
+	if has_rdseed() {
+		if let Some(val) = retry(rdseed) {
+			return Some(val);
+		}
+	}
+	if has_rdrand() {
+		if let Some(val) = retry(rdrand) {
+			return Some(val);
+		}
+	}
+	None
+}
+
+fn retry(f: unsafe fn() -> Option<u64>) -> Option<u64> {
+	for _ in 0..RETRIES {
+		if let Some(val) = unsafe { f() } {
+			return Some(val);
+		}
+		unsafe {
+			asm!("pause", options(nomem, nostack));
+		}
+	}
+	None
+}
+
+/// Must only be used if [`has_rdseed`]
+#[inline]
+unsafe fn rdseed() -> Option<u64> {
+	let val: u64;
+	let ok: u8;
+	asm!("rdseed {}", "setc {}", out(reg) val, out(reg_byte) ok, options(nomem, nostack));
+	if ok != 0 { Some(val) } else { None }
+}
+
+/// Must only be used if [`has_rdrand`]
+#[inline]
+unsafe fn rdrand() -> Option<u64> {
+	let val: u64;
+	let ok: u8;
+	asm!("rdrand {}", "setc {}", out(reg) val, out(reg_byte) ok, options(nomem, nostack));
+	if ok != 0 { Some(val) } else { None }
+}
@@ -1,4 +1,16 @@
+use core::arch::x86_64::__cpuid;
 
 // System-wide unique id of (virtual) cpus
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[repr(transparent)]
 pub struct CpuUid(pub u16);
+
+/// Returns the uid of the cpu executing this code
+/// 
+/// For now this is simply the initial local apic id of the cpu
+// TODO: Read this from per-cpu data once we have it
+#[inline]
+pub fn current_cpu_uid() -> CpuUid {
+	let feature_cpuid = unsafe { __cpuid(1) };
+	CpuUid((feature_cpuid.ebx >> 24) as u16)
+}
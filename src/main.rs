@@ -1,5 +1,6 @@
-#![no_std]
-#![no_main]
+// The buck allocator et al. are unit tested on the host, so allow building with std for tests
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 #![feature(lang_items)]
 #![feature(alloc_error_handler)]
@@ -45,12 +46,14 @@ use uefi_rs::ResultExt;
 use acpica_sys::{ACPI_MADT_INTERRUPT_OVERRIDE, ACPI_MADT_INTERRUPT_SOURCE, ACPI_MADT_IO_APIC, ACPI_MADT_LOCAL_APIC, ACPI_MADT_PCAT_COMPAT, ACPI_SUBTABLE_HEADER, ACPI_TABLE_DESC, ACPI_TABLE_HEADER, ACPI_TABLE_MADT, AcpiIsFailure, AcpiMadtType_ACPI_MADT_TYPE_INTERRUPT_OVERRIDE, AcpiMadtType_ACPI_MADT_TYPE_IO_APIC, AcpiMadtType_ACPI_MADT_TYPE_LOCAL_APIC};
 
 use crate::arch::x86_64::desctable::{LongCodeDataSegmentDesc, LongIdtDesc, LongNullSegmentDesc, LongSystemSegmentDesc, PseudoDesc, SegmentSel, SegmentSelTI};
+use crate::arch::x86_64::apic;
 use crate::arch::x86_64::interrupt;
-use crate::arch::x86_64::ioapic::{DeliveryMode, DestinationMode, IoApicDesc, IoApicRedTblVal, IrqPolarity, TriggerMode};
+use crate::arch::x86_64::ioapic::{DeliveryMode, DestinationMode, IoApic, IoApicDesc, IoApicRedTblVal, IrqPolarity, TriggerMode};
 use crate::arch::x86_64::interrupt::{cli, sti};
-use crate::arch::x86_64::msr::Msr;
+use crate::arch::x86_64::paging;
 use crate::global_alloc::KernelGlobalAlloc;
-use crate::mem::Phys;
+use crate::mem::PhysAddr;
+use crate::proc::{KernelStack, ThreadId, ThreadKind};
 use crate::tty::{read_tty_char, tty_writer};
 use crate::uefi::boot_alloc::{self, UefiBootAlloc};
 
@@ -66,7 +69,7 @@ pub mod tty;
 pub mod dis;
 pub mod cpu;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static KERNEL_GLOBAL_ALLOC: KernelGlobalAlloc = KernelGlobalAlloc::new();
 
 pub static GLOBAL_UEFI_STDOUT_PTR: AtomicUsize = AtomicUsize::new(0x0);
@@ -79,10 +82,51 @@ pub static LOW_HEX_FMT_FN: fn(&usize, &mut core::fmt::Formatter<'_>) -> core::fm
 #[cfg(target_arch = "x86_64")]
 #[no_mangle]
 pub extern "sysv64" fn start0(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi_rs::prelude::SystemTable<uefi_rs::table::Boot>) -> ! {
+	// Read kernel boot options from the uefi load options
+	uefi::boot_opts::parse_boot_opts(sys_table_uefi.boot_services(), bootloader_handle_uefi);
+	
+	// Move the kernel to a (random) address in the higher half and continue there
+	if let Err(err) = unsafe { mem::kaslr::relocate_kernel(sys_table_uefi.boot_services()) } {
+		// Nothing has been changed then, so the kernel can simply keep running at its link address
+		let _ = writeln!(sys_table_uefi.stdout(), "Failed to relocate the kernel ({:?}), running it at its link address instead", err);
+	}
+	let relocated_main = unsafe {
+		transmute::<usize, extern "sysv64" fn(uefi_rs::Handle, uefi_rs::prelude::SystemTable<uefi_rs::table::Boot>) -> !>(
+			mem::kaslr::image_phys_to_virt(kernel_main as usize)
+		)
+	};
+	relocated_main(bootloader_handle_uefi, sys_table_uefi)
+}
+
+/// The kernel's entry point in the relocated image, see [`start0`]
+extern "sysv64" fn kernel_main(bootloader_handle_uefi: uefi_rs::Handle, sys_table_uefi: uefi_rs::prelude::SystemTable<uefi_rs::table::Boot>) -> ! {
 	// Init the kernel on the bootstrap processor
 	// TODO: Init and start all other APs
 	init_kernel(bootloader_handle_uefi, sys_table_uefi);
 	
+	// Leave the firmware's stack for good, see kernel_main_on_own_stack
+	unsafe {
+		let main_stack = KernelStack::for_thread(ThreadId::new(), ThreadKind::Kernel)
+			.expect("Failed to allocate the kernel stack of the bootstrap processor");
+		
+		asm!(
+			"mov rsp, {stack_top}",
+			"call {continue_fn}",
+			"ud2",
+			stack_top = in(reg) main_stack.leak(),
+			continue_fn = sym kernel_main_on_own_stack,
+			options(noreturn),
+		);
+	}
+}
+
+/// Where [`kernel_main`] continues on a kernel stack
+extern "sysv64" fn kernel_main_on_own_stack() -> ! {
+	// Nothing refers to the boot stack anymore
+	unsafe {
+		mem::kernel_mem_map::unmap_boot_stack();
+	}
+
 //	// DEBUG: Test jump to usermode
 //	unsafe {
 //		// Use SYSCALL/SYSRET instead of SYSENTER/SYSEXIT
@@ -194,20 +238,17 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 	stdout.write_str("[[ inited boot alloc ]]\n").unwrap();
 	
 	// Alloc buffer for uefi memory map
+	// Nothing can be allocated anymore once exiting the boot services failed, so leave
+	// room for the descriptors this allocation and later changes to the map may add
 	let mmap_size = sys_table_uefi.boot_services().memory_map_size();
-	let mut mmap_buf = FallVec::<u8, UefiBootAlloc>::with_len_zeroed(mmap_size.map_size + 128).unwrap();
+	let mut mmap_buf = FallVec::<u8, UefiBootAlloc>::with_len_zeroed(mmap_size.map_size + 8 * mmap_size.entry_size).unwrap();
 	
 	// DEBUG:
 	let stdout = sys_table_uefi.stdout();
 	stdout.write_str("[[ alloc'ed mmap buffer ]]\n").unwrap();
 	
-	// Retrieve uefi memory map
-	let _ = sys_table_uefi.boot_services()
-		.memory_map(mmap_buf.as_mut_slice());
-	
-	// DEBUG:
-	let stdout = sys_table_uefi.stdout();
-	stdout.write_str("[[ retrieved mmap ]]\n").unwrap();
+	// Boot options have already been read by start0
+	mem::virt::init_paging_mode(uefi::boot_opts::force_4_level_paging());
 	
 	// Deinit the uefi boot allocator
 	unsafe {
@@ -222,9 +263,9 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 	let stdout = ();
 	
 	// Finally exit boot services
-	let (rt_table_uefi, mmap_iter) = sys_table_uefi
-		.exit_boot_services(bootloader_handle_uefi, mmap_buf.as_mut_slice())
+	let (rt_table_uefi, mmap_iter) = uefi::mmap::exit_boot_services(sys_table_uefi, bootloader_handle_uefi, mmap_buf.as_mut_slice())
 		.unwrap();
+	uefi::mmap::init_mem_map(mmap_iter.clone());
 	
 //	for mem_desc in mmap_iter {
 //		let mem_desc: &uefi_rs::table::boot::MemoryDescriptor = mem_desc;
@@ -305,14 +346,22 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 	
 	// Log
 	writeln!(tty_writer(), "After ExitBootServices");
+	uefi::mmap::dump_mem_map();
+	
+	// Keep everything the firmware, the loader and the kernel itself
+	// still need out of the frame allocator
+	let stack_ptr: usize;
+	unsafe {
+		asm!("mov {}, rsp", out(reg) stack_ptr, options(nomem, nostack));
+	}
+	mem::phys::reserve_uefi_mmap(mmap_iter.clone(), stack_ptr);
+	mem::phys::reserve_kernel_image();
 	
 	unsafe {
 		for entry in rt_table_uefi.config_table() {
 //			if entry.guid == RawUefiGuid::new(0x8868e871, 0xe4f1, 0x11d3, [0xbc,0x22,0x00,0x80,0xc7,0x3c,0x88,0x81]).into_uefi_rs() {
 			if entry.guid == uefi_rs::table::cfg::ACPI2_GUID {
-				let acpi_root_ptr = Phys::new(entry.address as *const cty::c_void);
-				
-				acpi::ACPI_ROOT_PTR.store(acpi_root_ptr, SeqCst);
+				acpi::ACPI_ROOT_PTR.store(PhysAddr::new(entry.address as usize), SeqCst);
 			}
 		}
 	}
@@ -358,7 +407,9 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 		
 		has_8259_pics = (madt.Flags & ACPI_MADT_PCAT_COMPAT) != 0;
 		
-		writeln!(tty_writer(), "madt lapic base addr: {:08x}", madt.Address as u32);
+		let madt_lapic_base = PhysAddr::new(madt.Address as usize);
+		writeln!(tty_writer(), "madt lapic base addr: {:08x}", madt_lapic_base);
+		mem::phys::reserve_phys_range(madt_lapic_base.as_usize(), (madt_lapic_base + 0x1000).as_usize(), mem::phys::ReservedKind::Mmio);
 		writeln!(tty_writer(), "madt has 8259PICs: {}", has_8259_pics);
 		
 		let mut sub_ptr = (madt as *const _ as usize + 44) as *const ACPI_SUBTABLE_HEADER;
@@ -381,12 +432,14 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 			
 			if sub.Type as u32 == AcpiMadtType_ACPI_MADT_TYPE_IO_APIC {
 				let io_apic_tab = &*(sub_ptr as *const ACPI_MADT_IO_APIC);
+				let io_apic_base = PhysAddr::new(io_apic_tab.Address as usize);
+				mem::phys::reserve_phys_range(io_apic_base.as_usize(), (io_apic_base + 0x1000).as_usize(), mem::phys::ReservedKind::Mmio);
 				
 				if io_apic_order == 0 {
 					first_io_apic.write(IoApicDesc {
 						order: io_apic_order,
 						id: io_apic_tab.Id,
-						regs: Phys(io_apic_tab.Address as usize as *mut u128),
+						base: io_apic_base,
 						base_gsi: io_apic_tab.GlobalIrqBase,
 					});
 				}
@@ -396,12 +449,25 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 			
 			sub_ptr = sub_ptr.byte_offset(sub.Length as _);
 		}
+		
+		// Drop the mapping of the table, it would be stale once we switch to our own address space
+		acpica_sys::AcpiPutTable(table_hdr);
 //		let first_io_apic = first_io_apic.assume_init();
 		
 //		// DEBUG:
 //		wait_here();
 	}
 	
+	// Hand all usable physical memory over to the frame allocator
+	// Note: Everything has to be reserved by now, including the APIC registers from the MADT
+	unsafe {
+		mem::phys::init_frame_alloc(mmap_iter.clone());
+		mem::phys::init_frame_descs();
+	}
+	
+	// Log
+	let _ = mem::phys::dump_meminfo(&mut tty_writer());
+	
 //	// Do full acpica initialization
 //	unsafe {
 //		// Init acpica subsystem
@@ -479,6 +545,9 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 		efer_val |= 0x1 << 11; // Enable NXE
 		EFER.write(efer_val);
 		
+		// Configure the memory types selectable by page table entries
+		paging::init_pat();
+		
 //		#[repr(C)]
 //		struct GdtrPseudoDesc {
 //			_pad: [u16; 3],
@@ -535,8 +604,12 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 			.write(LongCodeDataSegmentDesc::new_code(0, 1, 1, 0x3, 1)); // Usermode Code Segment
 		
 		// TODO: Figure out what RPL the TSS descriptor should have
-		(gdt_ptr.offset(5) as *mut LongSystemSegmentDesc)
-			.write(LongSystemSegmentDesc::new(interrupt::TSS_BUF.as_ptr() as u64, core::alloc::Layout::for_value(&interrupt::TSS_BUF).size().saturating_sub(1) as u32, 0b0, 0b0, 0b1, 0x0, 0xb)); // Long mode TSS
+		(gdt_ptr.offset(interrupt::TSS_GDT_IDX as isize) as *mut LongSystemSegmentDesc)
+			.write(LongSystemSegmentDesc::new(interrupt::TSS_BUF.as_ptr() as u64, core::alloc::Layout::for_value(&interrupt::TSS_BUF).size().saturating_sub(1) as u32, 0b0, 0b0, 0b1, 0x0, 0x9)); // Long mode TSS (available until loaded)
+		
+		// Only used to pass through compat mode when switching the paging mode
+		(gdt_ptr.offset(paging::KERNEL_COMPAT_CS_GDT_IDX as isize) as *mut LongCodeDataSegmentDesc)
+			.write(LongCodeDataSegmentDesc::new_compat_code(1, 0x0)); // Kernel Compat Code Segment
 		
 		// Load GDT
 		let gdt_desc = PseudoDesc {
@@ -638,10 +711,41 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 		// Set syscall entry points
 		CSTAR.write(0x0); // Clear bits, we explicitely don't support compat mode
 		LSTAR.write(syscall::syscall_entry_long0 as u64); // Setup long mode syscall handler routine
+		syscall::init_per_cpu_data(); // Where syscall_entry_long0 finds its kernel stack after swapgs
 		
 		// Set syscall SFMASK to disable irqs on syscall entry
 		const RFLAGS_IF: u64 = 0x0200;
-		SFMASK.write(RFLAGS_IF | 0); // Clear IF on syscall, disabling irqs
+		const RFLAGS_AC: u64 = 0x4_0000;
+		SFMASK.write(RFLAGS_IF | RFLAGS_AC); // Clear IF on syscall, disabling irqs, and AC so user mode can't lift SMAP
+	}
+	
+	// Switch to our own address space
+	// Note: This needs our GDT when switching between four- and five-level paging
+	unsafe {
+		mem::kernel_mem_map::init_kernel_mem_map(mmap_iter);
+		
+		// Keep the kernel from running user code and from accessing user memory by accident
+		// Note: Not before, the firmware's page tables may mark anything as user pages
+		mem::virt::enable_user_access_protection();
+		
+		// The firmware's GDT, IDT and page tables were the last things in use there
+		mem::phys::reclaim(mem::phys::ReclaimKind::BootServices);
+	}
+	
+	// Log
+	writeln!(tty_writer(), "Switched to the kernel address space (direct map at {:#x})", mem::kernel_mem_map::direct_map_base());
+	
+	// Set up the stacks the cpu switches to on its own
+	unsafe {
+		interrupt::init_double_fault_stack().expect("Failed to allocate the double fault stack");
+		
+		// TODO: Switch to the kernel stack of each thread before it runs user code
+		//  once there is a scheduler, for now there is just the initial thread
+		let init_stack = KernelStack::for_thread(ThreadId::new(), ThreadKind::User)
+			.expect("Failed to allocate the kernel stack of the initial thread");
+		let init_stack_top = init_stack.leak();
+		interrupt::set_kernel_entry_stack(init_stack_top);
+		syscall::set_syscall_stack(init_stack_top);
 	}
 	
 	// Disable pic
@@ -664,10 +768,9 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 		// and right now we don't enable it, so we only use
 		// normal APIC mode
 		
-		let apic_base_msr = Msr::<u64>::from_nr(0x0000_001b);
-		let apic_base_msr_val = apic_base_msr.read();
-		
-		let lapic_base = apic_base_msr_val & 0xf_ffff_ffff_f000;
+		let apic_base_msr_val = IA32_APIC_BASE.read();
+		let lapic_base = apic::lapic_base();
+		apic::map_lapic_regs().expect("Failed to map the local APIC registers");
 		
 		// DEBUG: Check x2APIC support
 		let feature_cpuid = __cpuid(1);
@@ -682,19 +785,19 @@ fn init_kernel(bootloader_handle_uefi: uefi_rs::Handle, mut sys_table_uefi: uefi
 			(apic_base_msr_val >> 8) & 0b1,
 		);
 		
-		writeln!(tty_writer(), "lapic id = 0x{:x}, lapic ver = 0x{:x}", ((lapic_base+0x20) as *const u32).read_volatile(), ((lapic_base+0x30) as *const u32).read_volatile());
+		writeln!(tty_writer(), "lapic id = 0x{:x}, lapic ver = 0x{:x}", apic::read_lapic_reg(apic::LAPIC_ID_REG), apic::read_lapic_reg(apic::LAPIC_VERSION_REG));
 		
 		// Enable lapic
 		// DEBUG:
-		writeln!(tty_writer(), "spurious reg = 0x{:0x}", ((lapic_base+0xf0) as *const u32).read_volatile());
+		writeln!(tty_writer(), "spurious reg = 0x{:0x}", apic::read_lapic_reg(apic::LAPIC_SPURIOUS_REG));
 		
 		let spurious_isr_nr: u8 = 0xff; // Map spurious apic isr to #255
-		((lapic_base+0xf0) as *mut u32).write_volatile(0x100 | (spurious_isr_nr & 0xff) as u32);
+		apic::write_lapic_reg(apic::LAPIC_SPURIOUS_REG, 0x100 | (spurious_isr_nr & 0xff) as u32);
 	}
 	
 	// Configure ioapic(s)
 	unsafe {
-		let io_apic = first_io_apic.assume_init();
+		let io_apic = IoApic::new(first_io_apic.assume_init()).expect("Failed to map the IO APIC registers");
 		
 		// TODO: use set_full_dest()
 		// https://wiki.osdev.org/IOAPIC#IOREDTBL
@@ -763,6 +866,7 @@ pub extern "C" fn _start() {
 	unimplemented!();
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 #[no_mangle]
 fn kernel_panic_handler(info: &core::panic::PanicInfo) -> ! {
@@ -930,6 +1034,7 @@ impl<T> PtrOpsExt for *mut T {
 	}
 }
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn kernel_alloc_error_handler(_layout: core::alloc::Layout) -> ! {
 	panic!("Unfallible global allocation failed (this is a bug, global allocation is forbidden)")
@@ -1,7 +1,15 @@
 use core::fmt;
 use core::fmt::Pointer;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
 use core::ptr::NonNull;
 
+use crate::mem::kernel_mem_map::phys_to_virt;
+use crate::mem::phys::buck::BASE_PAGE_ADDR_BITS;
+use crate::mem::virt::{is_canonical, virt_addr_bits};
+
+/// Max number of physical address bits supported by x86-64
+pub const MAX_PHYS_ADDR_BITS: usize = 52;
+
 #[deprecated(note = "Use normal pointers/references instead which are implicitely assumed to be in the virtual memory map of the kernel.")]
 #[repr(transparent)]
 pub struct Virt<A: Addr>(pub A);
@@ -19,6 +27,319 @@ impl<A: Addr> Phys<A> {
 	}
 }
 
+impl<T> Phys<*const T> {
+	#[inline]
+	pub fn addr(self) -> PhysAddr {
+		PhysAddr::new(self.0 as usize)
+	}
+	
+	/// The pointer through which the kernel accesses the memory, see [`PhysAddr::to_virt`]
+	#[inline]
+	pub fn to_virt(self) -> *const T {
+		self.addr().to_virt().as_ptr()
+	}
+}
+
+impl<T> Phys<*mut T> {
+	#[inline]
+	pub fn addr(self) -> PhysAddr {
+		PhysAddr::new(self.0 as usize)
+	}
+	
+	/// The pointer through which the kernel accesses the memory, see [`PhysAddr::to_virt`]
+	#[inline]
+	pub fn to_virt(self) -> *mut T {
+		self.addr().to_virt().as_mut_ptr()
+	}
+}
+
+impl<T> Phys<NonNull<T>> {
+	#[inline]
+	pub fn addr(self) -> PhysAddr {
+		PhysAddr::new(self.0.as_ptr() as usize)
+	}
+	
+	/// The pointer through which the kernel accesses the memory, see [`PhysAddr::to_virt`]
+	#[inline]
+	pub fn to_virt(self) -> NonNull<T> {
+		// Safety: The direct map never starts at 0
+		unsafe { NonNull::new_unchecked(self.addr().to_virt().as_mut_ptr()) }
+	}
+}
+
+/// A physical address
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+#[repr(transparent)]
+pub struct PhysAddr(usize);
+
+impl PhysAddr {
+	pub const ZERO: Self = Self(0);
+	
+	/// Panics if the address has bits above [`MAX_PHYS_ADDR_BITS`] set
+	#[inline]
+	pub const fn new(addr: usize) -> Self {
+		assert!((addr >> MAX_PHYS_ADDR_BITS) == 0, "Physical address out of range");
+		Self(addr)
+	}
+	
+	#[inline]
+	pub const fn try_new(addr: usize) -> Option<Self> {
+		if (addr >> MAX_PHYS_ADDR_BITS) == 0 {
+			Some(Self(addr))
+		} else {
+			None
+		}
+	}
+	
+	/// Clears all bits above [`MAX_PHYS_ADDR_BITS`] (e.g. flags in an msr or table entry)
+	#[inline]
+	pub const fn new_truncate(addr: usize) -> Self {
+		Self(addr & ((0x1 << MAX_PHYS_ADDR_BITS) - 1))
+	}
+	
+	#[inline]
+	pub const fn from_frame_number(pfn: usize) -> Self {
+		Self::new(pfn << BASE_PAGE_ADDR_BITS)
+	}
+	
+	/// Number of the base page frame containing the address
+	#[inline]
+	pub const fn frame_number(self) -> usize {
+		self.0 >> BASE_PAGE_ADDR_BITS
+	}
+	
+	/// The address in the kernel's direct map (or the address itself while
+	/// the kernel still runs identity mapped, see [`phys_to_virt`])
+	#[inline]
+	pub fn to_virt(self) -> VirtAddr {
+		VirtAddr(phys_to_virt(self.0))
+	}
+	
+	/// A typed physical pointer to the address
+	#[inline]
+	pub const fn phys_ptr<T>(self) -> Phys<*mut T> {
+		Phys(self.0 as *mut T)
+	}
+}
+
+/// A canonical virtual address
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+#[repr(transparent)]
+pub struct VirtAddr(usize);
+
+impl VirtAddr {
+	pub const ZERO: Self = Self(0);
+	
+	/// Panics if the address is not canonical for the current paging mode
+	#[inline]
+	pub fn new(addr: usize) -> Self {
+		Self::try_new(addr).expect("Non-canonical virtual address")
+	}
+	
+	#[inline]
+	pub fn try_new(addr: usize) -> Option<Self> {
+		if is_canonical(addr) {
+			Some(Self(addr))
+		} else {
+			None
+		}
+	}
+	
+	/// Sign extends the address from the highest virtual address bit
+	#[inline]
+	pub fn new_truncate(addr: usize) -> Self {
+		let unused_bits = usize::BITS as usize - virt_addr_bits();
+		Self((((addr << unused_bits) as isize) >> unused_bits) as usize)
+	}
+	
+	#[inline]
+	pub fn from_ptr<T: ?Sized>(ptr: *const T) -> Self {
+		Self::new(ptr as *const u8 as usize)
+	}
+	
+	#[inline]
+	pub fn from_page_number(page_number: usize) -> Self {
+		Self::new_truncate(page_number << BASE_PAGE_ADDR_BITS)
+	}
+	
+	/// Number of the base page containing the address, without the sign extension bits
+	#[inline]
+	pub fn page_number(self) -> usize {
+		(self.0 & ((0x1 << virt_addr_bits()) - 1)) >> BASE_PAGE_ADDR_BITS
+	}
+	
+	#[inline]
+	pub const fn as_ptr<T>(self) -> *const T {
+		self.0 as *const T
+	}
+	
+	#[inline]
+	pub const fn as_mut_ptr<T>(self) -> *mut T {
+		self.0 as *mut T
+	}
+}
+
+/// Arithmetic, alignment and formatting shared by [`PhysAddr`] and [`VirtAddr`]
+macro_rules! impl_addr_common {
+	($addr:ident, $name:literal) => {
+		impl $addr {
+			#[inline]
+			pub const fn as_usize(self) -> usize {
+				self.0
+			}
+			
+			#[inline]
+			pub const fn as_u64(self) -> u64 {
+				self.0 as u64
+			}
+			
+			/// Rounds down to a multiple of `align` (a power of two)
+			#[inline]
+			pub const fn align_down(self, align: usize) -> Self {
+				debug_assert!(align.is_power_of_two());
+				Self(self.0 & !(align - 1))
+			}
+			
+			/// Rounds up to a multiple of `align` (a power of two)
+			#[inline]
+			pub const fn align_up(self, align: usize) -> Self {
+				debug_assert!(align.is_power_of_two());
+				Self((self.0 + (align - 1)) & !(align - 1))
+			}
+			
+			#[inline]
+			pub const fn is_aligned(self, align: usize) -> bool {
+				(self.0 & (align - 1)) == 0
+			}
+			
+			/// Offset into the naturally aligned block of `align` bytes
+			#[inline]
+			pub const fn align_offset(self, align: usize) -> usize {
+				self.0 & (align - 1)
+			}
+		}
+		
+		impl Add<usize> for $addr {
+			type Output = Self;
+			
+			#[inline]
+			fn add(self, rhs: usize) -> Self {
+				Self(self.0.checked_add(rhs).expect(concat!($name, " overflow")))
+			}
+		}
+		
+		impl AddAssign<usize> for $addr {
+			#[inline]
+			fn add_assign(&mut self, rhs: usize) {
+				*self = *self + rhs;
+			}
+		}
+		
+		impl Sub<usize> for $addr {
+			type Output = Self;
+			
+			#[inline]
+			fn sub(self, rhs: usize) -> Self {
+				Self(self.0.checked_sub(rhs).expect(concat!($name, " underflow")))
+			}
+		}
+		
+		impl SubAssign<usize> for $addr {
+			#[inline]
+			fn sub_assign(&mut self, rhs: usize) {
+				*self = *self - rhs;
+			}
+		}
+		
+		/// Distance in bytes
+		impl Sub<$addr> for $addr {
+			type Output = usize;
+			
+			#[inline]
+			fn sub(self, rhs: Self) -> usize {
+				self.0.checked_sub(rhs.0).expect(concat!($name, " underflow"))
+			}
+		}
+		
+		impl fmt::Debug for $addr {
+			fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				write!(f, concat!($name, "({:#x})"), self.0)
+			}
+		}
+		
+		impl fmt::LowerHex for $addr {
+			fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				fmt::LowerHex::fmt(&self.0, f)
+			}
+		}
+	};
+}
+
+impl_addr_common!(PhysAddr, "PhysAddr");
+impl_addr_common!(VirtAddr, "VirtAddr");
+
+/// A range of physical addresses
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PhysRange {
+	pub start: PhysAddr,
+	/// Exclusive end address
+	pub end: PhysAddr,
+}
+
+/// A range of virtual addresses
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct VirtRange {
+	pub start: VirtAddr,
+	/// Exclusive end address
+	pub end: VirtAddr,
+}
+
+macro_rules! impl_addr_range {
+	($range:ident, $addr:ident) => {
+		impl $range {
+			#[inline]
+			pub fn new(start: $addr, end: $addr) -> Self {
+				debug_assert!(start <= end, "Range ends before it starts");
+				Self {start, end}
+			}
+			
+			#[inline]
+			pub fn with_size(start: $addr, size: usize) -> Self {
+				Self {start, end: start + size}
+			}
+			
+			#[inline]
+			pub fn size(&self) -> usize {
+				self.end - self.start
+			}
+			
+			#[inline]
+			pub fn is_empty(&self) -> bool {
+				self.start >= self.end
+			}
+			
+			#[inline]
+			pub fn contains(&self, addr: $addr) -> bool {
+				self.start <= addr && addr < self.end
+			}
+			
+			#[inline]
+			pub fn overlaps(&self, other: &Self) -> bool {
+				self.start < other.end && other.start < self.end
+			}
+			
+			/// The smallest range with `align` aligned bounds containing this range
+			#[inline]
+			pub fn align_outward(&self, align: usize) -> Self {
+				Self {start: self.start.align_down(align), end: self.end.align_up(align)}
+			}
+		}
+	};
+}
+
+impl_addr_range!(PhysRange, PhysAddr);
+impl_addr_range!(VirtRange, VirtAddr);
+
 pub trait Addr {}
 
 impl<T: ?Sized> Addr for *const T {}
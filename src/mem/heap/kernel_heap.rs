@@ -0,0 +1,174 @@
+//! Kernel heap
+//! 
+//! Allocations up to the biggest size class are served from one [`SlabCache`] per
+//! size class, with per-cpu magazines in front of them (see [`super::magazine`]).
+//! Anything bigger comes straight from the frame allocator. All memory is accessed
+//! through the direct map, so the heap only works once the kernel runs in its own
+//! address space.
+//! 
+//! Note: The slab caches are protected by spin locks, so the heap
+//! must not be used from interrupt handlers.
+
+use core::ptr::NonNull;
+
+use fallo::alloc::{AllocError, BareAlloc, FallibleAlloc};
+use fallo::stdalloc::Layout;
+
+use crate::mem::{Phys, VirtAddr};
+use crate::mem::heap::magazine::{magazine_alloc, magazine_free};
+use crate::mem::heap::slab::SlabCache;
+use crate::mem::kernel_mem_map::{direct_map_to_phys, is_kernel_mem_map_ready};
+use crate::mem::phys::{alloc_frames, frame_desc_of, free_frames, FrameOwner};
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BASE_PAGE_SIZE, BasePage, BUCK_LEVEL_COUNT, BuckBlock};
+
+/// Object sizes of the slab caches, allocations are rounded up to the next one
+pub const SIZE_CLASSES: [usize; SIZE_CLASS_COUNT] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+pub(super) const SIZE_CLASS_COUNT: usize = 8;
+
+static SLAB_CACHES: [SlabCache; SIZE_CLASS_COUNT] = [
+	SlabCache::new(SIZE_CLASSES[0]),
+	SlabCache::new(SIZE_CLASSES[1]),
+	SlabCache::new(SIZE_CLASSES[2]),
+	SlabCache::new(SIZE_CLASSES[3]),
+	SlabCache::new(SIZE_CLASSES[4]),
+	SlabCache::new(SIZE_CLASSES[5]),
+	SlabCache::new(SIZE_CLASSES[6]),
+	SlabCache::new(SIZE_CLASSES[7]),
+];
+
+/// The general purpose kernel allocator, usable for the whole kernel
+/// lifetime (after [`crate::mem::kernel_mem_map::init_kernel_mem_map`])
+pub struct KernelHeap;
+
+impl FallibleAlloc for KernelHeap {
+	type Error = AllocError;
+	
+	fn alloc(&mut self, layout: Layout) -> Result<NonNull<[u8]>, Self::Error> {
+		if !is_kernel_mem_map_ready() {
+			return Err(AllocError);
+		}
+		
+		if layout.size() == 0 {
+			// Safety: Alignments are never 0
+			let dangling = unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+			return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+		}
+		
+		if let Some(class_idx) = size_class_idx(layout) {
+			let obj = magazine_alloc(class_idx)
+				.or_else(|| slab_cache(class_idx).alloc())
+				.ok_or(AllocError)?;
+			
+			return Ok(NonNull::slice_from_raw_parts(obj, slab_cache(class_idx).obj_size()));
+		}
+		
+		// Bigger buffers don't need to be physically contiguous and should not use the heap
+		let order = large_alloc_order(layout);
+		if order >= BUCK_LEVEL_COUNT {
+			return Err(AllocError);
+		}
+		
+		let block = alloc_frames(order).ok_or(AllocError)?;
+		frame_desc_of(&block).set_owner(FrameOwner::KernelHeap);
+		
+		let ptr = block.ptr().to_virt().cast::<u8>();
+		Ok(NonNull::slice_from_raw_parts(ptr, block.real_size() as usize))
+	}
+	
+	unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+		if layout.size() == 0 {
+			return;
+		}
+		
+		if let Some(class_idx) = size_class_idx(layout) {
+			if !magazine_free(class_idx, ptr) {
+				slab_cache(class_idx).free(ptr);
+			}
+			return;
+		}
+		
+		let phys_addr = direct_map_to_phys(VirtAddr::from_ptr(ptr.as_ptr()))
+			.expect("Freed kernel heap memory that is not in the direct map");
+		free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(phys_addr.as_usize() as *mut BasePage)), large_alloc_order(layout) as u32));
+	}
+}
+
+impl BareAlloc for KernelHeap {
+	const INIT: Self = KernelHeap;
+}
+
+impl Clone for KernelHeap {
+	fn clone(&self) -> Self {
+		KernelHeap
+	}
+}
+
+#[inline]
+pub(super) fn slab_cache(class_idx: usize) -> &'static SlabCache {
+	&SLAB_CACHES[class_idx]
+}
+
+/// The smallest size class fitting the layout, objects are aligned to their size
+#[inline]
+fn size_class_idx(layout: Layout) -> Option<usize> {
+	let size = layout.size().max(layout.align()).max(SIZE_CLASSES[0]);
+	
+	if size > SIZE_CLASSES[SIZE_CLASS_COUNT - 1] {
+		None
+	} else {
+		Some((size.next_power_of_two().trailing_zeros() - SIZE_CLASSES[0].trailing_zeros()) as usize)
+	}
+}
+
+/// Frame order of allocations bigger than all size classes,
+/// blocks are naturally aligned so this covers the alignment too
+#[inline]
+fn large_alloc_order(layout: Layout) -> usize {
+	let size = layout.size().max(layout.align());
+	let page_count = (size + (BASE_PAGE_SIZE - 1)) >> BASE_PAGE_ADDR_BITS;
+	
+	page_count.next_power_of_two().trailing_zeros() as usize
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	fn layout(size: usize, align: usize) -> Layout {
+		Layout::from_size_align(size, align).unwrap()
+	}
+	
+	#[test]
+	fn size_class_of_size() {
+		assert_eq!(size_class_idx(layout(1, 1)), Some(0));
+		assert_eq!(size_class_idx(layout(16, 1)), Some(0));
+		assert_eq!(size_class_idx(layout(17, 1)), Some(1));
+		assert_eq!(size_class_idx(layout(100, 4)), Some(3));
+		
+		for (class_idx, &class_size) in SIZE_CLASSES.iter().enumerate() {
+			assert_eq!(size_class_idx(layout(class_size, 8)), Some(class_idx));
+			assert_eq!(SIZE_CLASSES[size_class_idx(layout(class_size - 1, 1)).unwrap()], class_size);
+		}
+		
+		assert_eq!(size_class_idx(layout(SIZE_CLASSES[SIZE_CLASS_COUNT - 1] + 1, 1)), None);
+	}
+	
+	#[test]
+	fn size_class_covers_alignment() {
+		assert_eq!(size_class_idx(layout(8, 64)), Some(2));
+		assert_eq!(size_class_idx(layout(1, 2048)), Some(SIZE_CLASS_COUNT - 1));
+		assert_eq!(size_class_idx(layout(8, 4096)), None);
+	}
+	
+	#[test]
+	fn large_alloc_orders() {
+		assert_eq!(large_alloc_order(layout(SIZE_CLASSES[SIZE_CLASS_COUNT - 1] + 1, 1)), 0);
+		assert_eq!(large_alloc_order(layout(BASE_PAGE_SIZE, 1)), 0);
+		assert_eq!(large_alloc_order(layout(BASE_PAGE_SIZE + 1, 1)), 1);
+		assert_eq!(large_alloc_order(layout(3 * BASE_PAGE_SIZE, 8)), 2);
+		assert_eq!(large_alloc_order(layout(0x1 << 20, 8)), 8);
+		
+		// Blocks are naturally aligned, so the alignment counts like the size
+		assert_eq!(large_alloc_order(layout(BASE_PAGE_SIZE, 4 * BASE_PAGE_SIZE)), 2);
+	}
+}
@@ -0,0 +1,132 @@
+//! Per-cpu magazines in front of the slab caches
+//! 
+//! Every cpu has a small stack of free objects per size class that it allocates
+//! from and frees to without taking any lock. Only when its magazine runs empty
+//! or full does a cpu go to the (locked) slab cache, moving half a magazine at once.
+//! 
+//! A magazine is only ever used by its own cpu. Its busy flag just guards against
+//! code interrupting the cpu in the middle of a magazine operation, which then
+//! bypasses the magazine.
+
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr};
+use core::sync::atomic::Ordering::*;
+
+use static_assertions::const_assert;
+
+use crate::cpu::current_cpu_uid;
+use crate::mem::{Phys, VirtAddr};
+use crate::mem::heap::kernel_heap::{slab_cache, SIZE_CLASS_COUNT};
+use crate::mem::kernel_mem_map::direct_map_to_phys;
+use crate::mem::phys::{alloc_frames, frame_desc_of, free_frames, FrameOwner};
+use crate::mem::phys::buck::{BASE_PAGE_SIZE, BasePage, BuckBlock};
+
+/// Max number of cpus with magazines, cpus with a higher uid always use the slab caches
+const MAX_MAGAZINE_CPUS: usize = 256;
+/// Chosen so that a magazine is exactly 256 bytes
+const MAGAZINE_CAPACITY: usize = 30;
+
+const NO_MAGAZINES: AtomicPtr<CpuMagazines> = AtomicPtr::new(ptr::null_mut());
+
+/// The magazines of every cpu, allocated on the first heap use of the cpu
+static CPU_MAGAZINES: [AtomicPtr<CpuMagazines>; MAX_MAGAZINE_CPUS] = [NO_MAGAZINES; MAX_MAGAZINE_CPUS];
+
+struct Magazine {
+	busy: AtomicBool,
+	count: UnsafeCell<usize>,
+	objs: UnsafeCell<[*mut u8; MAGAZINE_CAPACITY]>,
+}
+
+/// All magazines of a cpu, indexed by size class. All zeroes is a valid (empty) state.
+struct CpuMagazines([Magazine; SIZE_CLASS_COUNT]);
+
+const_assert!(size_of::<CpuMagazines>() <= BASE_PAGE_SIZE);
+
+/// Takes an object of the size class from the magazine of the current cpu,
+/// refilling it from the slab cache if needed.
+/// 
+/// Returns `None` if the magazine can't be used right now or there is no
+/// memory left, so the caller should fall back to the slab cache.
+pub(super) fn magazine_alloc(class_idx: usize) -> Option<NonNull<u8>> {
+	let mag = &cpu_magazines()?.0[class_idx];
+	if mag.busy.swap(true, Acquire) {
+		return None;
+	}
+	
+	let obj = unsafe {
+		let count = &mut *mag.count.get();
+		let objs = &mut *mag.objs.get();
+		
+		if *count == 0 {
+			*count = slab_cache(class_idx).alloc_batch(&mut objs[..(MAGAZINE_CAPACITY / 2)]);
+		}
+		
+		if *count > 0 {
+			*count -= 1;
+			NonNull::new(objs[*count])
+		} else {
+			None
+		}
+	};
+	
+	mag.busy.store(false, Release);
+	obj
+}
+
+/// Puts a free object of the size class into the magazine of the current cpu,
+/// flushing half of it back to the slab cache if it is full.
+/// 
+/// Returns whether the object was taken, if not the caller has to free it to the slab cache.
+pub(super) unsafe fn magazine_free(class_idx: usize, obj: NonNull<u8>) -> bool {
+	let mag = match cpu_magazines() {
+		Some(mags) => &mags.0[class_idx],
+		None => return false,
+	};
+	if mag.busy.swap(true, Acquire) {
+		return false;
+	}
+	
+	let count = &mut *mag.count.get();
+	let objs = &mut *mag.objs.get();
+	
+	if *count == MAGAZINE_CAPACITY {
+		slab_cache(class_idx).free_batch(&objs[(MAGAZINE_CAPACITY / 2)..]);
+		*count = MAGAZINE_CAPACITY / 2;
+	}
+	
+	objs[*count] = obj.as_ptr();
+	*count += 1;
+	
+	mag.busy.store(false, Release);
+	true
+}
+
+/// The magazines of the current cpu, allocating them on first use
+fn cpu_magazines() -> Option<&'static CpuMagazines> {
+	let slot = CPU_MAGAZINES.get(current_cpu_uid().0 as usize)?;
+	
+	let mags = slot.load(Acquire);
+	if !mags.is_null() {
+		return unsafe { Some(&*mags) };
+	}
+	
+	let block = alloc_frames(0)?;
+	frame_desc_of(&block).set_owner(FrameOwner::KernelHeap);
+	
+	let new_mags = block.ptr().to_virt().as_ptr() as *mut CpuMagazines;
+	unsafe {
+		ptr::write_bytes(new_mags as *mut u8, 0, BASE_PAGE_SIZE);
+	}
+	
+	// Code interrupting us on this cpu might have been faster
+	match slot.compare_exchange(ptr::null_mut(), new_mags, AcqRel, Acquire) {
+		Ok(_) => unsafe { Some(&*new_mags) },
+		Err(mags) => unsafe {
+			let phys_addr = direct_map_to_phys(VirtAddr::from_ptr(new_mags)).unwrap();
+			free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(phys_addr.as_usize() as *mut BasePage)), 0));
+			Some(&*mags)
+		},
+	}
+}
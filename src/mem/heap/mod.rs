@@ -0,0 +1,7 @@
+//! Kernel heap for the whole kernel lifetime, see [`KernelHeap`]
+
+pub use kernel_heap::*;
+
+mod kernel_heap;
+mod magazine;
+mod slab;
@@ -0,0 +1,375 @@
+//! Slab caches of equally sized objects
+//! 
+//! Every slab is a naturally aligned block from the frame allocator, accessed through
+//! the direct map. It starts with a [`SlabHeader`] followed by the objects, so the
+//! slab of an object is found by rounding its address down to the slab size.
+//! Free objects are linked together inside of their slab.
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::*;
+
+use crate::mem::{Phys, VirtAddr};
+use crate::mem::kernel_mem_map::direct_map_to_phys;
+use crate::mem::phys::{alloc_frames, frame_desc_of, free_frames, FrameOwner};
+use crate::mem::phys::buck::{BASE_PAGE_SIZE, BasePage, BuckBlock};
+
+/// Min number of objects per slab, bigger objects get bigger slabs
+const MIN_SLAB_OBJS: usize = 8;
+
+#[repr(C)]
+struct SlabHeader {
+	next: *mut SlabHeader,
+	prev: *mut SlabHeader,
+	free_list: *mut FreeObj,
+	in_use: usize,
+}
+
+struct FreeObj {
+	next: *mut FreeObj,
+}
+
+/// A cache of objects of one size, see the module docs
+pub(super) struct SlabCache {
+	locked: AtomicBool,
+	obj_size: usize,
+	/// Frame order of each slab
+	slab_order: usize,
+	/// Slabs with at least one free and one used object
+	partial: UnsafeCell<*mut SlabHeader>,
+	/// A single completely free slab kept around, so a cache
+	/// going back and forth between 0 and 1 objects is cheap
+	empty: UnsafeCell<*mut SlabHeader>,
+}
+
+unsafe impl Sync for SlabCache {}
+
+impl SlabCache {
+	/// `obj_size` must be a power of two
+	pub(super) const fn new(obj_size: usize) -> Self {
+		let mut slab_order = 0;
+		while (BASE_PAGE_SIZE << slab_order) < obj_size * MIN_SLAB_OBJS {
+			slab_order += 1;
+		}
+		
+		Self {
+			locked: AtomicBool::new(false),
+			obj_size,
+			slab_order,
+			partial: UnsafeCell::new(ptr::null_mut()),
+			empty: UnsafeCell::new(ptr::null_mut()),
+		}
+	}
+	
+	#[inline]
+	pub(super) fn obj_size(&self) -> usize {
+		self.obj_size
+	}
+	
+	pub(super) fn alloc(&self) -> Option<NonNull<u8>> {
+		let mut obj = ptr::null_mut();
+		if self.alloc_batch(core::slice::from_mut(&mut obj)) == 1 {
+			NonNull::new(obj)
+		} else {
+			None
+		}
+	}
+	
+	/// Objects must have been allocated from this cache
+	pub(super) unsafe fn free(&self, obj: NonNull<u8>) {
+		self.free_batch(&[obj.as_ptr()]);
+	}
+	
+	/// Fills `objs` with as many objects as possible and returns their count
+	pub(super) fn alloc_batch(&self, objs: &mut [*mut u8]) -> usize {
+		self.lock();
+		
+		let mut count = 0;
+		unsafe {
+			while count < objs.len() {
+				match self.alloc_locked() {
+					Some(obj) => {
+						objs[count] = obj;
+						count += 1;
+					},
+					None => break,
+				}
+			}
+		}
+		
+		self.unlock();
+		count
+	}
+	
+	/// Objects must have been allocated from this cache
+	pub(super) unsafe fn free_batch(&self, objs: &[*mut u8]) {
+		self.lock();
+		for &obj in objs {
+			self.free_locked(obj);
+		}
+		self.unlock();
+	}
+	
+	unsafe fn alloc_locked(&self) -> Option<*mut u8> {
+		let mut slab = *self.partial.get();
+		
+		if slab.is_null() {
+			slab = if !(*self.empty.get()).is_null() {
+				ptr::replace(self.empty.get(), ptr::null_mut())
+			} else {
+				self.new_slab()?
+			};
+			push_slab(self.partial.get(), slab);
+		}
+		
+		let obj = (*slab).free_list;
+		(*slab).free_list = (*obj).next;
+		(*slab).in_use += 1;
+		
+		// Full slabs are not tracked, they are found again through their objects
+		if (*slab).free_list.is_null() {
+			remove_slab(self.partial.get(), slab);
+		}
+		
+		Some(obj as *mut u8)
+	}
+	
+	unsafe fn free_locked(&self, obj: *mut u8) {
+		let slab = ((obj as usize) & !(self.slab_size() - 1)) as *mut SlabHeader;
+		debug_assert!((obj as usize) >= (slab as usize) + self.first_obj_offset(), "Freed a slab header");
+		
+		let was_full = (*slab).free_list.is_null();
+		
+		let free_obj = obj as *mut FreeObj;
+		(*free_obj).next = (*slab).free_list;
+		(*slab).free_list = free_obj;
+		(*slab).in_use -= 1;
+		
+		if was_full {
+			push_slab(self.partial.get(), slab);
+		}
+		
+		if (*slab).in_use == 0 {
+			remove_slab(self.partial.get(), slab);
+			
+			if (*self.empty.get()).is_null() {
+				*self.empty.get() = slab;
+			} else {
+				self.release_slab(slab);
+			}
+		}
+	}
+	
+	unsafe fn new_slab(&self) -> Option<*mut SlabHeader> {
+		let block = alloc_frames(self.slab_order)?;
+		frame_desc_of(&block).set_owner(FrameOwner::KernelHeap);
+		
+		let slab = block.ptr().to_virt().as_ptr() as *mut SlabHeader;
+		self.init_slab(slab);
+		Some(slab)
+	}
+	
+	/// Writes the header and links up all objects of a slab
+	/// at `slab`, which must be naturally aligned memory
+	unsafe fn init_slab(&self, slab: *mut SlabHeader) {
+		slab.write(SlabHeader {
+			next: ptr::null_mut(),
+			prev: ptr::null_mut(),
+			free_list: ptr::null_mut(),
+			in_use: 0,
+		});
+		
+		// Link the objects up in address order
+		let mut obj_offset = self.slab_size() - self.obj_size;
+		while obj_offset >= self.first_obj_offset() {
+			let obj = ((slab as usize) + obj_offset) as *mut FreeObj;
+			(*obj).next = (*slab).free_list;
+			(*slab).free_list = obj;
+			
+			obj_offset -= self.obj_size;
+		}
+	}
+	
+	unsafe fn release_slab(&self, slab: *mut SlabHeader) {
+		let phys_addr = direct_map_to_phys(VirtAddr::from_ptr(slab))
+			.expect("Slab is not in the direct map");
+		
+		free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(phys_addr.as_usize() as *mut BasePage)), self.slab_order as u32));
+	}
+	
+	#[inline]
+	fn slab_size(&self) -> usize {
+		BASE_PAGE_SIZE << self.slab_order
+	}
+	
+	/// Objects are aligned to their size
+	#[inline]
+	fn first_obj_offset(&self) -> usize {
+		(size_of::<SlabHeader>() + (self.obj_size - 1)) & !(self.obj_size - 1)
+	}
+	
+	#[inline]
+	fn lock(&self) {
+		while self.locked.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+			unsafe {
+				asm!("pause", options(nomem, nostack));
+			}
+		}
+	}
+	
+	#[inline]
+	fn unlock(&self) {
+		self.locked.store(false, Release);
+	}
+}
+
+unsafe fn push_slab(list: *mut *mut SlabHeader, slab: *mut SlabHeader) {
+	(*slab).prev = ptr::null_mut();
+	(*slab).next = *list;
+	if !(*list).is_null() {
+		(**list).prev = slab;
+	}
+	*list = slab;
+}
+
+unsafe fn remove_slab(list: *mut *mut SlabHeader, slab: *mut SlabHeader) {
+	if (*slab).prev.is_null() {
+		*list = (*slab).next;
+	} else {
+		(*(*slab).prev).next = (*slab).next;
+	}
+	if !(*slab).next.is_null() {
+		(*(*slab).next).prev = (*slab).prev;
+	}
+	
+	(*slab).next = ptr::null_mut();
+	(*slab).prev = ptr::null_mut();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use std::alloc::{alloc, dealloc, Layout};
+	
+	/// A cache with a single slab from the host heap, which is handed out
+	/// as the empty slab so the frame allocator is never asked for more
+	struct TestCache {
+		cache: SlabCache,
+		slab: *mut SlabHeader,
+	}
+	
+	impl TestCache {
+		fn new(obj_size: usize) -> Self {
+			let cache = SlabCache::new(obj_size);
+			
+			unsafe {
+				let slab = alloc(Self::slab_layout(&cache)) as *mut SlabHeader;
+				assert!(!slab.is_null());
+				
+				cache.init_slab(slab);
+				*cache.empty.get() = slab;
+				
+				Self { cache, slab }
+			}
+		}
+		
+		fn slab_layout(cache: &SlabCache) -> Layout {
+			Layout::from_size_align(cache.slab_size(), cache.slab_size()).unwrap()
+		}
+		
+		fn objs_per_slab(&self) -> usize {
+			(self.cache.slab_size() - self.cache.first_obj_offset()) / self.cache.obj_size()
+		}
+	}
+	
+	impl Drop for TestCache {
+		fn drop(&mut self) {
+			unsafe { dealloc(self.slab as *mut u8, Self::slab_layout(&self.cache)); }
+		}
+	}
+	
+	#[test]
+	fn slab_sizes() {
+		assert_eq!(SlabCache::new(16).slab_size(), BASE_PAGE_SIZE);
+		assert_eq!(SlabCache::new(512).slab_size(), BASE_PAGE_SIZE);
+		assert_eq!(SlabCache::new(1024).slab_size(), 2 * BASE_PAGE_SIZE);
+		assert_eq!(SlabCache::new(2048).slab_size(), 4 * BASE_PAGE_SIZE);
+		
+		// The header shares the first object slot with as few objects as possible
+		assert_eq!(SlabCache::new(16).first_obj_offset(), 32);
+		assert_eq!(SlabCache::new(64).first_obj_offset(), 64);
+	}
+	
+	#[test]
+	fn alloc_fills_slab_with_aligned_objects() {
+		for &obj_size in [16, 64, 256, 2048].iter() {
+			let test = TestCache::new(obj_size);
+			let slab_addr = test.slab as usize;
+			
+			let mut objs = vec![0usize; test.objs_per_slab()];
+			for obj in objs.iter_mut() {
+				*obj = test.cache.alloc().expect("Slab ran out early").as_ptr() as usize;
+				
+				assert_eq!(*obj & (obj_size - 1), 0, "Object not aligned to its size");
+				assert!(*obj >= slab_addr + test.cache.first_obj_offset(), "Object overlaps the header");
+				assert!(*obj + obj_size <= slab_addr + test.cache.slab_size(), "Object outside of the slab");
+			}
+			
+			// Handed out in address order, each object once
+			assert!(objs.windows(2).all(|pair| pair[0] + obj_size == pair[1]));
+			unsafe {
+				assert!((*test.cache.partial.get()).is_null(), "Full slab still partial");
+				assert_eq!((*test.slab).in_use, objs.len());
+			}
+			
+			for &obj in objs.iter() {
+				unsafe { test.cache.free(NonNull::new(obj as *mut u8).unwrap()); }
+			}
+		}
+	}
+	
+	#[test]
+	fn freed_objects_are_reused() {
+		let test = TestCache::new(128);
+		
+		let first = test.cache.alloc().unwrap();
+		let second = test.cache.alloc().unwrap();
+		unsafe { test.cache.free(first); }
+		
+		// The free list is LIFO
+		assert_eq!(test.cache.alloc(), Some(first));
+		
+		unsafe {
+			test.cache.free(second);
+			test.cache.free(first);
+		}
+	}
+	
+	#[test]
+	fn empty_slab_is_kept() {
+		let test = TestCache::new(32);
+		
+		let mut objs = vec![ptr::null_mut(); test.objs_per_slab()];
+		assert_eq!(test.cache.alloc_batch(&mut objs), objs.len());
+		
+		// A full slab becomes partial again on the first free
+		unsafe {
+			test.cache.free_batch(&objs[..1]);
+			assert_eq!(*test.cache.partial.get(), test.slab);
+			
+			test.cache.free_batch(&objs[1..]);
+			assert!((*test.cache.partial.get()).is_null());
+			assert_eq!(*test.cache.empty.get(), test.slab);
+			assert_eq!((*test.slab).in_use, 0);
+		}
+		
+		// And is handed out again instead of a new one
+		let obj = test.cache.alloc().unwrap();
+		assert_eq!((obj.as_ptr() as usize) & !(test.cache.slab_size() - 1), test.slab as usize);
+		unsafe { test.cache.free(obj); }
+	}
+}
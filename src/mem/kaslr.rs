@@ -0,0 +1,198 @@
+//! Kernel address space layout randomization
+//! 
+//! The loader places the kernel image at its link address, which is also its physical
+//! address. Before [`crate::init_kernel`] runs, [`relocate_kernel`] moves the kernel into
+//! the higher half: it picks a random page aligned slot in the region at [`KERNEL_IMAGE_BASE`],
+//! maps the image there in a copy of the firmware's page table and applies the relocations
+//! of the image (it is linked as a static PIE) for that slot. The kernel then continues in
+//! the relocated image. The other regions of the kernel address space start at a random
+//! offset as well, see [`random_slide`].
+//! 
+//! Randomness comes from RDSEED or RDRAND. Without either, or with the `nokaslr` boot
+//! option, the image is placed at [`KERNEL_IMAGE_BASE`] plus its link address instead
+//! and all slides are 0.
+
+use core::ptr;
+use core::slice;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::*;
+
+use uefi_rs::table::boot::{AllocateType, BootServices, MemoryType};
+
+use crate::arch::x86_64::paging::{is_la57_active, read_cr3, write_cr3};
+use crate::arch::x86_64::rng::hw_random_u64;
+use crate::mem::kernel_mem_map::{KERNEL_IMAGE_BASE, KERNEL_IMAGE_REGION_SIZE};
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+use crate::uefi::boot_opts::no_kaslr;
+
+/// The only relocation type in a static PIE
+const R_X86_64_RELATIVE: u32 = 8;
+
+const EARLY_PTE_PRESENT: u64 = 0x1 << 0;
+/// Present and writable, the permissions are only enforced by the kernel's own page table
+const EARLY_PTE_FLAGS: u64 = EARLY_PTE_PRESENT | (0x1 << 1);
+/// Physical address bits of an entry
+const EARLY_PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Offset of the running image from its physical address, 0 until relocated
+static IMAGE_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RelocateErr {
+	/// The image has a relocation of the contained type, which is not supported
+	UnsupportedRelocation(u32),
+	/// The firmware could not allocate a page table
+	OutOfMemory,
+}
+
+/// An entry of `.rela.dyn`
+#[repr(C)]
+struct Elf64Rela {
+	offset: u64,
+	info: u64,
+	addend: i64,
+}
+
+/// Maps the image into the higher half and relocates it there, see the module docs.
+/// 
+/// Must be called exactly once on the bootstrap processor before anything stored pointers
+/// into the image. The caller keeps running at the link address and has to continue in the
+/// relocated image through [`image_phys_to_virt`]. On error nothing has been changed.
+pub unsafe fn relocate_kernel(boot_services: &BootServices) -> Result<(), RelocateErr> {
+	extern "C" {
+		static __kernel_image_start: u8;
+		static __kernel_image_end: u8;
+		static __rela_dyn_start: Elf64Rela;
+		static __rela_dyn_end: Elf64Rela;
+	}
+	
+	// Still running at the link address, so these are physical addresses
+	let image_start = &__kernel_image_start as *const u8 as usize;
+	let image_size = &__kernel_image_end as *const u8 as usize - image_start;
+	
+	let relas = {
+		let start = &__rela_dyn_start as *const Elf64Rela;
+		let count = (&__rela_dyn_end as *const Elf64Rela).offset_from(start) as usize;
+		slice::from_raw_parts(start, count)
+	};
+	if let Some(rela) = relas.iter().find(|rela| rela.info as u32 != R_X86_64_RELATIVE) {
+		return Err(RelocateErr::UnsupportedRelocation(rela.info as u32));
+	}
+	
+	let slot_count = (KERNEL_IMAGE_REGION_SIZE - image_size) / BASE_PAGE_SIZE;
+	let image_base = match random_below(slot_count) {
+		Some(slot) => KERNEL_IMAGE_BASE + slot * BASE_PAGE_SIZE,
+		None => KERNEL_IMAGE_BASE + image_start,
+	};
+	map_image_early(boot_services, image_base, image_start, image_size)?;
+	
+	// Written through the new mapping as the firmware may have mapped the loaded image read only
+	let offset = image_base.wrapping_sub(image_start);
+	for rela in relas {
+		let place = (rela.offset as usize).wrapping_add(offset) as *mut u64;
+		place.write_unaligned((rela.addend as u64).wrapping_add(offset as u64));
+	}
+	
+	IMAGE_OFFSET.store(offset, SeqCst);
+	Ok(())
+}
+
+/// Offset of the virtual addresses of the running image from its physical addresses
+#[inline]
+pub fn image_offset() -> usize {
+	IMAGE_OFFSET.load(Relaxed)
+}
+
+/// The address of a physical address of the image in the running image
+#[inline]
+pub fn image_phys_to_virt(phys_addr: usize) -> usize {
+	phys_addr.wrapping_add(image_offset())
+}
+
+/// The physical address of an address in the running image
+#[inline]
+pub fn image_virt_to_phys(virt_addr: usize) -> usize {
+	virt_addr.wrapping_sub(image_offset())
+}
+
+/// A random multiple of `align` below `max`, or 0 if randomization is off
+pub fn random_slide(max: usize, align: usize) -> usize {
+	match random_below(max / align) {
+		Some(slot) => slot * align,
+		None => 0,
+	}
+}
+
+/// A uniformly distributed random number below `bound`,
+/// `None` if randomization is off or `bound` is 0
+fn random_below(bound: usize) -> Option<usize> {
+	if bound == 0 {
+		return None;
+	}
+	
+	// Taking the remainder of anything above the last whole multiple
+	// of `bound` would favor the low numbers, so those are rejected
+	let bound = bound as u64;
+	let rejected_count = (u64::MAX % bound + 1) % bound;
+	loop {
+		let rand = random_u64()?;
+		if rand <= u64::MAX - rejected_count {
+			return Some((rand % bound) as usize);
+		}
+	}
+}
+
+#[inline]
+fn random_u64() -> Option<u64> {
+	if no_kaslr() {
+		None
+	} else {
+		hw_random_u64()
+	}
+}
+
+/// Maps the image at `virt_base` in a copy of the current (firmware) page table and
+/// switches to it. The tables are boot services data, so they are only reclaimed once
+/// the kernel switched to its own address space.
+unsafe fn map_image_early(boot_services: &BootServices, virt_base: usize, phys_start: usize, size: usize) -> Result<(), RelocateErr> {
+	let root_level = if is_la57_active() { 4 } else { 3 };
+	
+	// The firmware identity maps all memory, so tables are accessed at their physical address
+	let alloc_table = || -> Result<*mut u64, RelocateErr> {
+		let table = boot_services.allocate_pages(AllocateType::AnyPages, MemoryType::BOOT_SERVICES_DATA, 1)
+			.map_err(|_| RelocateErr::OutOfMemory)? as *mut u64;
+		ptr::write_bytes(table, 0, BASE_PAGE_SIZE / 8);
+		Ok(table)
+	};
+	
+	let root = alloc_table()?;
+	ptr::copy_nonoverlapping((read_cr3() as u64 & EARLY_PTE_ADDR_MASK) as *const u64, root, BASE_PAGE_SIZE / 8);
+	
+	// The firmware has nothing in the higher half, new tables there keep its own tables untouched
+	for idx in table_idx(virt_base, root_level)..=table_idx(virt_base + size - 1, root_level) {
+		assert_eq!(*root.add(idx) & EARLY_PTE_PRESENT, 0, "The firmware maps memory where the kernel image goes");
+		*root.add(idx) = alloc_table()? as u64 | EARLY_PTE_FLAGS;
+	}
+	
+	for page_offset in (0..size).step_by(BASE_PAGE_SIZE) {
+		let virt_addr = virt_base + page_offset;
+		
+		let mut table = root;
+		for level in (1..=root_level).rev() {
+			let entry = table.add(table_idx(virt_addr, level));
+			if (*entry & EARLY_PTE_PRESENT) == 0 {
+				*entry = alloc_table()? as u64 | EARLY_PTE_FLAGS;
+			}
+			table = (*entry & EARLY_PTE_ADDR_MASK) as *mut u64;
+		}
+		*table.add(table_idx(virt_addr, 0)) = (phys_start + page_offset) as u64 | EARLY_PTE_FLAGS;
+	}
+	
+	write_cr3(root as usize);
+	Ok(())
+}
+
+#[inline]
+const fn table_idx(virt_addr: usize, level: usize) -> usize {
+	(virt_addr >> (12 + 9 * level)) & 0x1ff
+}
@@ -1,4 +1,415 @@
+//! The kernel's own address space
+//! 
+//! Layout of the upper half (five-level paging in parentheses):
+//! - `0xffff_8880_0000_0000` (`0xff11_0000_0000_0000`): Direct map of all physical
+//!   memory in the UEFI memory map, see [`phys_to_virt`]
+//! - `0xffff_c900_0000_0000` (`0xffa0_0000_0000_0000`): Virtually contiguous
+//!   allocations and device memory, see [`crate::mem::virt::vmalloc`] and
+//!   [`crate::mem::virt::ioremap`]
+//! - [`KERNEL_IMAGE_BASE`]: The kernel image, mapped per section with its
+//!   permissions (see [`KernelSection`])
+//! 
+//! The direct map and the vmalloc area start at a random offset into the first half of
+//! their region and the image is placed at a random address in its region, see
+//! [`crate::mem::kaslr`].
+//! 
+//! The image is loaded at a low physical address and relocated into the higher half before
+//! the kernel starts. It is also mapped at its physical address until the switch to this
+//! address space, which may have to run identity mapped code (see
+//! [`crate::arch::x86_64::paging::switch_paging_mode`]). The firmware stack the bootstrap
+//! processor is running on stays identity mapped until it has switched to a kernel stack,
+//! see [`unmap_boot_stack`].
+//! 
+//! Every user address space shares these mappings (see [`crate::mem::virt::MemMap`]).
 
+use core::arch::asm;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::Ordering::*;
+
+use uefi_rs::table::boot::{MemoryAttribute, MemoryDescriptor, MemoryType};
+
+use crate::arch::x86_64::paging::enable_write_protect;
+use crate::mem::{PhysAddr, VirtAddr, VirtRange};
+use crate::mem::kaslr::{image_offset, image_virt_to_phys, random_slide};
+use crate::mem::phys::{reserved_ranges, ReservedKind};
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BASE_PAGE_SIZE};
+use crate::mem::virt::{MapErr, PAGE_TABLE_ENTRIES, PageTable, paging_levels, PteFlags};
+
+/// Start of the direct map with four-level paging
+pub const DIRECT_MAP_BASE_4_LEVEL: usize = 0xffff_8880_0000_0000;
+/// Size of the direct map with four-level paging (64 TiB)
+pub const DIRECT_MAP_SIZE_4_LEVEL: usize = 0x1 << 46;
+/// Start of the direct map with five-level paging
+pub const DIRECT_MAP_BASE_5_LEVEL: usize = 0xff11_0000_0000_0000;
+/// Size of the direct map with five-level paging (32 PiB)
+pub const DIRECT_MAP_SIZE_5_LEVEL: usize = 0x1 << 55;
+
+/// Start of the vmalloc area with four-level paging
+pub const VMALLOC_BASE_4_LEVEL: usize = 0xffff_c900_0000_0000;
+/// Size of the vmalloc area with four-level paging (32 TiB)
+pub const VMALLOC_SIZE_4_LEVEL: usize = 0x1 << 45;
+/// Start of the vmalloc area with five-level paging
+pub const VMALLOC_BASE_5_LEVEL: usize = 0xffa0_0000_0000_0000;
+/// Size of the vmalloc area with five-level paging (4 PiB)
+pub const VMALLOC_SIZE_5_LEVEL: usize = 0x1 << 52;
+
+/// Start of the region the kernel image is placed in
+pub const KERNEL_IMAGE_BASE: usize = 0xffff_ffff_8000_0000;
+/// Size of the kernel image region (1 GiB), inside of the top 2 GiB the kernel code model requires
+pub const KERNEL_IMAGE_REGION_SIZE: usize = 0x1 << 30;
+
+/// Alignment of the random offsets of the direct map and the vmalloc area,
+/// which keeps 1 GiB pages usable in the direct map
+const REGION_SLIDE_ALIGN: usize = 0x1 << 30;
+
+/// Random offsets of the direct map and the vmalloc area into their regions
+static DIRECT_MAP_SLIDE: AtomicUsize = AtomicUsize::new(0);
+static VMALLOC_SLIDE: AtomicUsize = AtomicUsize::new(0);
+
+/// Offset added to physical addresses to access them, 0 while still identity mapped
+static PHYS_MAP_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+static mut KERNEL_MEM_MAP: MaybeUninit<KernelMemMap> = MaybeUninit::uninit();
+static KERNEL_MEM_MAP_READY: AtomicBool = AtomicBool::new(false);
+static KERNEL_MEM_MAP_LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// The address through which the kernel accesses the physical address.
+/// 
+/// Before [`init_kernel_mem_map`] this is the physical address itself as the
+/// firmware identity maps everything, afterwards it is in the direct map.
+#[inline]
+pub fn phys_to_virt(phys_addr: usize) -> usize {
+	phys_addr + PHYS_MAP_OFFSET.load(Relaxed)
+}
+
+/// The physical address of a direct map address (or the address itself
+/// while the kernel still runs identity mapped, see [`phys_to_virt`])
+#[inline]
+pub fn direct_map_to_phys(virt_addr: VirtAddr) -> Option<PhysAddr> {
+	let offset = PHYS_MAP_OFFSET.load(Relaxed);
+	
+	if offset == 0 {
+		PhysAddr::try_new(virt_addr.as_usize())
+	} else if virt_addr.as_usize() >= offset && virt_addr.as_usize() - offset < direct_map_size() {
+		Some(PhysAddr::new(virt_addr.as_usize() - offset))
+	} else {
+		None
+	}
+}
+
+/// Whether the page at the physical address is in the direct map, which is the
+/// case for all RAM (see [`init_kernel_mem_map`])
+pub fn is_direct_mapped(phys_addr: PhysAddr) -> bool {
+	phys_addr.as_usize() < direct_map_size()
+		&& with_kernel_mem_map(|map| map.page_table().translate(direct_map_base() + phys_addr.as_usize()).is_some())
+}
+
+/// Start of the direct map
+#[inline]
+pub fn direct_map_base() -> usize {
+	direct_map_region().start.as_usize() + DIRECT_MAP_SLIDE.load(Relaxed)
+}
+
+/// Size of the direct map, i.e. the rest of its region
+#[inline]
+pub fn direct_map_size() -> usize {
+	direct_map_region().size() - DIRECT_MAP_SLIDE.load(Relaxed)
+}
+
+/// The virtual range [`crate::mem::virt::vmalloc`] allocates from, half of its region
+#[inline]
+pub fn vmalloc_range() -> VirtRange {
+	let region = vmalloc_region();
+	VirtRange::with_size(region.start + VMALLOC_SLIDE.load(Relaxed), region.size() / 2)
+}
+
+/// The region the direct map is placed in for the paging mode
+#[inline]
+fn direct_map_region() -> VirtRange {
+	if paging_levels() == 5 {
+		VirtRange::with_size(VirtAddr::new(DIRECT_MAP_BASE_5_LEVEL), DIRECT_MAP_SIZE_5_LEVEL)
+	} else {
+		VirtRange::with_size(VirtAddr::new(DIRECT_MAP_BASE_4_LEVEL), DIRECT_MAP_SIZE_4_LEVEL)
+	}
+}
+
+/// The region the vmalloc area is placed in for the paging mode
+#[inline]
+fn vmalloc_region() -> VirtRange {
+	if paging_levels() == 5 {
+		VirtRange::with_size(VirtAddr::new(VMALLOC_BASE_5_LEVEL), VMALLOC_SIZE_5_LEVEL)
+	} else {
+		VirtRange::with_size(VirtAddr::new(VMALLOC_BASE_4_LEVEL), VMALLOC_SIZE_4_LEVEL)
+	}
+}
+
+/// The page table shared by the kernel part of all address spaces
 pub struct KernelMemMap {
+	page_table: PageTable,
+}
+
+impl KernelMemMap {
+	fn build<'a>(mmap: impl Iterator<Item = &'a MemoryDescriptor>) -> Result<Self, MapErr> {
+		let mut map = Self {
+			page_table: PageTable::new()?,
+		};
+		
+		// Map adjacent descriptors in one go so huge pages can be used across them.
+		// A run ends at anything else, so no (huge) page ever covers device memory.
+		let mut run: Option<(usize, usize)> = None;
+		for desc in mmap.filter(|desc| is_write_back_ram(desc)) {
+			let start = desc.phys_start as usize;
+			let end = start + ((desc.page_count as usize) << BASE_PAGE_ADDR_BITS);
+			
+			run = match run {
+				Some((run_start, run_end)) if run_end == start => Some((run_start, end)),
+				Some((run_start, run_end)) => {
+					map.map_direct(run_start, run_end)?;
+					Some((start, end))
+				},
+				None => Some((start, end)),
+			};
+		}
+		if let Some((run_start, run_end)) = run {
+			map.map_direct(run_start, run_end)?;
+		}
+		
+		for range in reserved_ranges() {
+			match range.kind {
+				ReservedKind::BootStack => {
+					map.page_table.map(range.start, range.start, range.end - range.start, PteFlags::WRITABLE | PteFlags::NO_EXECUTE)?;
+				},
+				_ => {},
+			}
+		}
+		
+		map.map_kernel_image()?;
+		
+		// User page tables only share the first root entry of the lower half, see crate::mem::virt::user_range
+		assert!(
+			(1..(PAGE_TABLE_ENTRIES / 2)).all(|idx| !map.page_table.is_root_entry_present(idx)),
+			"The kernel has mappings in the user part of the lower half",
+		);
+		
+		// User address spaces share the upper half tables, see PageTable::new_user
+		map.page_table.populate_upper_half()?;
+		
+		Ok(map)
+	}
+	
+	#[inline]
+	pub fn page_table(&self) -> &PageTable {
+		&self.page_table
+	}
+	
+	#[inline]
+	pub fn page_table_mut(&mut self) -> &mut PageTable {
+		&mut self.page_table
+	}
+	
+	fn map_direct(&mut self, start: usize, end: usize) -> Result<(), MapErr> {
+		assert!(end <= direct_map_size(), "Physical memory at {:#x} is out of reach of the direct map", end);
+		
+		self.page_table.map(direct_map_base() + start, start, end - start, PteFlags::WRITABLE | PteFlags::GLOBAL | PteFlags::NO_EXECUTE)
+	}
+	
+	/// Maps every page of the running image with the permissions of its section,
+	/// and temporarily at its physical address (see [`Self::unmap_image_alias`])
+	fn map_kernel_image(&mut self) -> Result<(), MapErr> {
+		extern "C" {
+			static __kernel_image_start: u8;
+			static __kernel_image_end: u8;
+			static __text_start: u8;
+			static __rodata_start: u8;
+			static __data_start: u8;
+		}
+		
+		let sym = |sym: &u8| sym as *const u8 as usize;
+		let (image_start, image_end) = unsafe { (sym(&__kernel_image_start), sym(&__kernel_image_end)) };
+		
+		// Otherwise a page could end up both writable and executable
+		let bounds = unsafe { [image_start, image_end, sym(&__text_start), sym(&__rodata_start), sym(&__data_start)] };
+		assert!(
+			bounds.iter().all(|addr| addr & (BASE_PAGE_SIZE - 1) == 0),
+			"Kernel image sections are not page aligned, is the image linked with kernel.ld?",
+		);
+		
+		for page_addr in (image_start..image_end).step_by(BASE_PAGE_SIZE) {
+			let flags = kernel_image_section(page_addr)
+				.expect("Page of the kernel image in no section")
+				.pte_flags();
+			let phys_addr = image_virt_to_phys(page_addr);
+			
+			self.page_table.map(page_addr, phys_addr, BASE_PAGE_SIZE, flags | PteFlags::GLOBAL)?;
+			if phys_addr != page_addr {
+				self.page_table.map(phys_addr, phys_addr, BASE_PAGE_SIZE, flags)?;
+			}
+		}
+		Ok(())
+	}
+	
+	/// Removes the identity mapping of the image once the kernel runs in this address space.
+	/// Nothing is mapped there if the kernel could not be relocated and still runs from it.
+	fn unmap_image_alias(&mut self) -> Result<(), MapErr> {
+		extern "C" {
+			static __kernel_image_start: u8;
+			static __kernel_image_end: u8;
+		}
+		
+		if image_offset() == 0 {
+			return Ok(());
+		}
+		
+		let (image_start, image_end) = unsafe {
+			(&__kernel_image_start as *const u8 as usize, &__kernel_image_end as *const u8 as usize)
+		};
+		self.page_table.unmap(image_virt_to_phys(image_start), image_end - image_start)
+	}
+}
+
+/// Removes the identity mapping of the firmware stack (see [`crate::mem::phys::ReservedKind::BootStack`]).
+/// 
+/// Must only be called once the bootstrap processor runs on a kernel stack, nothing
+/// may refer to the boot stack anymore.
+pub unsafe fn unmap_boot_stack() {
+	with_kernel_mem_map(|map| {
+		for range in reserved_ranges().iter().filter(|range| range.kind == ReservedKind::BootStack) {
+			map.page_table.unmap(range.start, range.end - range.start)
+				.expect("Failed to unmap the identity mapped boot stack");
+		}
+	});
+}
+
+/// Builds the kernel address space and switches to it.
+/// 
+/// Must be called exactly once on the bootstrap processor after the frame allocator
+/// is up and our own GDT is loaded (it is needed to switch between four- and
+/// five-level paging). Afterwards nothing but the kernel image and the boot stack
+/// must be accessed through identity mapped pointers, and the kernel can't write
+/// to its own code, read only data or copy-on-write pages anymore.
+pub unsafe fn init_kernel_mem_map<'a>(mmap: impl Iterator<Item = &'a MemoryDescriptor>) {
+	// Place the regions, whose sizes depend on the paging mode
+	DIRECT_MAP_SLIDE.store(random_slide(direct_map_region().size() / 2, REGION_SLIDE_ALIGN), SeqCst);
+	VMALLOC_SLIDE.store(random_slide(vmalloc_region().size() / 2, REGION_SLIDE_ALIGN), SeqCst);
+	
+	let mut map = KernelMemMap::build(mmap)
+		.expect("Failed to build the kernel address space");
+	
+	map.page_table.activate();
+	PHYS_MAP_OFFSET.store(direct_map_base(), SeqCst);
+	
+	map.unmap_image_alias()
+		.expect("Failed to unmap the identity mapped kernel image");
+	
+	// Make read only pages read only for the kernel as well, otherwise kernel
+	// writes to copy-on-write user pages would go to the shared frame
+	enable_write_protect();
+	
+	KERNEL_MEM_MAP.write(map);
+	KERNEL_MEM_MAP_READY.store(true, Release);
+}
+
+/// Whether [`init_kernel_mem_map`] has run, i.e. the direct map is in use
+#[inline]
+pub fn is_kernel_mem_map_ready() -> bool {
+	KERNEL_MEM_MAP_READY.load(Acquire)
+}
+
+/// Runs `f` with exclusive access to the kernel address space
+pub fn with_kernel_mem_map<R>(f: impl FnOnce(&mut KernelMemMap) -> R) -> R {
+	assert!(is_kernel_mem_map_ready(), "The kernel address space is not set up yet");
+	
+	while KERNEL_MEM_MAP_LOCKED.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+		unsafe {
+			asm!("pause", options(nomem, nostack));
+		}
+	}
+	
+	let ret = f(unsafe { KERNEL_MEM_MAP.assume_init_mut() });
+	KERNEL_MEM_MAP_LOCKED.store(false, Release);
+	ret
+}
+
+/// Like [`with_kernel_mem_map`], but returns `None` instead of waiting if the
+/// kernel address space is locked already
+pub fn try_with_kernel_mem_map<R>(f: impl FnOnce(&mut KernelMemMap) -> R) -> Option<R> {
+	assert!(is_kernel_mem_map_ready(), "The kernel address space is not set up yet");
+	
+	if KERNEL_MEM_MAP_LOCKED.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+		return None;
+	}
+	
+	let ret = f(unsafe { KERNEL_MEM_MAP.assume_init_mut() });
+	KERNEL_MEM_MAP_LOCKED.store(false, Release);
+	Some(ret)
+}
+
+/// Whether the descriptor is RAM that belongs in the direct map. RAM the firmware
+/// doesn't allow to be cached write-back is left to [`crate::mem::virt::ioremap`].
+#[inline]
+fn is_write_back_ram(desc: &MemoryDescriptor) -> bool {
+	is_ram(desc.ty) && desc.att.contains(MemoryAttribute::WRITE_BACK)
+}
+
+/// Whether memory of the type is RAM
+#[inline]
+fn is_ram(ty: MemoryType) -> bool {
+	match ty {
+		MemoryType::CONVENTIONAL
+			| MemoryType::LOADER_CODE | MemoryType::LOADER_DATA
+			| MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA
+			| MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA
+			| MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE
+			| MemoryType::PERSISTENT_MEMORY => true,
+		_ => false,
+	}
+}
+
+/// A part of the kernel image with its own page permissions.
+/// 
+/// `kernel.ld` aligns the sections to pages, so every page belongs to exactly one
+/// of them and no page is ever both writable and executable.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum KernelSection {
+	/// `.text`, readable and executable
+	Text,
+	/// `.rodata`, `.data.rel.ro` and `.got`, only readable
+	ReadOnlyData,
+	/// `.data`, `.bss` and any orphan sections, readable and writable
+	Data,
+}
+
+impl KernelSection {
+	pub const fn pte_flags(self) -> PteFlags {
+		match self {
+			Self::Text => PteFlags::EMPTY,
+			Self::ReadOnlyData => PteFlags::NO_EXECUTE,
+			Self::Data => PteFlags(PteFlags::WRITABLE.0 | PteFlags::NO_EXECUTE.0),
+		}
+	}
+}
+
+/// The section of the running kernel image containing the address
+pub fn kernel_image_section(addr: usize) -> Option<KernelSection> {
+	extern "C" {
+		static __kernel_image_start: u8;
+		static __kernel_image_end: u8;
+		static __text_end: u8;
+		static __rodata_end: u8;
+	}
+	
+	let sym = |sym: &u8| sym as *const u8 as usize;
 	
+	unsafe {
+		if addr < sym(&__kernel_image_start) || addr >= sym(&__kernel_image_end) {
+			None
+		} else if addr < sym(&__text_end) {
+			Some(KernelSection::Text)
+		} else if addr < sym(&__rodata_end) {
+			Some(KernelSection::ReadOnlyData)
+		} else {
+			Some(KernelSection::Data)
+		}
+	}
 }
@@ -1,5 +1,8 @@
 pub use addr::*;
 
+pub mod heap;
+pub mod kaslr;
+pub mod kernel_mem_map;
 pub mod phys;
 pub mod virt;
 mod addr;
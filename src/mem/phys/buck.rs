@@ -9,9 +9,11 @@
 //! 
 //! Note: Right now I implement a very inefficient dumb buddy allocator, but it'll work *shrug*
 
+use core::fmt;
 use core::ptr;
 use core::ptr::NonNull;
 use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::*;
 
 use crate::cpu::CpuUid;
 use crate::Phys;
@@ -22,6 +24,13 @@ pub const BUCK_LOWER_TREE_MAX_LEVEL: usize = 5;
 pub const BASE_PAGE_ADDR_BITS: usize = 12;
 pub const BASE_PAGE_SIZE: usize = (0x1 << BASE_PAGE_ADDR_BITS);
 
+/// Number of base pages managed by a single shard
+pub const BUCK_SHARD_PAGES: usize = (0x1 << BUCK_UPPER_TREE_MAX_LEVEL);
+/// Number of bytes managed by a single shard
+pub const BUCK_SHARD_SIZE: usize = (BASE_PAGE_SIZE << BUCK_UPPER_TREE_MAX_LEVEL);
+/// Number of distinct block sizes
+pub const BUCK_LEVEL_COUNT: usize = BUCK_UPPER_TREE_MAX_LEVEL + 1;
+
 /// Platform base page page (as opposed to huge or giga pages)
 #[repr(C, align(4096))]
 pub struct BasePage([u64; 4096/8]);
@@ -32,9 +41,9 @@ pub struct BasePage([u64; 4096/8]);
 /// and has thus no synchronization
 ///
 /// Freeing is harder since memory allocated by this shard's
-/// cpu can be freed from another cpu. Because of that we store
-/// atomic u64 that represent which blocks to be freed on the next
-/// malloc (which will always happen on the owning cpu)
+/// cpu can be freed from another cpu. Because of that those frees
+/// are queued in the shard's [`BuckRemoteFrees`] and processed on
+/// the next malloc (which will always happen on the owning cpu)
 #[repr(C)]
 pub struct BuckShard {
 	/// A packed 6-high binary tree where each node is one bit.
@@ -56,13 +65,38 @@ pub struct BuckShard {
 	/// TODO: Maybe we want shards to be owned by cpus and by isrs if they need to do any allocation
 	owning_cpu: CpuUid,
 	
-	free_in_upper: AtomicU64,
-	free_lowers: AtomicU64,
-	free_in_lowers: [AtomicU64; 64],
+	/// Number of blocks currently handed out by [`Self::alloc`] per level
+	used_blocks: [u32; BUCK_LEVEL_COUNT],
+	/// Number of pages claimed as ranges instead of blocks
+	/// (reservations and contiguous allocations)
+	claimed_pages: u32,
+	/// Number of calls to [`Self::alloc`] that found no free block
+	failed_allocs: u32,
+	
+	/// The node of every block currently handed out by [`Self::alloc`],
+	/// to catch double frees and frees of never allocated blocks
+	#[cfg(feature = "debug_phys_frames")]
+	alloced_upper_nodes: u64,
+	#[cfg(feature = "debug_phys_frames")]
+	alloced_lower_nodes: [u64; 64],
 }
 
 impl BuckShard {
 	pub unsafe fn alloc(&mut self, level: usize) -> Option<BuckBlock> {
+		let block = self.alloc_block(level);
+		
+		if let Some(block) = &block {
+			self.used_blocks[level] += 1;
+			
+			#[cfg(feature = "debug_phys_frames")]
+			self.track_alloc(self.node_of(block));
+		} else {
+			self.failed_allocs += 1;
+		}
+		block
+	}
+	
+	unsafe fn alloc_block(&mut self, level: usize) -> Option<BuckBlock> {
 		// Check level upper bound
 		if level > BUCK_UPPER_TREE_MAX_LEVEL {
 			return None;
@@ -80,7 +114,7 @@ impl BuckShard {
 			self.lower_trees_alloced_by_upper |= UPPER_ALLOC_IDX_TO_LOWER_TREE_USED_FLAGS[upper_idx];
 			
 			// Return allocated block
-			let block_addr = (self.base_addr as usize) + (upper_idx_in_level << (BASE_PAGE_ADDR_BITS + level));
+			let block_addr = (self.base_addr.ptr() as usize) + (upper_idx_in_level << (BASE_PAGE_ADDR_BITS + level));
 			let block_size = (BASE_PAGE_SIZE << level) as u32;
 			
 			// Assert
@@ -122,32 +156,22 @@ impl BuckShard {
 			// Allocate in lower tree
 			let lower_tree_ref = unsafe { self.lower_trees.get_unchecked_mut(lower_tree_idx) };
 			
-			let mut is_now_full = false;
-			let (_, block_idx_in_level) = alloc_in_tree(lower_tree_ref, level, &mut is_now_full)?;
+			let (_, block_idx_in_level) = alloc_in_tree(lower_tree_ref, level, &mut false)?;
 			
-			// If level is now full, remove from available flags
-			if is_now_full {
-				self.lower_levels_available[level] &= !(0x1 << lower_tree_idx);
-			}
-			
-			// Mark upper tree parents as used
-			let mut upper_tree_new_bits = 0;
-			let mut upper_parent_idx = (lower_tree_idx >> 1) + 31;
-			
-//			// DEBUG:
-//			println!("upper_parent_idx {upper_parent_idx}");
+			// Allocating a block also uses up its parents (and children),
+			// so the availability of every level of this tree may have changed
+			self.sync_lower_levels_available(lower_tree_idx);
 			
-			for _ in 0..6 {
-				upper_tree_new_bits |= (0x1 << upper_parent_idx);
-				upper_parent_idx = (upper_parent_idx - 1) >> 1;
-			}
-			self.upper_tree |= upper_tree_new_bits;
+			// Mark the upper tree leaf covering this lower tree and its parents as used
+			mark_in_tree(&mut self.upper_tree, (lower_tree_idx >> 1) + 31);
 			
 //			// DEBUG:
 //			println!("block_idx {block_idx}");
 			
 			// Return allocated block
-			let block_addr = (self.base_addr as usize) + (block_idx_in_level << (BASE_PAGE_ADDR_BITS + level));
+			let block_addr = (self.base_addr.ptr() as usize)
+				+ (lower_tree_idx << (BASE_PAGE_ADDR_BITS + BUCK_LOWER_TREE_MAX_LEVEL))
+				+ (block_idx_in_level << (BASE_PAGE_ADDR_BITS + level));
 			let block_size = (BASE_PAGE_SIZE << level) as u32;
 			
 			return Some(BuckBlock {
@@ -158,28 +182,505 @@ impl BuckShard {
 		}
 	}
 	
-	/// Actually processes queued frees.
+	/// Frees a block previously allocated from this shard and
+	/// merges it with its buddies as far up as possible.
 	/// Must only be called by the shard's owning cpu
 	pub unsafe fn free_local(&mut self, block: BuckBlock) {
-		todo!()
+		let node = self.node_of(&block);
+		
+		#[cfg(feature = "debug_phys_frames")]
+		self.track_free(node);
+		
+		self.used_blocks[block.level as usize] -= 1;
+		
+		match node {
+			BuckNode::Upper(upper_idx) => self.free_upper_node(upper_idx),
+			BuckNode::Lower(lower_tree_idx, lower_idx) => self.free_lower_node(lower_tree_idx, lower_idx),
+		}
+	}
+	
+	/// Frees all blocks queued in `remote_frees`, which must belong to this shard.
+	/// Must only be called by the shard's owning cpu
+	pub unsafe fn drain_remote_frees(&mut self, remote_frees: &BuckRemoteFrees) {
+		debug_assert_eq!(remote_frees.base_addr, self.base_addr.ptr() as usize, "Drained the remote frees of another shard");
+		
+		if remote_frees.free_in_upper.load(Relaxed) != 0 {
+			let mut upper_bits = remote_frees.free_in_upper.swap(0, Acquire);
+			while upper_bits != 0 {
+				let upper_idx = upper_bits.trailing_zeros() as usize;
+				upper_bits &= upper_bits - 1;
+				
+				#[cfg(feature = "debug_phys_frames")]
+				self.track_free(BuckNode::Upper(upper_idx));
+				
+				self.free_upper_node(upper_idx);
+				self.used_blocks[node_level(BuckNode::Upper(upper_idx))] -= 1;
+			}
+		}
+		
+		if remote_frees.free_lowers.load(Relaxed) != 0 {
+			let mut lower_tree_bits = remote_frees.free_lowers.swap(0, Acquire);
+			while lower_tree_bits != 0 {
+				let lower_tree_idx = lower_tree_bits.trailing_zeros() as usize;
+				lower_tree_bits &= lower_tree_bits - 1;
+				
+				// A racing free_remote may have already set its node bit but
+				// not yet its tree bit. We then simply free it early here and
+				// find an empty mask for the stale tree bit on the next drain.
+				let mut lower_bits = remote_frees.free_in_lowers[lower_tree_idx].swap(0, Acquire);
+				while lower_bits != 0 {
+					let lower_idx = lower_bits.trailing_zeros() as usize;
+					lower_bits &= lower_bits - 1;
+					
+					#[cfg(feature = "debug_phys_frames")]
+					self.track_free(BuckNode::Lower(lower_tree_idx, lower_idx));
+					
+					self.free_lower_node(lower_tree_idx, lower_idx);
+					self.used_blocks[node_level(BuckNode::Lower(lower_tree_idx, lower_idx))] -= 1;
+				}
+			}
+		}
+	}
+	
+	unsafe fn free_upper_node(&mut self, upper_idx: usize) {
+		// Lower trees covered by the block are usable on their own again
+		self.lower_trees_alloced_by_upper &= !UPPER_ALLOC_IDX_TO_LOWER_TREE_USED_FLAGS[upper_idx];
+		
+		free_in_tree(&mut self.upper_tree, upper_idx);
+	}
+	
+	unsafe fn free_lower_node(&mut self, lower_tree_idx: usize, lower_idx: usize) {
+		let lower_tree_ref = self.lower_trees.get_unchecked_mut(lower_tree_idx);
+		free_in_tree(lower_tree_ref, lower_idx);
+		let lower_tree_now_free = (*lower_tree_ref == 0);
+		
+		self.sync_lower_levels_available(lower_tree_idx);
+		
+		// If the whole lower tree and its buddy tree are free now
+		// their shared upper tree leaf can be merged upwards as well
+		if lower_tree_now_free && self.lower_trees[lower_tree_idx ^ 0x1] == 0 {
+			let upper_leaf_idx = (lower_tree_idx >> 1) + 31;
+			
+			self.upper_tree &= !(0x1 << upper_leaf_idx);
+			coalesce_in_tree(&mut self.upper_tree, upper_leaf_idx);
+		}
+	}
+	
+	/// Recomputes the [`Self::lower_levels_available`] bits of a single lower tree
+	fn sync_lower_levels_available(&mut self, lower_tree_idx: usize) {
+		let tree = self.lower_trees[lower_tree_idx];
+		
+		for level in 0..=BUCK_LOWER_TREE_MAX_LEVEL {
+			let level_mask = ((0x1u64 << (32 >> level)) - 1) << level_first_idx(level);
+			
+			if (tree & level_mask) != level_mask {
+				self.lower_levels_available[level] |= (0x1 << lower_tree_idx);
+			} else {
+				self.lower_levels_available[level] &= !(0x1 << lower_tree_idx);
+			}
+		}
+	}
+	
+	/// Records the node of a block handed out by [`Self::alloc`]
+	#[cfg(feature = "debug_phys_frames")]
+	fn track_alloc(&mut self, node: BuckNode) {
+		match node {
+			BuckNode::Upper(upper_idx) => self.alloced_upper_nodes |= 0x1 << upper_idx,
+			BuckNode::Lower(lower_tree_idx, lower_idx) => self.alloced_lower_nodes[lower_tree_idx] |= 0x1 << lower_idx,
+		}
+	}
+	
+	/// Forgets the node of a freed block, panics if it is not currently allocated
+	#[cfg(feature = "debug_phys_frames")]
+	fn track_free(&mut self, node: BuckNode) {
+		let (alloced_nodes, node_bit) = match node {
+			BuckNode::Upper(upper_idx) => (&mut self.alloced_upper_nodes, 0x1 << upper_idx),
+			BuckNode::Lower(lower_tree_idx, lower_idx) => (&mut self.alloced_lower_nodes[lower_tree_idx], 0x1 << lower_idx),
+		};
+		
+		if (*alloced_nodes & node_bit) == 0 {
+			panic!("Double free or free of never allocated buck block {:#x} (level {})", self.node_addr(node), node_level(node));
+		}
+		*alloced_nodes &= !node_bit;
+	}
+	
+	/// Physical address of the block represented by the node
+	#[inline]
+	fn node_addr(&self, node: BuckNode) -> usize {
+		node_addr_in(self.base_addr.ptr() as usize, node)
+	}
+	
+	/// Locates the tree node representing the block
+	#[inline]
+	fn node_of(&self, block: &BuckBlock) -> BuckNode {
+		node_of_in(self.base_addr.ptr() as usize, block)
+	}
+	
+	/// Marks the pages `first_page..first_page+page_count` (relative to the shard base)
+	/// as allocated so they are never handed out.
+	/// 
+	/// The range is split into the biggest naturally aligned blocks
+	/// possible, which must all still be free.
+	pub fn reserve_range(&mut self, first_page: usize, page_count: usize) {
+		debug_assert!(first_page + page_count <= BUCK_SHARD_PAGES, "Reserved range must lie inside the shard");
+		
+		for node in range_nodes(first_page, page_count) {
+			let claimed = self.claim_node(node);
+			debug_assert!(claimed, "Reserved range overlaps an already allocated block");
+		}
+		self.claimed_pages += page_count as u32;
+	}
+	
+	/// Like [`Self::reserve_range`] but only reserves anything if
+	/// the whole range is still free. Returns whether it did.
+	pub fn try_reserve_range(&mut self, first_page: usize, page_count: usize) -> bool {
+		debug_assert!(first_page + page_count <= BUCK_SHARD_PAGES, "Reserved range must lie inside the shard");
+		
+		if !self.is_range_free(first_page, page_count) {
+			return false;
+		}
+		
+		for node in range_nodes(first_page, page_count) {
+			self.claim_node(node);
+		}
+		self.claimed_pages += page_count as u32;
+		true
+	}
+	
+	/// Frees a range previously reserved with exactly the same
+	/// bounds by [`Self::reserve_range`] or [`Self::try_reserve_range`].
+	/// Must only be called by the shard's owning cpu
+	pub unsafe fn release_range(&mut self, first_page: usize, page_count: usize) {
+		for node in range_nodes(first_page, page_count) {
+			match node {
+				BuckNode::Upper(upper_idx) => self.free_upper_node(upper_idx),
+				BuckNode::Lower(lower_tree_idx, lower_idx) => self.free_lower_node(lower_tree_idx, lower_idx),
+			}
+		}
+		self.claimed_pages -= page_count as u32;
+	}
+	
+	/// Whether none of the pages in the range are allocated (ignoring queued remote frees)
+	pub fn is_range_free(&self, first_page: usize, page_count: usize) -> bool {
+		range_nodes(first_page, page_count).all(|node| self.is_node_free(node))
+	}
+	
+	/// Whether the block represented by the node and all its children are free
+	fn is_node_free(&self, node: BuckNode) -> bool {
+		match node {
+			BuckNode::Upper(upper_idx) => (self.upper_tree & (0x1 << upper_idx)) == 0,
+			BuckNode::Lower(lower_tree_idx, lower_idx) => {
+				(self.lower_trees_alloced_by_upper & (0x1 << lower_tree_idx)) == 0
+					&& (self.lower_trees[lower_tree_idx] & (0x1 << lower_idx)) == 0
+			},
+		}
+	}
+	
+	/// Allocates the specific block represented by the node if it is entirely free
+	fn claim_node(&mut self, node: BuckNode) -> bool {
+		if !self.is_node_free(node) {
+			return false;
+		}
+		
+		match node {
+			BuckNode::Upper(upper_idx) => {
+				mark_in_tree(&mut self.upper_tree, upper_idx);
+				self.lower_trees_alloced_by_upper |= UPPER_ALLOC_IDX_TO_LOWER_TREE_USED_FLAGS[upper_idx];
+			},
+			BuckNode::Lower(lower_tree_idx, lower_idx) => {
+				mark_in_tree(&mut self.lower_trees[lower_tree_idx], lower_idx);
+				self.sync_lower_levels_available(lower_tree_idx);
+				
+				mark_in_tree(&mut self.upper_tree, (lower_tree_idx >> 1) + 31);
+			},
+		}
+		true
+	}
+	
+	/// Counts the free and used blocks of this shard.
+	/// 
+	/// Blocks queued by [`BuckRemoteFrees::free_remote`] still count as used
+	/// until drained. The remote free backlog is left at zero as the queue
+	/// is not part of the shard, see [`BuckRemoteFrees::backlog`].
+	pub fn stats(&self) -> BuckStats {
+		let mut stats = BuckStats::default();
+		
+		// Free blocks in the upper tree that are not part of an even bigger free block
+		for upper_level in 0..=(BUCK_UPPER_TREE_MAX_LEVEL - (BUCK_LOWER_TREE_MAX_LEVEL + 1)) {
+			stats.free_blocks[upper_level + (BUCK_LOWER_TREE_MAX_LEVEL + 1)] += count_unmerged_free_in_level(self.upper_tree, upper_level);
+		}
+		
+		// Free blocks in the lower trees whose upper leaf is (partially) used,
+		// otherwise they are part of a free upper block already counted above
+		for lower_tree_idx in 0..64 {
+			let upper_leaf_idx = (lower_tree_idx >> 1) + 31;
+			if (self.lower_trees_alloced_by_upper & (0x1 << lower_tree_idx)) != 0 || (self.upper_tree & (0x1 << upper_leaf_idx)) == 0 {
+				continue;
+			}
+			
+			for level in 0..=BUCK_LOWER_TREE_MAX_LEVEL {
+				stats.free_blocks[level] += count_unmerged_free_in_level(self.lower_trees[lower_tree_idx], level);
+			}
+		}
+		
+		for level in 0..BUCK_LEVEL_COUNT {
+			stats.used_blocks[level] = self.used_blocks[level] as usize;
+		}
+		stats.largest_free_level = (0..BUCK_LEVEL_COUNT).rev().find(|&level| stats.free_blocks[level] != 0);
+		stats.claimed_pages = self.claimed_pages as usize;
+		stats.failed_allocs = self.failed_allocs as usize;
+		stats.shard_count = 1;
+		
+		stats
+	}
+	
+	/// Copies the trees of this shard so they can be rendered without holding the shard
+	#[inline]
+	pub fn occupancy(&self) -> BuckOccupancy {
+		BuckOccupancy {
+			upper_tree: self.upper_tree,
+			lower_trees_alloced_by_upper: self.lower_trees_alloced_by_upper,
+			lower_trees: self.lower_trees,
+		}
+	}
+	
+	#[inline]
+	pub fn base_addr(&self) -> Phys<*mut BasePage> {
+		self.base_addr
+	}
+	
+	#[inline]
+	pub fn owning_cpu(&self) -> CpuUid {
+		self.owning_cpu
+	}
+	
+	/// Whether the physical address lies inside the memory managed by this shard
+	#[inline]
+	pub fn contains(&self, addr: usize) -> bool {
+		let base = self.base_addr.ptr() as usize;
+		addr >= base && (addr - base) < BUCK_SHARD_SIZE
 	}
 	
 	pub const fn new() -> Self {
-		const ATOMIC_ZERO: AtomicU64 = AtomicU64::new(0);
+		Self::new_at(Phys(ptr::null_mut()), CpuUid(0))
+	}
+	
+	/// Creates a fully free shard managing the [`BUCK_SHARD_SIZE`] bytes
+	/// starting at `base_addr`, which must be aligned to [`BUCK_SHARD_SIZE`]
+	pub const fn new_at(base_addr: Phys<*mut BasePage>, owning_cpu: CpuUid) -> Self {
 		Self {
 			upper_tree: 0,
 			lower_trees: [0; 64],
 			lower_trees_alloced_by_upper: 0,
 			lower_levels_available: [u64::MAX; 6],
 			
-			base_addr: Phys(ptr::null_mut()),
-			owning_cpu: CpuUid(0),
+			base_addr,
+			owning_cpu,
+			
+			used_blocks: [0; BUCK_LEVEL_COUNT],
+			claimed_pages: 0,
+			failed_allocs: 0,
 			
+			#[cfg(feature = "debug_phys_frames")]
+			alloced_upper_nodes: 0,
+			#[cfg(feature = "debug_phys_frames")]
+			alloced_lower_nodes: [0; 64],
+		}
+	}
+}
+
+/// Blocks of a [`BuckShard`] freed by cpus other than its owner, queued
+/// until the owner folds them in with [`BuckShard::drain_remote_frees`].
+/// 
+/// The queue lives next to the shard instead of inside of it, as other
+/// cpus must not even form a shared reference to the shard while its
+/// owner may be mutating it.
+pub struct BuckRemoteFrees {
+	/// Base address of the shard the queue belongs to
+	base_addr: usize,
+	free_in_upper: AtomicU64,
+	free_lowers: AtomicU64,
+	free_in_lowers: [AtomicU64; 64],
+}
+
+impl BuckRemoteFrees {
+	/// Creates an empty queue for the shard at `base_addr`
+	pub fn new_at(base_addr: Phys<*mut BasePage>) -> Self {
+		const ATOMIC_ZERO: AtomicU64 = AtomicU64::new(0);
+		Self {
+			base_addr: base_addr.ptr() as usize,
 			free_in_upper: ATOMIC_ZERO,
 			free_lowers: ATOMIC_ZERO,
 			free_in_lowers: [ATOMIC_ZERO; 64],
 		}
 	}
+	
+	/// Queues a block for freeing on the next allocation done by the
+	/// shard's owning cpu. Can be called from any cpu without synchronization.
+	pub unsafe fn free_remote(&self, block: BuckBlock) {
+		let node = node_of_in(self.base_addr, &block);
+		
+		let was_queued = match node {
+			BuckNode::Upper(upper_idx) => {
+				(self.free_in_upper.fetch_or(0x1 << upper_idx, Release) & (0x1 << upper_idx)) != 0
+			},
+			BuckNode::Lower(lower_tree_idx, lower_idx) => {
+				// Note: The node bit must be published before the tree bit
+				// as the drain only looks at trees flagged in `free_lowers`
+				let prev_bits = self.free_in_lowers[lower_tree_idx].fetch_or(0x1 << lower_idx, Release);
+				self.free_lowers.fetch_or(0x1 << lower_tree_idx, Release);
+				(prev_bits & (0x1 << lower_idx)) != 0
+			},
+		};
+		
+		if cfg!(feature = "debug_phys_frames") && was_queued {
+			panic!("Double free of buck block {:#x} (level {}), it is already queued for freeing", node_addr_in(self.base_addr, node), node_level(node));
+		}
+	}
+	
+	/// Number of blocks queued that have not been drained yet.
+	/// Can be called from any cpu but is only a rough snapshot then
+	pub fn backlog(&self) -> usize {
+		let lowers_backlog: u32 = self.free_in_lowers.iter()
+			.map(|bits| bits.load(Relaxed).count_ones())
+			.sum();
+		(self.free_in_upper.load(Relaxed).count_ones() + lowers_backlog) as usize
+	}
+}
+
+/// Physical address of the block represented by the node in the shard at `shard_base`
+#[inline]
+fn node_addr_in(shard_base: usize, node: BuckNode) -> usize {
+	shard_base + (node_first_page(node) << BASE_PAGE_ADDR_BITS)
+}
+
+/// Locates the tree node representing the block in the shard at `shard_base`
+#[inline]
+fn node_of_in(shard_base: usize, block: &BuckBlock) -> BuckNode {
+	let level = block.level as usize;
+	let offset = (block.ptr.ptr().as_ptr() as usize) - shard_base;
+	
+	debug_assert!(
+		(offset & ((BASE_PAGE_SIZE << level) - 1)) == 0 && (offset >> BASE_PAGE_ADDR_BITS) < BUCK_SHARD_PAGES,
+		"Buck block must lie inside the shard and be aligned to its own size",
+	);
+	
+	node_at(offset >> BASE_PAGE_ADDR_BITS, level)
+}
+
+/// Block counts of one or more shards, see [`BuckShard::stats`]
+#[derive(Clone, Default, Debug)]
+pub struct BuckStats {
+	pub shard_count: usize,
+	/// Number of free blocks per level that cannot be merged any further
+	/// (i.e. what a free list based buddy allocator would have in its lists)
+	pub free_blocks: [usize; BUCK_LEVEL_COUNT],
+	/// Number of blocks handed out by [`BuckShard::alloc`] per level
+	pub used_blocks: [usize; BUCK_LEVEL_COUNT],
+	/// Number of pages claimed as ranges (reservations and contiguous allocations)
+	pub claimed_pages: usize,
+	/// Level of the biggest free block, if any page is free at all
+	pub largest_free_level: Option<usize>,
+	/// Number of remotely freed blocks that have not been drained yet
+	pub remote_free_backlog: usize,
+	/// Number of shard allocations that failed. Note that the frame allocator
+	/// tries multiple shards per allocation, so this includes allocations that
+	/// succeeded on another shard in the end
+	pub failed_allocs: usize,
+}
+
+impl BuckStats {
+	pub fn total_pages(&self) -> usize {
+		self.shard_count * BUCK_SHARD_PAGES
+	}
+	
+	pub fn free_pages(&self) -> usize {
+		(0..BUCK_LEVEL_COUNT).map(|level| self.free_blocks[level] << level).sum()
+	}
+	
+	/// Pages handed out as blocks (not including claimed pages)
+	pub fn used_pages(&self) -> usize {
+		(0..BUCK_LEVEL_COUNT).map(|level| self.used_blocks[level] << level).sum()
+	}
+	
+	/// Adds the counts of another set of shards to these
+	pub fn merge(&mut self, other: &BuckStats) {
+		self.shard_count += other.shard_count;
+		for level in 0..BUCK_LEVEL_COUNT {
+			self.free_blocks[level] += other.free_blocks[level];
+			self.used_blocks[level] += other.used_blocks[level];
+		}
+		self.claimed_pages += other.claimed_pages;
+		self.largest_free_level = self.largest_free_level.max(other.largest_free_level);
+		self.remote_free_backlog += other.remote_free_backlog;
+		self.failed_allocs += other.failed_allocs;
+	}
+}
+
+/// A copy of the trees of a shard, see [`BuckShard::occupancy`]
+#[derive(Copy, Clone, Debug)]
+pub struct BuckOccupancy {
+	upper_tree: u64,
+	lower_trees_alloced_by_upper: u64,
+	lower_trees: [u64; 64],
+}
+
+impl BuckOccupancy {
+	/// Renders the upper tree level by level followed by one
+	/// character per lower tree:
+	/// `#` allocated by the upper tree, `x` fully used, `+` partially used, `.` free
+	pub fn write_to(&self, w: &mut impl fmt::Write) -> fmt::Result {
+		write_tree(w, self.upper_tree, BUCK_LOWER_TREE_MAX_LEVEL + 1)?;
+		
+		w.write_str("L0-5 ")?;
+		for lower_tree_idx in 0..64 {
+			let lower_tree = self.lower_trees[lower_tree_idx];
+			let leaves_mask = ((0x1u64 << 32) - 1) << level_first_idx(0);
+			
+			let c = if (self.lower_trees_alloced_by_upper & (0x1 << lower_tree_idx)) != 0 {
+				'#'
+			} else if (lower_tree & leaves_mask) == leaves_mask {
+				'x'
+			} else if lower_tree != 0 {
+				'+'
+			} else {
+				'.'
+			};
+			w.write_char(c)?;
+		}
+		w.write_char('\n')
+	}
+}
+
+/// Renders a packed tree one level per line, root first
+/// (`x` used or partially used, `.` free).
+/// `level_offset` is added to the printed level numbers.
+pub fn write_tree(w: &mut impl fmt::Write, tree: u64, level_offset: usize) -> fmt::Result {
+	let mut cursor: usize = 0;
+	for l in 0..6 {
+		write!(w, "L{:<3} ", (5 - l) + level_offset)?;
+		for _ in 0..(0x1 << l) {
+			if ((tree >> cursor) & 0x1) != 0 {
+				w.write_char('x')?;
+			} else {
+				w.write_char('.')?;
+			}
+			cursor += 1;
+		}
+		w.write_char('\n')?;
+	}
+	Ok(())
+}
+
+/// Number of free nodes in the level of a packed tree whose parent
+/// is (partially) used, so they cannot be merged any further
+#[inline]
+fn count_unmerged_free_in_level(tree: u64, level: usize) -> usize {
+	let first_idx = level_first_idx(level);
+	(first_idx..(first_idx + (32 >> level)))
+		.filter(|&idx| (tree & (0x1 << idx)) == 0)
+		.filter(|&idx| idx == 0 || (tree & (0x1 << ((idx - 1) >> 1))) != 0)
+		.count()
 }
 
 /// NOTE: Level goes from 5 (biggest) to 0 (smallest)
@@ -205,25 +706,143 @@ fn alloc_in_tree(tree: &mut u64, level: usize, is_now_full: &mut bool) -> Option
 
 //	println!("Free block idx {free_block_idx}");
 	
+	mark_in_tree(tree, free_block_idx);
+	
+	return Some((free_block_idx, free_block_idx_in_level));
+}
+
+/// Marks the node, all its children and all its parents as used
+#[inline(always)]
+fn mark_in_tree(tree: &mut u64, idx: usize) {
 	// Mark self and children as used
-	let mark_self_and_children = if free_block_idx < 31 {
-		MARK_SELF_AND_CHILDREN_LUT[free_block_idx]
+	let mark_self_and_children = if idx < 31 {
+		MARK_SELF_AND_CHILDREN_LUT[idx]
 	} else {
 		// We're in the lowest level, so just mark the block
 		// itself as used
-		(0x1 << free_block_idx)
+		(0x1 << idx)
 	};
-	let mut upper_tree_new_bits = mark_self_and_children;
+	let mut tree_new_bits = mark_self_and_children;
 	
 	// Mark parents as used
-	let mut parent_idx = free_block_idx;
-	for _ in 0..5 {
+	let mut parent_idx = idx;
+	while parent_idx != 0 {
 		parent_idx = (parent_idx - 1) >> 1;
-		upper_tree_new_bits |= (0x1 << parent_idx);
+		tree_new_bits |= (0x1 << parent_idx);
+	}
+	*tree |= tree_new_bits;
+}
+
+/// Locates the tree node of the block of the given level
+/// starting at the page index (relative to the shard base)
+#[inline]
+fn node_at(page_idx: usize, level: usize) -> BuckNode {
+	if level > BUCK_LOWER_TREE_MAX_LEVEL {
+		let upper_level = level - (BUCK_LOWER_TREE_MAX_LEVEL + 1);
+		BuckNode::Upper(level_first_idx(upper_level) + (page_idx >> level))
+	} else {
+		let lower_tree_idx = page_idx >> BUCK_LOWER_TREE_MAX_LEVEL;
+		let lower_idx = level_first_idx(level) + ((page_idx & ((0x1 << BUCK_LOWER_TREE_MAX_LEVEL) - 1)) >> level);
+		BuckNode::Lower(lower_tree_idx, lower_idx)
 	}
-	*tree |= upper_tree_new_bits;
+}
+
+/// Splits the page range into the biggest naturally aligned blocks possible
+fn range_nodes(first_page: usize, page_count: usize) -> impl Iterator<Item = BuckNode> + Clone {
+	let end_page = first_page + page_count;
+	let mut page_idx = first_page;
 	
-	return Some((free_block_idx, free_block_idx_in_level));
+	core::iter::from_fn(move || {
+		if page_idx >= end_page {
+			return None;
+		}
+		
+		let level = max_block_level_at(page_idx, end_page);
+		let node = node_at(page_idx, level);
+		page_idx += 0x1 << level;
+		
+		Some(node)
+	})
+}
+
+/// The level of the biggest block that starts at the page index
+/// (i.e. is naturally aligned) and does not extend past the end page
+#[inline]
+fn max_block_level_at(page_idx: usize, end_page: usize) -> usize {
+	let mut level = (page_idx.trailing_zeros() as usize).min(BUCK_UPPER_TREE_MAX_LEVEL);
+	while page_idx + (0x1 << level) > end_page {
+		level -= 1;
+	}
+	level
+}
+
+/// A node in one of the trees of a shard
+#[derive(Copy, Clone, Debug)]
+enum BuckNode {
+	/// Node index in the upper tree
+	Upper(usize),
+	/// Lower tree index and node index in that lower tree
+	Lower(usize, usize),
+}
+
+/// The block level of a node
+#[inline]
+fn node_level(node: BuckNode) -> usize {
+	match node {
+		BuckNode::Upper(upper_idx) => BUCK_UPPER_TREE_MAX_LEVEL - (63 - (upper_idx as u64 + 1).leading_zeros() as usize),
+		BuckNode::Lower(_, lower_idx) => BUCK_LOWER_TREE_MAX_LEVEL - (63 - (lower_idx as u64 + 1).leading_zeros() as usize),
+	}
+}
+
+/// Index of the first page of the block of a node (relative to the shard base)
+#[inline]
+fn node_first_page(node: BuckNode) -> usize {
+	let level = node_level(node);
+	match node {
+		BuckNode::Upper(upper_idx) => (upper_idx - level_first_idx(level - (BUCK_LOWER_TREE_MAX_LEVEL + 1))) << level,
+		BuckNode::Lower(lower_tree_idx, lower_idx) => {
+			(lower_tree_idx << BUCK_LOWER_TREE_MAX_LEVEL) + ((lower_idx - level_first_idx(level)) << level)
+		},
+	}
+}
+
+/// Index of the first node of the given level in a packed tree
+/// (level 5 is the root, level 0 are the leaves)
+#[inline(always)]
+const fn level_first_idx(level: usize) -> usize {
+	(32 >> level) - 1
+}
+
+/// Marks the node and all its children as free
+/// and then merges it with its buddies
+#[inline(always)]
+fn free_in_tree(tree: &mut u64, idx: usize) {
+	let mark_self_and_children = if idx < 31 {
+		MARK_SELF_AND_CHILDREN_LUT[idx]
+	} else {
+		(0x1 << idx)
+	};
+	*tree &= !mark_self_and_children;
+	
+	coalesce_in_tree(tree, idx);
+}
+
+/// Walks up from an already freed node, freeing each parent
+/// whose other child is also free
+#[inline(always)]
+fn coalesce_in_tree(tree: &mut u64, idx: usize) {
+	let mut idx = idx;
+	while idx != 0 {
+		let buddy_idx = if (idx & 0x1) != 0 { idx + 1 } else { idx - 1 };
+		
+		// Buddy (or one of its children) still in use, stop merging
+		if (*tree & (0x1 << buddy_idx)) != 0 {
+			break;
+		}
+		
+		idx = (idx - 1) >> 1;
+		*tree &= !(0x1 << idx);
+	}
 }
 
 /// An allocated buck allocator physical memory block
@@ -238,6 +857,35 @@ pub struct BuckBlock {
 	level: u32,
 }
 
+impl BuckBlock {
+	/// Recreates a block from its address and level, e.g. to free it when
+	/// only the address has been kept around.
+	/// The block must have been returned by [`BuckShard::alloc`] with exactly this level.
+	#[inline]
+	pub unsafe fn from_raw(ptr: Phys<NonNull<BasePage>>, level: u32) -> Self {
+		Self {
+			ptr,
+			real_size: (BASE_PAGE_SIZE << level) as u32,
+			level,
+		}
+	}
+	
+	#[inline]
+	pub fn ptr(&self) -> Phys<NonNull<BasePage>> {
+		self.ptr
+	}
+	
+	#[inline]
+	pub fn real_size(&self) -> u32 {
+		self.real_size
+	}
+	
+	#[inline]
+	pub fn level(&self) -> u32 {
+		self.level
+	}
+}
+
 unsafe impl Send for BuckBlock {}
 unsafe impl Sync for BuckBlock {}
 
@@ -345,41 +993,367 @@ const UPPER_ALLOC_IDX_TO_LOWER_TREE_USED_FLAGS: [u64; 63] = [
 	0b1100000000000000000000000000000000000000000000000000000000000000,
 ];
 
-/*
-#![allow(unused_parens)]
-
-use std::sync::atomic::*;
-
-pub fn main() {
-	unsafe {
-		let mut shard = BuckShard::new();
-		draw(shard.upper_tree);
-		shard.alloc(0);
-		draw(shard.upper_tree);
-		shard.alloc(0);
-		draw(shard.upper_tree);
-		shard.alloc(7);
-		draw(shard.upper_tree);
-		shard.alloc(9);
-		draw(shard.upper_tree);
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	/// Arbitrary shard aligned physical base address for the tests
+	const TEST_BASE_ADDR: usize = 0x4000_0000;
+	
+	/// Tiny xorshift rng so the tests are reproducible without pulling in deps
+	struct XorShift(u64);
+	
+	impl XorShift {
+		fn new(seed: u64) -> Self {
+			Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 0x1)
+		}
+		
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
+		}
+		
+		fn below(&mut self, bound: usize) -> usize {
+			(self.next() % bound as u64) as usize
+		}
+	}
+	
+	/// Reference model of a shard: simply one flag per base page
+	struct ShardModel {
+		used_pages: Vec<bool>,
+	}
+	
+	impl ShardModel {
+		fn new() -> Self {
+			Self {
+				used_pages: vec![false; BUCK_SHARD_PAGES],
+			}
+		}
+		
+		/// Whether any naturally aligned block of the level is entirely free
+		fn has_free_block(&self, level: usize) -> bool {
+			self.used_pages
+				.chunks(0x1 << level)
+				.any(|block| block.iter().all(|&used| !used))
+		}
+		
+		fn mark(&mut self, first_page: usize, page_count: usize, used: bool) {
+			for page in &mut self.used_pages[first_page..first_page + page_count] {
+				assert_ne!(*page, used, "Page marked twice");
+				*page = used;
+			}
+		}
+	}
+	
+	fn test_shard() -> Box<BuckShard> {
+		Box::new(BuckShard::new_at(Phys(TEST_BASE_ADDR as *mut BasePage), CpuUid(0)))
+	}
+	
+	fn test_remote_frees() -> BuckRemoteFrees {
+		BuckRemoteFrees::new_at(Phys(TEST_BASE_ADDR as *mut BasePage))
+	}
+	
+	fn first_page_of(block: &BuckBlock) -> usize {
+		((block.ptr().ptr().as_ptr() as usize) - TEST_BASE_ADDR) >> BASE_PAGE_ADDR_BITS
+	}
+	
+	/// Allocates and checks the result against the model
+	fn checked_alloc(shard: &mut BuckShard, model: &mut ShardModel, level: usize) -> Option<BuckBlock> {
+		let block = unsafe { shard.alloc(level) };
+		
+		match &block {
+			Some(block) => {
+				let first_page = first_page_of(block);
+				
+				assert_eq!(block.level() as usize, level);
+				assert_eq!(block.real_size() as usize, BASE_PAGE_SIZE << level);
+				assert_eq!(first_page % (0x1 << level), 0, "Block is not aligned to its level");
+				
+				model.mark(first_page, 0x1 << level, true);
+			},
+			None => {
+				assert!(!model.has_free_block(level), "Alloc of level {} failed even though a free block exists", level);
+			},
+		}
+		block
+	}
+	
+	fn assert_fully_free(shard: &BuckShard) {
+		assert_eq!(shard.upper_tree, 0);
+		assert_eq!(shard.lower_trees_alloced_by_upper, 0);
+		assert_eq!(shard.lower_trees, [0; 64]);
+		assert_eq!(shard.lower_levels_available, [u64::MAX; 6]);
+	}
+	
+	#[test]
+	fn mark_self_and_children_lut_matches_tree() {
+		for idx in 0..31 {
+			let mut expected = 0u64;
+			let mut level_first = idx;
+			let mut level_len = 1;
+			while level_first < 63 {
+				expected |= ((0x1u64 << level_len) - 1) << level_first;
+				level_first = 2 * level_first + 1;
+				level_len *= 2;
+			}
+			
+			assert_eq!(MARK_SELF_AND_CHILDREN_LUT[idx], expected, "Wrong mask for node {}", idx);
+		}
+	}
+	
+	#[test]
+	fn upper_alloc_lut_matches_lower_trees() {
+		for upper_level in 0..=5 {
+			for idx_in_level in 0..(32 >> upper_level) {
+				let upper_idx = level_first_idx(upper_level) + idx_in_level;
+				
+				// Each upper leaf covers two lower trees
+				let lower_count = 2 << upper_level;
+				let expected = (u64::MAX >> (64 - lower_count)) << (idx_in_level * lower_count);
+				
+				assert_eq!(UPPER_ALLOC_IDX_TO_LOWER_TREE_USED_FLAGS[upper_idx], expected, "Wrong lower trees for upper node {}", upper_idx);
+			}
+		}
+	}
+	
+	#[test]
+	fn alloc_every_level_until_exhausted() {
+		for level in 0..=BUCK_UPPER_TREE_MAX_LEVEL {
+			let mut shard = test_shard();
+			let mut model = ShardModel::new();
+			let mut blocks = Vec::new();
+			
+			while let Some(block) = checked_alloc(&mut shard, &mut model, level) {
+				blocks.push(block);
+			}
+			assert_eq!(blocks.len(), BUCK_SHARD_PAGES >> level);
+			
+			for block in blocks {
+				unsafe { shard.free_local(block); }
+			}
+			assert_fully_free(&shard);
+		}
+	}
+	
+	#[test]
+	fn randomized_alloc_free_matches_model() {
+		for seed in 0..256 {
+			let mut rng = XorShift::new(seed);
+			let mut shard = test_shard();
+			let remote_frees = test_remote_frees();
+			let mut model = ShardModel::new();
+			let mut blocks = Vec::new();
+			
+			for _ in 0..2048 {
+				if blocks.is_empty() || rng.below(3) != 0 {
+					// Bias towards small blocks like real workloads
+					let level = rng.below(BUCK_UPPER_TREE_MAX_LEVEL + 1).min(rng.below(BUCK_UPPER_TREE_MAX_LEVEL + 1));
+					
+					// Like the frame allocator, fold in the remote frees before allocating
+					unsafe { shard.drain_remote_frees(&remote_frees); }
+					if let Some(block) = checked_alloc(&mut shard, &mut model, level) {
+						blocks.push(block);
+					}
+				} else {
+					let block = blocks.swap_remove(rng.below(blocks.len()));
+					model.mark(first_page_of(&block), 0x1 << block.level(), false);
+					
+					if rng.below(2) == 0 {
+						unsafe { shard.free_local(block); }
+					} else {
+						unsafe { remote_frees.free_remote(block); }
+					}
+				}
+			}
+			
+			for block in blocks.drain(..) {
+				unsafe { remote_frees.free_remote(block); }
+			}
+			unsafe { shard.drain_remote_frees(&remote_frees); }
+			
+			assert_fully_free(&shard);
+		}
+	}
+	
+	#[test]
+	fn stats_match_model() {
+		for seed in 0..64 {
+			let mut rng = XorShift::new(seed);
+			let mut shard = test_shard();
+			let mut model = ShardModel::new();
+			let mut blocks = Vec::new();
+			
+			for _ in 0..512 {
+				if blocks.is_empty() || rng.below(3) != 0 {
+					let level = rng.below(BUCK_UPPER_TREE_MAX_LEVEL + 1).min(rng.below(BUCK_UPPER_TREE_MAX_LEVEL + 1));
+					
+					if let Some(block) = checked_alloc(&mut shard, &mut model, level) {
+						blocks.push(block);
+					}
+				} else {
+					let block = blocks.swap_remove(rng.below(blocks.len()));
+					model.mark(first_page_of(&block), 0x1 << block.level(), false);
+					unsafe { shard.free_local(block); }
+				}
+				
+				let stats = shard.stats();
+				let model_used_pages = model.used_pages.iter().filter(|&&used| used).count();
+				
+				assert_eq!(stats.used_pages(), model_used_pages);
+				assert_eq!(stats.free_pages(), BUCK_SHARD_PAGES - model_used_pages);
+				assert_eq!(
+					stats.largest_free_level,
+					(0..=BUCK_UPPER_TREE_MAX_LEVEL).rev().find(|&level| model.has_free_block(level)),
+				);
+			}
+		}
+		
+		let mut shard = test_shard();
+		let remote_frees = test_remote_frees();
+		assert_eq!(shard.stats().free_blocks[BUCK_UPPER_TREE_MAX_LEVEL], 1);
+		
+		let block = unsafe { shard.alloc(0) }.unwrap();
+		unsafe { remote_frees.free_remote(block); }
+		assert_eq!(remote_frees.backlog(), 1);
+		assert_eq!(shard.stats().free_blocks, [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+		
+		unsafe { shard.drain_remote_frees(&remote_frees); }
+		assert_eq!(remote_frees.backlog(), 0);
+		assert_eq!(shard.stats().used_blocks, [0; BUCK_LEVEL_COUNT]);
+	}
+	
+	#[test]
+	fn reserved_ranges_are_never_handed_out() {
+		for seed in 0..64 {
+			let mut rng = XorShift::new(seed);
+			let mut shard = test_shard();
+			let mut model = ShardModel::new();
+			
+			let mut page = rng.below(64);
+			while page < BUCK_SHARD_PAGES {
+				let page_count = (rng.below(300) + 1).min(BUCK_SHARD_PAGES - page);
+				shard.reserve_range(page, page_count);
+				model.mark(page, page_count, true);
+				
+				page += page_count + rng.below(300) + 1;
+			}
+			
+			// Every single free page must be allocatable, and nothing else
+			let free_pages = model.used_pages.iter().filter(|&&used| !used).count();
+			let mut alloced_pages = 0;
+			while let Some(_) = checked_alloc(&mut shard, &mut model, 0) {
+				alloced_pages += 1;
+			}
+			assert_eq!(alloced_pages, free_pages);
+		}
+	}
+	
+	#[test]
+	fn released_ranges_are_fully_reusable() {
+		for seed in 0..64 {
+			let mut rng = XorShift::new(seed);
+			let mut shard = test_shard();
+			let mut ranges = Vec::new();
+			
+			let mut page = rng.below(64);
+			while page < BUCK_SHARD_PAGES {
+				let page_count = (rng.below(300) + 1).min(BUCK_SHARD_PAGES - page);
+				assert!(shard.try_reserve_range(page, page_count));
+				ranges.push((page, page_count));
+				
+				page += page_count + rng.below(300) + 1;
+			}
+			
+			// Overlapping an existing range must fail without reserving anything
+			let (page, page_count) = ranges[0];
+			let upper_tree = shard.upper_tree;
+			assert!(!shard.try_reserve_range(page.saturating_sub(1), page_count + 1));
+			assert_eq!(shard.upper_tree, upper_tree);
+			
+			for (page, page_count) in ranges {
+				unsafe { shard.release_range(page, page_count); }
+			}
+			assert_fully_free(&shard);
+		}
+	}
+	
+	#[test]
+	fn node_first_page_matches_node_at() {
+		for level in 0..=BUCK_UPPER_TREE_MAX_LEVEL {
+			for page_idx in (0..BUCK_SHARD_PAGES).step_by(0x1 << level) {
+				let node = node_at(page_idx, level);
+				assert_eq!(node_level(node), level);
+				assert_eq!(node_first_page(node), page_idx);
+			}
+		}
+	}
+	
+	#[test]
+	#[cfg(feature = "debug_phys_frames")]
+	#[should_panic(expected = "Double free")]
+	fn double_free_local_panics() {
+		let mut shard = test_shard();
+		unsafe {
+			let block = shard.alloc(3).unwrap();
+			shard.free_local(block.clone());
+			shard.free_local(block);
+		}
+	}
+	
+	#[test]
+	#[cfg(feature = "debug_phys_frames")]
+	#[should_panic(expected = "Double free")]
+	fn double_free_remote_panics() {
+		let remote_frees = test_remote_frees();
+		unsafe {
+			let block = BuckBlock {
+				ptr: Phys(NonNull::new_unchecked(TEST_BASE_ADDR as *mut BasePage)),
+				real_size: BASE_PAGE_SIZE as u32,
+				level: 0,
+			};
+			remote_frees.free_remote(block.clone());
+			remote_frees.free_remote(block);
+		}
+	}
+	
+	#[test]
+	#[cfg(feature = "debug_phys_frames")]
+	#[should_panic(expected = "never allocated")]
+	fn free_of_never_allocated_block_panics() {
+		let mut shard = test_shard();
+		unsafe {
+			let block = shard.alloc(7).unwrap();
+			let never_alloced = BuckBlock {
+				ptr: Phys(NonNull::new_unchecked((block.ptr().ptr().as_ptr() as usize + (BASE_PAGE_SIZE << 7)) as *mut BasePage)),
+				real_size: (BASE_PAGE_SIZE << 7) as u32,
+				level: 7,
+			};
+			shard.free_local(never_alloced);
+		}
+	}
+	
+	#[test]
+	#[ignore]
+	fn draw_trees() {
+		let mut shard = test_shard();
+		unsafe {
+			draw(shard.upper_tree);
+			shard.alloc(0);
+			draw(shard.upper_tree);
+			shard.alloc(0);
+			draw(shard.upper_tree);
+			shard.alloc(7);
+			draw(shard.upper_tree);
+			shard.alloc(9);
+			draw(shard.upper_tree);
+		}
+	}
+	
+	pub fn draw(tree: u64) {
+		let mut out = String::new();
+		write_tree(&mut out, tree, 0).unwrap();
+		print!("{}", out);
 	}
 }
-
-pub fn draw(tree: u64) {
-    let mut cursor: usize = 0;
-    for l in 0..6 {
-        print!("L{} ", 5-l);
-        for _ in 0..(0x1 << l) {
-            if ((tree >> cursor) & 0x1) != 0 {
-                print!("x");
-            } else {
-                print!(".");
-            }
-            cursor += 1;
-        }
-        println!();
-    }
-}
-
-// ---------
-*/
\ No newline at end of file
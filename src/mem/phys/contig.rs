@@ -0,0 +1,148 @@
+//! Physically contiguous allocations bigger than the biggest buck block
+//! 
+//! A contiguous range always starts at a shard base and spans a run of
+//! shards that are adjacent in physical memory. All shards but the last
+//! one are claimed in full, the last one only for the remaining pages.
+//! The whole run is locked while claiming so a range is either allocated
+//! completely or not at all.
+
+use core::ptr::NonNull;
+
+use crate::mem::Phys;
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BASE_PAGE_SIZE, BasePage, BUCK_SHARD_PAGES, BUCK_SHARD_SIZE};
+use crate::mem::phys::frame::{base_of, shard_slots, ShardSlot};
+use crate::mem::phys::frame_desc::{on_frames_alloced, on_frames_freed};
+
+/// A physically contiguous range of base pages spanning one or more shards
+#[derive(Debug)]
+pub struct ContigFrames {
+	ptr: Phys<NonNull<BasePage>>,
+	page_count: usize,
+}
+
+impl ContigFrames {
+	#[inline]
+	pub fn ptr(&self) -> Phys<NonNull<BasePage>> {
+		self.ptr
+	}
+	
+	#[inline]
+	pub fn page_count(&self) -> usize {
+		self.page_count
+	}
+	
+	/// Size of the range in bytes
+	#[inline]
+	pub fn size(&self) -> usize {
+		self.page_count << BASE_PAGE_ADDR_BITS
+	}
+}
+
+unsafe impl Send for ContigFrames {}
+unsafe impl Sync for ContigFrames {}
+
+/// Allocates `page_count` physically contiguous base pages whose start is
+/// aligned to `align` bytes (but at least to [`BUCK_SHARD_SIZE`]) and
+/// which lie entirely at or below `max_phys_addr` (inclusive).
+/// 
+/// Use [`super::alloc_frames`] for anything fitting in a single buck block,
+/// this is considerably slower. The highest addresses are tried first
+/// to spare the low zones.
+/// 
+/// Note: Only runs starting at a shard base are considered, so this fails if
+/// no such run is free, even if enough free memory is contiguous elsewhere
+/// (e.g. the upper half of one shard and the lower half of the next).
+pub fn alloc_contiguous(page_count: usize, align: usize, max_phys_addr: usize) -> Option<ContigFrames> {
+	if page_count == 0 {
+		return None;
+	}
+	
+	let size = page_count.checked_mul(BASE_PAGE_SIZE)?;
+	let run_len = page_count.checked_add(BUCK_SHARD_PAGES - 1)? / BUCK_SHARD_PAGES;
+	let align = align.max(BUCK_SHARD_SIZE);
+	
+	let slots = shard_slots();
+	
+	for first_idx in (0..slots.len()).rev() {
+		let run = match slots.get(first_idx..first_idx + run_len) {
+			Some(run) => run,
+			None => continue,
+		};
+		
+		let start_addr = base_of(&run[0]);
+		if (start_addr & (align - 1)) != 0
+			|| start_addr.checked_add(size - 1).map_or(true, |last_addr| last_addr > max_phys_addr)
+			|| !run.windows(2).all(|pair| base_of(&pair[1]) == base_of(&pair[0]) + BUCK_SHARD_SIZE) {
+			continue;
+		}
+		
+		if unsafe { try_claim_run(run, page_count) } {
+			on_frames_alloced(start_addr, page_count);
+			return Some(ContigFrames {
+				ptr: Phys(unsafe { NonNull::new_unchecked(start_addr as *mut BasePage) }),
+				page_count,
+			});
+		}
+	}
+	
+	None
+}
+
+/// Frees a range returned by [`alloc_contiguous`] as a whole
+pub unsafe fn free_contiguous(frames: ContigFrames) {
+	let slots = shard_slots();
+	let start_addr = frames.ptr.ptr().as_ptr() as usize;
+	
+	let first_idx = slots
+		.binary_search_by_key(&start_addr, |slot| base_of(slot))
+		.expect("Freed contiguous frames do not start at a shard");
+	let run_len = (frames.page_count + (BUCK_SHARD_PAGES - 1)) / BUCK_SHARD_PAGES;
+	
+	on_frames_freed(start_addr, frames.page_count);
+	
+	for (slot, pages) in slots[first_idx..first_idx + run_len].iter().zip(pages_per_shard(frames.page_count)) {
+		slot.lock();
+		(*slot.shard.get()).release_range(0, pages);
+		slot.unlock();
+	}
+}
+
+/// Locks all shards of the run and claims the pages in them only if all are free
+unsafe fn try_claim_run(run: &[ShardSlot], page_count: usize) -> bool {
+	// Always lock in ascending address order so two
+	// concurrent claims of overlapping runs can't deadlock
+	for slot in run {
+		slot.lock();
+		(*slot.shard.get()).drain_remote_frees(&slot.remote_frees);
+	}
+	
+	let all_free = run.iter()
+		.zip(pages_per_shard(page_count))
+		.all(|(slot, pages)| (*slot.shard.get()).is_range_free(0, pages));
+	
+	if all_free {
+		for (slot, pages) in run.iter().zip(pages_per_shard(page_count)) {
+			(*slot.shard.get()).reserve_range(0, pages);
+		}
+	}
+	
+	for slot in run {
+		slot.unlock();
+	}
+	
+	all_free
+}
+
+/// Number of pages taken from each shard of a run, starting at the first one
+fn pages_per_shard(page_count: usize) -> impl Iterator<Item = usize> {
+	let mut remaining = page_count;
+	core::iter::from_fn(move || {
+		if remaining == 0 {
+			return None;
+		}
+		
+		let pages = remaining.min(BUCK_SHARD_PAGES);
+		remaining -= pages;
+		Some(pages)
+	})
+}
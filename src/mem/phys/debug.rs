@@ -0,0 +1,30 @@
+//! Physical frame poisoning (`debug_phys_frames` feature only)
+//! 
+//! Freed frames are filled with [`FRAME_POISON`] and checked on their next
+//! allocation, so writes through dangling physical pointers are noticed.
+
+use crate::mem::kernel_mem_map::phys_to_virt;
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+
+pub const FRAME_POISON: u64 = 0x6b6b_6b6b_dead_f4a3;
+
+/// Fills the frame with the poison pattern
+pub unsafe fn poison_frame(page_addr: usize) {
+	let page = &mut *(phys_to_virt(page_addr) as *mut [u64; BASE_PAGE_SIZE / 8]);
+	page.fill(FRAME_POISON);
+}
+
+/// Panics if the frame does not contain the poison pattern anymore
+pub unsafe fn check_frame_poison(page_addr: usize) {
+	let page = &*(phys_to_virt(page_addr) as *const [u64; BASE_PAGE_SIZE / 8]);
+	
+	if let Some(word_idx) = page.iter().position(|&word| word != FRAME_POISON) {
+		panic!(
+			"Freed physical frame {:#x} was written to (offset {:#x} is {:#018x}, expected {:#018x})",
+			page_addr,
+			word_idx * 8,
+			page[word_idx],
+			FRAME_POISON,
+		);
+	}
+}
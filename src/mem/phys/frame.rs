@@ -0,0 +1,432 @@
+//! Global physical frame allocator
+//! 
+//! Physical memory is split into naturally aligned [`BUCK_SHARD_SIZE`] chunks,
+//! each managed by its own [`BuckShard`]. Shards only exist for chunks that
+//! contain at least some managed memory, pages of a shard that are not managed
+//! or are in the reserved range registry are reserved at init so they
+//! are never handed out.
+//! 
+//! The shard array itself lives in usable physical memory carved out
+//! right at init (before any shard exists) and is sorted by base address.
+//! 
+//! Every shard belongs to exactly one [`PhysZone`] as the zone limits are
+//! multiples of [`BUCK_SHARD_SIZE`].
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+use core::sync::atomic::Ordering::*;
+
+use uefi_rs::table::boot::{MemoryDescriptor, MemoryType};
+
+use crate::cpu::{current_cpu_uid, CpuUid};
+use crate::mem::Phys;
+use crate::mem::kernel_mem_map::phys_to_virt;
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BASE_PAGE_SIZE, BasePage, BUCK_SHARD_PAGES, BUCK_SHARD_SIZE, BuckBlock, BuckRemoteFrees, BuckShard};
+use crate::mem::phys::frame_desc::{FrameDesc, on_frames_alloced, on_frames_freed};
+use crate::mem::phys::reserved::{is_phys_range_reserved, reserve_phys_range, reserved_ranges, ReservedKind};
+
+/// Physical address of the shard array
+static SHARDS_PTR: AtomicPtr<ShardSlot> = AtomicPtr::new(ptr::null_mut());
+static SHARD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of frame allocations that could not be satisfied by any shard
+static FAILED_FRAME_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+/// A shard together with a tiny lock, so that cpus can also
+/// allocate from shards they don't own if theirs are exhausted.
+/// 
+/// The shard must only be touched while holding the lock, everything
+/// needed without it lives in the slot itself.
+pub(super) struct ShardSlot {
+	locked: AtomicBool,
+	pub(super) zone: PhysZone,
+	/// Copies of the shard's base address and owner, which never change
+	base_addr: usize,
+	owning_cpu: CpuUid,
+	pub(super) shard: UnsafeCell<BuckShard>,
+	/// Frees of cpus that could not get hold of the shard
+	pub(super) remote_frees: BuckRemoteFrees,
+	/// Physical address of the [`BUCK_SHARD_PAGES`] descriptors of this
+	/// shard's pages, null until [`super::init_frame_descs`] has run
+	pub(super) frame_descs: AtomicPtr<FrameDesc>,
+}
+
+impl ShardSlot {
+	#[inline]
+	pub(super) fn try_lock(&self) -> bool {
+		self.locked.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+	}
+	
+	#[inline]
+	pub(super) fn lock(&self) {
+		while !self.try_lock() {
+			unsafe {
+				asm!("pause", options(nomem, nostack));
+			}
+		}
+	}
+	
+	#[inline]
+	pub(super) fn unlock(&self) {
+		self.locked.store(false, Release);
+	}
+}
+
+/// Physical address ranges with different device addressing constraints
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PhysZone {
+	/// Below 16 MiB, reachable by ISA DMA
+	IsaDma,
+	/// Below 4 GiB, reachable by 32-bit DMA
+	Dma32,
+	/// All memory above 4 GiB
+	Normal,
+}
+
+impl PhysZone {
+	/// Zones in the order they are tried by allocations,
+	/// so that the scarce low zones are used last
+	pub const FALLBACK_ORDER: [PhysZone; 3] = [PhysZone::Normal, PhysZone::Dma32, PhysZone::IsaDma];
+	
+	#[inline]
+	pub const fn start_addr(self) -> usize {
+		match self {
+			Self::IsaDma => 0x0,
+			Self::Dma32 => 0x100_0000,
+			Self::Normal => 0x1_0000_0000,
+		}
+	}
+	
+	/// Exclusive end address of the zone
+	#[inline]
+	pub const fn end_addr(self) -> usize {
+		match self {
+			Self::IsaDma => 0x100_0000,
+			Self::Dma32 => 0x1_0000_0000,
+			Self::Normal => usize::MAX,
+		}
+	}
+	
+	#[inline]
+	pub const fn of_addr(addr: usize) -> Self {
+		if addr < Self::IsaDma.end_addr() {
+			Self::IsaDma
+		} else if addr < Self::Dma32.end_addr() {
+			Self::Dma32
+		} else {
+			Self::Normal
+		}
+	}
+}
+
+/// Whether memory of this type is managed by the frame allocator at all.
+/// 
+/// This includes memory that is only handed out after it
+/// has been reclaimed (see [`super::reclaim`]).
+pub fn is_managed_by_frame_alloc(ty: MemoryType) -> bool {
+	matches!(ty, MemoryType::CONVENTIONAL | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA | MemoryType::ACPI_RECLAIM)
+}
+
+/// Builds the shards from the memory map returned by `exit_boot_services`.
+/// 
+/// Everything in the reserved range registry is excluded,
+/// so it must have been filled from the same memory map before.
+/// Must be called exactly once on the bootstrap processor before
+/// any call to [`alloc_frames`] or [`free_frames`].
+pub unsafe fn init_frame_alloc<'a>(mmap: impl Iterator<Item = &'a MemoryDescriptor> + Clone) {
+	let managed_ranges = mmap
+		.filter(|desc| is_managed_by_frame_alloc(desc.ty))
+		.map(|desc| {
+			let start = desc.phys_start as usize;
+			(start, start + ((desc.page_count as usize) << BASE_PAGE_ADDR_BITS))
+		});
+	
+	// Upper bound of the number of shards (ranges sharing a shard are counted twice)
+	let shard_capacity: usize = managed_ranges.clone()
+		.map(|(start, end)| (align_up(end, BUCK_SHARD_SIZE) - align_down(start, BUCK_SHARD_SIZE)) / BUCK_SHARD_SIZE)
+		.sum();
+	
+	// Carve the shard array out of the first unreserved range big enough
+	let storage_size = align_up(shard_capacity * size_of::<ShardSlot>(), BASE_PAGE_SIZE);
+	let (storage_start, storage_end) = managed_ranges.clone()
+		.map(|(start, end)| (start.max(BASE_PAGE_SIZE), end))
+		.find(|&(start, end)| start < end && end - start >= storage_size && !is_phys_range_reserved(start, start + storage_size))
+		.map(|(start, _)| (start, start + storage_size))
+		.expect("Not enough usable physical memory for the frame allocator shards");
+	
+	reserve_phys_range(storage_start, storage_end, ReservedKind::FrameAllocMeta);
+	
+	let shards = storage_start as *mut ShardSlot;
+	let owning_cpu = current_cpu_uid();
+	
+	// Create one shard per distinct shard-aligned chunk, sorted by base address
+	let mut shard_count = 0;
+	for (start, end) in managed_ranges.clone() {
+		let mut base = align_down(start, BUCK_SHARD_SIZE);
+		while base < end {
+			let existing = core::slice::from_raw_parts(shards, shard_count);
+			
+			if let Err(insert_idx) = existing.binary_search_by_key(&base, |slot| base_of(slot)) {
+				ptr::copy(shards.add(insert_idx), shards.add(insert_idx + 1), shard_count - insert_idx);
+				shards.add(insert_idx).write(ShardSlot {
+					locked: AtomicBool::new(false),
+					zone: PhysZone::of_addr(base),
+					base_addr: base,
+					owning_cpu,
+					shard: UnsafeCell::new(BuckShard::new_at(Phys(base as *mut BasePage), owning_cpu)),
+					remote_frees: BuckRemoteFrees::new_at(Phys(base as *mut BasePage)),
+					frame_descs: AtomicPtr::new(ptr::null_mut()),
+				});
+				shard_count += 1;
+			}
+			
+			base += BUCK_SHARD_SIZE;
+		}
+	}
+	
+	// Reserve everything in the shards that is not usable
+	for slot_idx in 0..shard_count {
+		let shard = &mut *(*shards.add(slot_idx)).shard.get();
+		let shard_base = shard.base_addr().ptr() as usize;
+		
+		// Pages that can be handed out right away
+		let mut usable_pages = PageBitmap::new();
+		// Pages that have already been reserved on their own
+		let mut claimed_pages = PageBitmap::new();
+		
+		for (start, end) in managed_ranges.clone() {
+			if let Some((first_page, page_count)) = clip_to_shard(shard_base, start, end) {
+				usable_pages.set_range(first_page, page_count, true);
+			}
+		}
+		
+		for range in reserved_ranges() {
+			if let Some((first_page, page_count)) = clip_to_shard(shard_base, range.start, range.end) {
+				usable_pages.set_range(first_page, page_count, false);
+				
+				// Reclaimable ranges are reserved on their own so they
+				// can later be released with exactly the same bounds
+				if range.kind.reclaim_kind().is_some() {
+					shard.reserve_range(first_page, page_count);
+					claimed_pages.set_range(first_page, page_count, true);
+				}
+			}
+		}
+		
+		// Reserve each run of the remaining unusable pages
+		let mut page_idx = 0;
+		while page_idx < BUCK_SHARD_PAGES {
+			let is_free_to_reserve = |page_idx: usize| !usable_pages.get(page_idx) && !claimed_pages.get(page_idx);
+			
+			if !is_free_to_reserve(page_idx) {
+				page_idx += 1;
+				continue;
+			}
+			
+			let run_start = page_idx;
+			while page_idx < BUCK_SHARD_PAGES && is_free_to_reserve(page_idx) {
+				page_idx += 1;
+			}
+			shard.reserve_range(run_start, page_idx - run_start);
+		}
+	}
+	
+	SHARDS_PTR.store(shards, SeqCst);
+	SHARD_COUNT.store(shard_count, SeqCst);
+}
+
+/// Releases a range that has been reserved on its own at init
+/// (see [`init_frame_alloc`]) back into the shards
+pub(super) unsafe fn release_reserved(start: usize, end: usize) {
+	for slot in shard_slots() {
+		if let Some((first_page, page_count)) = clip_to_shard(base_of(slot), start, end) {
+			on_frames_freed(base_of(slot) + (first_page << BASE_PAGE_ADDR_BITS), page_count);
+			
+			slot.lock();
+			(*slot.shard.get()).release_range(first_page, page_count);
+			slot.unlock();
+		}
+	}
+}
+
+/// The part of `start..end` inside the shard at `shard_base` as first page and page count
+#[inline]
+fn clip_to_shard(shard_base: usize, start: usize, end: usize) -> Option<(usize, usize)> {
+	let start = start.max(shard_base);
+	let end = end.min(shard_base + BUCK_SHARD_SIZE);
+	
+	if start >= end {
+		return None;
+	}
+	
+	let first_page = (start - shard_base) >> BASE_PAGE_ADDR_BITS;
+	let end_page = (align_up(end, BASE_PAGE_SIZE) - shard_base) >> BASE_PAGE_ADDR_BITS;
+	Some((first_page, end_page - first_page))
+}
+
+/// One bit per page of a shard
+struct PageBitmap([u64; BUCK_SHARD_PAGES / 64]);
+
+impl PageBitmap {
+	#[inline]
+	const fn new() -> Self {
+		Self([0; BUCK_SHARD_PAGES / 64])
+	}
+	
+	#[inline]
+	fn get(&self, page_idx: usize) -> bool {
+		(self.0[page_idx / 64] >> (page_idx % 64)) & 0x1 != 0
+	}
+	
+	fn set_range(&mut self, first_page: usize, page_count: usize, val: bool) {
+		for page_idx in first_page..(first_page + page_count) {
+			if val {
+				self.0[page_idx / 64] |= 0x1 << (page_idx % 64);
+			} else {
+				self.0[page_idx / 64] &= !(0x1 << (page_idx % 64));
+			}
+		}
+	}
+}
+
+/// Whether [`init_frame_alloc`] has been called
+#[inline]
+pub fn is_frame_alloc_ready() -> bool {
+	!SHARDS_PTR.load(Acquire).is_null()
+}
+
+#[inline]
+pub(super) fn shard_slots() -> &'static [ShardSlot] {
+	unsafe {
+		let shards = SHARDS_PTR.load(Acquire);
+		if shards.is_null() {
+			&[]
+		} else {
+			core::slice::from_raw_parts(phys_to_virt(shards as usize) as *const ShardSlot, SHARD_COUNT.load(Acquire))
+		}
+	}
+}
+
+/// Allocates a physically contiguous block of `2^order` base pages
+/// anywhere in physical memory.
+/// 
+/// See [`alloc_frames_in`] for how shards and zones are chosen.
+#[inline]
+pub fn alloc_frames(order: usize) -> Option<BuckBlock> {
+	alloc_frames_in(order, usize::MAX)
+}
+
+/// Allocates a physically contiguous block of `2^order` base pages
+/// that lies entirely at or below `max_phys_addr` (inclusive).
+/// 
+/// Zones are tried from the highest allowed one downwards. The ISA DMA zone
+/// is only used by limited allocations, as it is tiny and drivers of old
+/// devices have no other choice. It is their last resort once the
+/// other zones below the limit are exhausted.
+/// Inside of a zone, shards owned by the calling cpu are tried first, then
+/// all other shards that are not currently busy.
+pub fn alloc_frames_in(order: usize, max_phys_addr: usize) -> Option<BuckBlock> {
+	let cpu = current_cpu_uid();
+	let slots = shard_slots();
+	
+	let try_alloc = |slot: &ShardSlot| unsafe {
+		let shard = &mut *slot.shard.get();
+		
+		// Fold in blocks freed by other cpus first so they can be reused right away
+		shard.drain_remote_frees(&slot.remote_frees);
+		let block = shard.alloc(order);
+		slot.unlock();
+		
+		if let Some(block) = &block {
+			on_frames_alloced(block.ptr().ptr().as_ptr() as usize, 0x1 << order);
+		}
+		block
+	};
+	
+	for zone in PhysZone::FALLBACK_ORDER {
+		if zone.start_addr() > max_phys_addr
+			|| (zone == PhysZone::IsaDma && max_phys_addr == usize::MAX) {
+			continue;
+		}
+		
+		// Only shards that lie entirely below the limit are eligible
+		let zone_slots = slots.iter()
+			.filter(|slot| slot.zone == zone)
+			.filter(|slot| base_of(slot) + (BUCK_SHARD_SIZE - 1) <= max_phys_addr);
+		
+		for slot in zone_slots.clone().filter(|slot| owner_of(slot) == cpu) {
+			slot.lock();
+			if let Some(block) = try_alloc(slot) {
+				return Some(block);
+			}
+		}
+		
+		for slot in zone_slots.filter(|slot| owner_of(slot) != cpu) {
+			if slot.try_lock() {
+				if let Some(block) = try_alloc(slot) {
+					return Some(block);
+				}
+			}
+		}
+	}
+	
+	FAILED_FRAME_ALLOCS.fetch_add(1, Relaxed);
+	None
+}
+
+#[inline]
+pub(super) fn failed_frame_allocs() -> usize {
+	FAILED_FRAME_ALLOCS.load(Relaxed)
+}
+
+/// Frees a block returned by [`alloc_frames`].
+/// 
+/// Only the cpu owning the shard frees into it directly. Other cpus (and the
+/// owner if the shard is busy) queue the free, which is processed on the
+/// next allocation from the shard.
+pub unsafe fn free_frames(block: BuckBlock) {
+	let slot = shard_of(block.ptr().ptr().as_ptr() as usize)
+		.expect("Freed frames do not belong to any shard");
+	
+	// Catch double frees and reset the descriptors before the frames can be handed out again
+	on_frames_freed(block.ptr().ptr().as_ptr() as usize, 0x1 << block.level());
+	
+	if owner_of(slot) == current_cpu_uid() && slot.try_lock() {
+		(*slot.shard.get()).free_local(block);
+		slot.unlock();
+	} else {
+		slot.remote_frees.free_remote(block);
+	}
+}
+
+#[inline]
+pub(super) fn shard_of(addr: usize) -> Option<&'static ShardSlot> {
+	let slots = shard_slots();
+	let slot_idx = slots
+		.binary_search_by_key(&align_down(addr, BUCK_SHARD_SIZE), |slot| base_of(slot))
+		.ok()?;
+	Some(&slots[slot_idx])
+}
+
+#[inline]
+pub(super) fn base_of(slot: &ShardSlot) -> usize {
+	slot.base_addr
+}
+
+#[inline]
+fn owner_of(slot: &ShardSlot) -> CpuUid {
+	slot.owning_cpu
+}
+
+#[inline(always)]
+const fn align_down(addr: usize, align: usize) -> usize {
+	addr & !(align - 1)
+}
+
+#[inline(always)]
+const fn align_up(addr: usize, align: usize) -> usize {
+	(addr + (align - 1)) & !(align - 1)
+}
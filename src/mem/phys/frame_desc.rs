@@ -0,0 +1,334 @@
+//! Per frame metadata database
+//! 
+//! Every base page managed by the frame allocator has a [`FrameDesc`]
+//! recording its owner, refcount, number of mappings and state flags.
+//! The descriptors are stored per shard (in one [`BuckBlock`] of
+//! [`BUCK_SHARD_PAGES`] descriptors each) and are looked up by physical
+//! frame number via the sorted shard array, so holes in the physical
+//! address space cost nothing.
+//! 
+//! The frame allocator resets the descriptors of every block it hands out
+//! or takes back, everything else is up to the owner of the frame.
+//! 
+//! With the `debug_phys_frames` feature freed frames are also poisoned here
+//! and checked on their next allocation (see [`super::debug`]).
+
+use core::mem::size_of;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering::*;
+
+use crate::mem::kernel_mem_map::phys_to_virt;
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BUCK_SHARD_PAGES, BUCK_SHARD_SIZE, BuckBlock};
+use crate::mem::phys::frame::{alloc_frames, base_of, shard_of, shard_slots, ShardSlot};
+
+/// Metadata of a single physical base page
+#[repr(C, align(16))]
+pub struct FrameDesc {
+	refcount: AtomicU32,
+	/// Number of page table entries mapping this frame
+	map_count: AtomicU32,
+	flags: AtomicU32,
+	/// A [`FrameOwner`] as u32
+	owner: AtomicU32,
+}
+
+impl FrameDesc {
+	const FREE: Self = Self {
+		refcount: AtomicU32::new(0),
+		map_count: AtomicU32::new(0),
+		flags: AtomicU32::new(0),
+		owner: AtomicU32::new(FrameOwner::Free as u32),
+	};
+	
+	#[inline]
+	pub fn refcount(&self) -> u32 {
+		self.refcount.load(Acquire)
+	}
+	
+	/// Takes another reference and returns the new refcount
+	#[inline]
+	pub fn get(&self) -> u32 {
+		let prev = self.refcount.fetch_add(1, Relaxed);
+		debug_assert!(prev != 0, "Took a reference to a frame that is not referenced");
+		prev + 1
+	}
+	
+	/// Drops a reference and returns whether it was the last one,
+	/// in which case the caller is responsible for freeing the frame
+	#[inline]
+	pub fn put(&self) -> bool {
+		let prev = self.refcount.fetch_sub(1, Release);
+		debug_assert!(prev != 0, "Dropped a reference to a frame that is not referenced");
+		
+		if prev == 1 {
+			// Synchronize with all other drops before the frame is reused
+			core::sync::atomic::fence(Acquire);
+			true
+		} else {
+			false
+		}
+	}
+	
+	#[inline]
+	pub fn map_count(&self) -> u32 {
+		self.map_count.load(Relaxed)
+	}
+	
+	/// Records a new mapping of the frame and returns the new map count
+	#[inline]
+	pub fn inc_map_count(&self) -> u32 {
+		self.map_count.fetch_add(1, Relaxed) + 1
+	}
+	
+	/// Records the removal of a mapping and returns the new map count
+	#[inline]
+	pub fn dec_map_count(&self) -> u32 {
+		let prev = self.map_count.fetch_sub(1, Relaxed);
+		debug_assert!(prev != 0, "Frame map count underflow");
+		prev - 1
+	}
+	
+	#[inline]
+	pub fn flags(&self) -> FrameFlags {
+		FrameFlags(self.flags.load(Acquire))
+	}
+	
+	/// Sets the flags and returns the previous flags
+	#[inline]
+	pub fn set_flags(&self, flags: FrameFlags) -> FrameFlags {
+		FrameFlags(self.flags.fetch_or(flags.0, AcqRel))
+	}
+	
+	/// Clears the flags and returns the previous flags
+	#[inline]
+	pub fn clear_flags(&self, flags: FrameFlags) -> FrameFlags {
+		FrameFlags(self.flags.fetch_and(!flags.0, AcqRel))
+	}
+	
+	#[inline]
+	pub fn owner(&self) -> FrameOwner {
+		FrameOwner::from_raw(self.owner.load(Acquire))
+	}
+	
+	#[inline]
+	pub fn set_owner(&self, owner: FrameOwner) {
+		self.owner.store(owner as u32, Release);
+	}
+	
+	#[inline]
+	fn reset(&self, owner: FrameOwner, refcount: u32, flags: FrameFlags) {
+		self.map_count.store(0, Relaxed);
+		self.flags.store(flags.0, Relaxed);
+		self.refcount.store(refcount, Relaxed);
+		self.owner.store(owner as u32, Release);
+	}
+}
+
+/// State bits of a frame, see [`FrameDesc::flags`]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FrameFlags(pub u32);
+
+impl FrameFlags {
+	pub const EMPTY: Self = Self(0);
+	/// Mapped into at least one address space
+	pub const MAPPED: Self = Self(0x1 << 0);
+	/// Must not be moved or reclaimed (e.g. used for DMA)
+	pub const PINNED: Self = Self(0x1 << 1);
+	/// Written to since it was last cleaned
+	pub const DIRTY: Self = Self(0x1 << 2);
+	/// Shared between address spaces and must be copied before writing
+	pub const COPY_ON_WRITE: Self = Self(0x1 << 3);
+	/// Shared memory that is intentionally mapped writable in multiple address spaces
+	pub const SHARED: Self = Self(0x1 << 4);
+	/// Currently locked by someone inspecting or changing the frame
+	pub const LOCKED: Self = Self(0x1 << 5);
+	/// Filled with the poison pattern when it was freed (`debug_phys_frames` only)
+	pub const POISONED: Self = Self(0x1 << 6);
+	
+	#[inline]
+	pub const fn contains(self, other: Self) -> bool {
+		(self.0 & other.0) == other.0
+	}
+	
+	#[inline]
+	pub const fn intersects(self, other: Self) -> bool {
+		(self.0 & other.0) != 0
+	}
+}
+
+impl core::ops::BitOr for FrameFlags {
+	type Output = Self;
+	
+	#[inline]
+	fn bitor(self, rhs: Self) -> Self {
+		Self(self.0 | rhs.0)
+	}
+}
+
+/// What a frame is used for, see [`FrameDesc::owner`]
+#[repr(u32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FrameOwner {
+	/// Not allocated
+	Free = 0,
+	/// Not managed by the allocator (firmware, kernel image, holes, ..)
+	Reserved,
+	/// Frame allocator bookkeeping such as the frame descriptors themselves
+	FrameAllocMeta,
+	/// Allocated by the kernel without a more specific owner
+	Kernel,
+	PageTable,
+	KernelHeap,
+	User,
+	/// Backing a virtually contiguous kernel allocation, see [`crate::mem::virt::vmalloc`]
+	Vmalloc,
+}
+
+impl FrameOwner {
+	#[inline]
+	fn from_raw(raw: u32) -> Self {
+		match raw {
+			0 => Self::Free,
+			1 => Self::Reserved,
+			2 => Self::FrameAllocMeta,
+			3 => Self::Kernel,
+			4 => Self::PageTable,
+			5 => Self::KernelHeap,
+			6 => Self::User,
+			7 => Self::Vmalloc,
+			_ => unreachable!("Invalid frame owner {}", raw),
+		}
+	}
+}
+
+/// Allocates the descriptors of all shards from the frame allocator itself.
+/// Must be called exactly once on the bootstrap processor right after
+/// [`super::init_frame_alloc`], allocations made before are not tracked.
+pub unsafe fn init_frame_descs() {
+	let descs_level = ((BUCK_SHARD_PAGES * size_of::<FrameDesc>()) >> BASE_PAGE_ADDR_BITS).trailing_zeros() as usize;
+	
+	for slot in shard_slots() {
+		let block = alloc_frames(descs_level)
+			.expect("Not enough physical memory for the frame descriptors");
+		
+		let descs_addr = block.ptr().ptr().as_ptr() as usize;
+		let descs = phys_to_virt(descs_addr) as *mut FrameDesc;
+		for page_idx in 0..BUCK_SHARD_PAGES {
+			descs.add(page_idx).write(FrameDesc::FREE);
+		}
+		
+		slot.lock();
+		let shard = &*slot.shard.get();
+		for page_idx in 0..BUCK_SHARD_PAGES {
+			if !shard.is_range_free(page_idx, 1) {
+				(*descs.add(page_idx)).owner.store(FrameOwner::Reserved as u32, Relaxed);
+			}
+		}
+		slot.unlock();
+		
+		slot.frame_descs.store(descs_addr as *mut FrameDesc, Release);
+	}
+	
+	// Now that all descriptors exist, record the ones we just allocated
+	for slot in shard_slots() {
+		let descs_addr = slot.frame_descs.load(Acquire) as usize;
+		let desc_pages = (BUCK_SHARD_PAGES * size_of::<FrameDesc>()) >> BASE_PAGE_ADDR_BITS;
+		
+		for_each_frame_desc(descs_addr, desc_pages, |_, desc| desc.reset(FrameOwner::FrameAllocMeta, 1, FrameFlags::EMPTY));
+	}
+}
+
+/// The descriptor of the physical frame number, if the frame is managed by the frame allocator
+pub fn frame_desc(pfn: usize) -> Option<&'static FrameDesc> {
+	let addr = pfn << BASE_PAGE_ADDR_BITS;
+	let slot = shard_of(addr)?;
+	let descs = descs_of(slot)?;
+	
+	let page_idx = (addr - base_of(slot)) >> BASE_PAGE_ADDR_BITS;
+	unsafe { Some(&*descs.add(page_idx)) }
+}
+
+/// The descriptor of the first page of the block
+#[inline]
+pub fn frame_desc_of(block: &BuckBlock) -> &'static FrameDesc {
+	frame_desc((block.ptr().ptr().as_ptr() as usize) >> BASE_PAGE_ADDR_BITS)
+		.expect("Block has no frame descriptor")
+}
+
+/// Flags of frames that are not allocated
+const FREE_FRAME_FLAGS: FrameFlags = if cfg!(feature = "debug_phys_frames") {
+	FrameFlags::POISONED
+} else {
+	FrameFlags::EMPTY
+};
+
+/// Resets the descriptors of freshly allocated frames
+/// to be owned by the kernel with a single reference
+#[inline]
+pub(super) fn on_frames_alloced(start_addr: usize, page_count: usize) {
+	for_each_frame_desc(start_addr, page_count, |_page_addr, desc| {
+		#[cfg(feature = "debug_phys_frames")]
+		if desc.flags().contains(FrameFlags::POISONED) {
+			unsafe { super::debug::check_frame_poison(_page_addr); }
+		}
+		
+		desc.reset(FrameOwner::Kernel, 1, FrameFlags::EMPTY);
+	});
+}
+
+/// Resets the descriptors of frames given back to the frame allocator.
+/// 
+/// With `debug_phys_frames` a double free panics before any frame of the
+/// range is poisoned, so the poison of the first free stays intact
+/// (the buddy allocator only notices it once a queued remote free is drained).
+#[inline]
+pub(super) fn on_frames_freed(start_addr: usize, page_count: usize) {
+	#[cfg(feature = "debug_phys_frames")]
+	for_each_frame_desc(start_addr, page_count, |page_addr, desc| {
+		if desc.owner() == FrameOwner::Free {
+			panic!("Double free of physical frame {:#x}", page_addr);
+		}
+	});
+	
+	for_each_frame_desc(start_addr, page_count, |_page_addr, desc| {
+		#[cfg(feature = "debug_phys_frames")]
+		unsafe { super::debug::poison_frame(_page_addr); }
+		
+		desc.reset(FrameOwner::Free, 0, FREE_FRAME_FLAGS);
+	});
+}
+
+/// Calls `f` for the descriptor of every page in the range,
+/// skipping shards whose descriptors don't exist (yet)
+fn for_each_frame_desc(start_addr: usize, page_count: usize, f: impl Fn(usize, &FrameDesc)) {
+	let end_addr = start_addr + (page_count << BASE_PAGE_ADDR_BITS);
+	let mut addr = start_addr;
+	
+	while addr < end_addr {
+		let shard_base = addr & !(BUCK_SHARD_SIZE - 1);
+		let chunk_end = end_addr.min(shard_base + BUCK_SHARD_SIZE);
+		
+		if let Some(descs) = shard_of(addr).and_then(descs_of) {
+			let first_page = (addr - shard_base) >> BASE_PAGE_ADDR_BITS;
+			let end_page = (chunk_end - shard_base) >> BASE_PAGE_ADDR_BITS;
+			
+			for page_idx in first_page..end_page {
+				f(shard_base + (page_idx << BASE_PAGE_ADDR_BITS), unsafe { &*descs.add(page_idx) });
+			}
+		}
+		
+		addr = chunk_end;
+	}
+}
+
+/// The descriptors of the shard as seen by the kernel, if they exist yet
+#[inline]
+fn descs_of(slot: &ShardSlot) -> Option<*mut FrameDesc> {
+	let descs_addr = slot.frame_descs.load(Acquire) as usize;
+	
+	if descs_addr == 0 {
+		None
+	} else {
+		Some(phys_to_virt(descs_addr) as *mut FrameDesc)
+	}
+}
@@ -0,0 +1,108 @@
+//! Physical memory accounting and introspection
+//! 
+//! All numbers are only a snapshot, shards are locked one at a time
+//! so allocations on other cpus can happen while it is taken.
+
+use core::fmt;
+
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BUCK_LEVEL_COUNT, BuckStats};
+use crate::mem::phys::frame::{base_of, failed_frame_allocs, shard_slots, PhysZone};
+
+/// Snapshot of the state of the frame allocator, see [`meminfo`]
+#[derive(Clone, Default, Debug)]
+pub struct MemInfo {
+	/// Counts of all shards combined
+	pub total: BuckStats,
+	/// Counts per zone, indexed like [`PhysZone::FALLBACK_ORDER`]
+	pub zones: [BuckStats; 3],
+	/// Number of frame allocations that no shard could satisfy
+	pub failed_frame_allocs: usize,
+}
+
+impl MemInfo {
+	#[inline]
+	pub fn zone(&self, zone: PhysZone) -> &BuckStats {
+		&self.zones[zone_idx(zone)]
+	}
+}
+
+/// Takes a snapshot of the block counts of all shards
+pub fn meminfo() -> MemInfo {
+	let mut info = MemInfo::default();
+	
+	for slot in shard_slots() {
+		slot.lock();
+		let mut stats = unsafe { (*slot.shard.get()).stats() };
+		slot.unlock();
+		stats.remote_free_backlog = slot.remote_frees.backlog();
+		
+		info.zones[zone_idx(slot.zone)].merge(&stats);
+		info.total.merge(&stats);
+	}
+	info.failed_frame_allocs = failed_frame_allocs();
+	
+	info
+}
+
+/// Writes a human readable summary of [`meminfo`] followed by
+/// the tree occupancy of every shard (meant for the serial tty)
+pub fn dump_meminfo(w: &mut impl fmt::Write) -> fmt::Result {
+	let info = meminfo();
+	
+	writeln!(w, "Physical memory: {} shards, {} KiB free, {} KiB used, {} KiB claimed, {} KiB total",
+		info.total.shard_count,
+		pages_to_kib(info.total.free_pages()),
+		pages_to_kib(info.total.used_pages()),
+		pages_to_kib(info.total.claimed_pages),
+		pages_to_kib(info.total.total_pages()),
+	)?;
+	writeln!(w, "Largest free level: {:?}, remote free backlog: {}, failed shard allocs: {}, failed frame allocs: {}",
+		info.total.largest_free_level,
+		info.total.remote_free_backlog,
+		info.total.failed_allocs,
+		info.failed_frame_allocs,
+	)?;
+	
+	for zone in PhysZone::FALLBACK_ORDER {
+		let stats = info.zone(zone);
+		writeln!(w, "  {:?}: {} shards, {} KiB free", zone, stats.shard_count, pages_to_kib(stats.free_pages()))?;
+	}
+	
+	writeln!(w, "  level:  free / used blocks")?;
+	for level in (0..BUCK_LEVEL_COUNT).rev() {
+		writeln!(w, "  {:>5}: {:>5} / {}", level, info.total.free_blocks[level], info.total.used_blocks[level])?;
+	}
+	
+	for slot in shard_slots() {
+		slot.lock();
+		let (stats, occupancy) = unsafe {
+			let shard = &*slot.shard.get();
+			(shard.stats(), shard.occupancy())
+		};
+		slot.unlock();
+		
+		writeln!(w, "Shard {:#x} ({:?}): {} KiB free, largest free level {:?}",
+			base_of(slot),
+			slot.zone,
+			pages_to_kib(stats.free_pages()),
+			stats.largest_free_level,
+		)?;
+		occupancy.write_to(w)?;
+	}
+	
+	Ok(())
+}
+
+#[inline(always)]
+const fn pages_to_kib(page_count: usize) -> usize {
+	(page_count << BASE_PAGE_ADDR_BITS) >> 10
+}
+
+#[inline]
+fn zone_idx(zone: PhysZone) -> usize {
+	match zone {
+		PhysZone::Normal => 0,
+		PhysZone::Dma32 => 1,
+		PhysZone::IsaDma => 2,
+	}
+}
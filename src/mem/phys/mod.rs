@@ -1 +1,14 @@
+pub use contig::*;
+pub use frame::*;
+pub use frame_desc::*;
+pub use meminfo::*;
+pub use reserved::*;
+
 pub mod buck;
+mod contig;
+#[cfg(feature = "debug_phys_frames")]
+mod debug;
+mod frame;
+mod frame_desc;
+mod meminfo;
+mod reserved;
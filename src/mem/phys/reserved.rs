@@ -0,0 +1,229 @@
+//! Registry of physical memory ranges that must not be handed out by the frame allocator
+//! 
+//! The registry is filled on the bootstrap processor during `init_kernel`
+//! before the frame allocator is initialized, as only then the ranges can still be
+//! kept out of its shards. Afterwards it only shrinks when reclaimable ranges
+//! are given back via [`reclaim`].
+//! 
+//! Note that reclaimable ranges never overlap any other range, so that they can
+//! be reserved in the shards on their own and later be released with exactly
+//! the same bounds. [`reserve_phys_range`] enforces this.
+
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::*;
+
+use uefi_rs::table::boot::{MemoryDescriptor, MemoryType};
+
+use crate::mem::kaslr::image_virt_to_phys;
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BASE_PAGE_SIZE};
+use crate::mem::phys::frame::is_frame_alloc_ready;
+
+/// Max number of distinct reserved ranges. Adjacent ranges of the
+/// same kind are merged so this is plenty even for big memory maps.
+const MAX_RESERVED_RANGES: usize = 256;
+
+static mut RESERVED_RANGES: [ReservedRange; MAX_RESERVED_RANGES] = [ReservedRange::EMPTY; MAX_RESERVED_RANGES];
+static RESERVED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ReservedKind {
+	/// The real mode IVT and BDA page, also keeps null out of the allocator
+	NullPage,
+	/// The loaded kernel ELF image
+	KernelImage,
+	/// Memory owned by the bootloader and [`crate::uefi::boot_alloc::UefiBootAlloc`]
+	Loader,
+	/// The firmware stack the bootstrap processor is still running on
+	BootStack,
+	/// Boot services code and data, free to use once nothing refers to it anymore
+	BootServices,
+	/// ACPI tables, free to use once ACPICA is done with them
+	AcpiReclaim,
+	/// ACPI non-volatile storage, must be preserved forever
+	AcpiNvs,
+	/// UEFI runtime services code and data
+	UefiRuntime,
+	/// Memory mapped device registers (LAPIC, IOAPIC, ..)
+	Mmio,
+	/// Reserved or unusable according to the firmware
+	Firmware,
+	/// The frame allocator's own bookkeeping
+	FrameAllocMeta,
+}
+
+impl ReservedKind {
+	/// Whether ranges of this kind can be given back with [`reclaim`]
+	#[inline]
+	pub fn reclaim_kind(self) -> Option<ReclaimKind> {
+		match self {
+			Self::BootServices => Some(ReclaimKind::BootServices),
+			Self::AcpiReclaim => Some(ReclaimKind::AcpiReclaim),
+			_ => None,
+		}
+	}
+}
+
+/// Kinds of reserved memory that can be handed over to the frame allocator after boot
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ReclaimKind {
+	AcpiReclaim,
+	BootServices,
+}
+
+/// A page aligned reserved physical address range
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ReservedRange {
+	pub start: usize,
+	/// Exclusive end address
+	pub end: usize,
+	pub kind: ReservedKind,
+}
+
+impl ReservedRange {
+	const EMPTY: Self = Self {start: 0, end: 0, kind: ReservedKind::Firmware};
+	
+	#[inline]
+	pub fn contains(&self, addr: usize) -> bool {
+		self.start <= addr && addr < self.end
+	}
+	
+	#[inline]
+	pub fn overlaps(&self, start: usize, end: usize) -> bool {
+		self.start < end && start < self.end
+	}
+}
+
+/// Adds the range `start..end` to the registry, widened to page boundaries.
+/// Must be called before the frame allocator is initialized.
+/// 
+/// Panics if the range would overlap a range of another kind
+/// while either of them is reclaimable.
+pub fn reserve_phys_range(start: usize, end: usize, kind: ReservedKind) {
+	assert!(!is_frame_alloc_ready(), "Reserved a physical range after the frame allocator was initialized");
+	
+	let start = start & !(BASE_PAGE_SIZE - 1);
+	let end = (end + (BASE_PAGE_SIZE - 1)) & !(BASE_PAGE_SIZE - 1);
+	if start >= end {
+		return;
+	}
+	
+	unsafe {
+		let count = RESERVED_COUNT.load(Acquire);
+		
+		// Reclaimable ranges must be released with exactly the bounds they were reserved with
+		if let Some(conflict) = RESERVED_RANGES[..count].iter()
+			.find(|range| range.kind != kind && range.overlaps(start, end)
+				&& (range.kind.reclaim_kind().is_some() || kind.reclaim_kind().is_some())) {
+			panic!("Reserved range {:#x}..{:#x} ({:?}) overlaps the reclaimable range {:#x}..{:#x} ({:?})",
+				start, end, kind, conflict.start, conflict.end, conflict.kind);
+		}
+		
+		// Merge with an adjacent range of the same kind
+		if let Some(existing) = RESERVED_RANGES[..count].iter_mut()
+			.find(|range| range.kind == kind && range.start <= end && start <= range.end) {
+			existing.start = existing.start.min(start);
+			existing.end = existing.end.max(end);
+			return;
+		}
+		
+		assert!(count < MAX_RESERVED_RANGES, "Too many reserved physical ranges");
+		RESERVED_RANGES[count] = ReservedRange {start, end, kind};
+		RESERVED_COUNT.store(count + 1, Release);
+	}
+}
+
+/// All currently reserved ranges in the order they were registered
+#[inline]
+pub fn reserved_ranges() -> &'static [ReservedRange] {
+	unsafe {
+		&RESERVED_RANGES[..RESERVED_COUNT.load(Acquire)]
+	}
+}
+
+/// The reserved range containing the physical address, if any
+pub fn reserved_range_of(addr: usize) -> Option<ReservedRange> {
+	reserved_ranges().iter()
+		.find(|range| range.contains(addr))
+		.copied()
+}
+
+/// Whether any part of `start..end` is reserved
+pub fn is_phys_range_reserved(start: usize, end: usize) -> bool {
+	reserved_ranges().iter()
+		.any(|range| range.overlaps(start, end))
+}
+
+/// Registers everything in the memory map that is not plain conventional memory.
+/// 
+/// `stack_ptr` is the current stack pointer, the boot services range
+/// containing it is registered as [`ReservedKind::BootStack`] instead.
+pub fn reserve_uefi_mmap<'a>(mmap: impl Iterator<Item = &'a MemoryDescriptor>, stack_ptr: usize) {
+	// Reserve the null page first so reclaimable ranges can be cut around it
+	reserve_phys_range(0, BASE_PAGE_SIZE, ReservedKind::NullPage);
+	
+	for desc in mmap {
+		let mut start = desc.phys_start as usize;
+		let end = start + ((desc.page_count as usize) << BASE_PAGE_ADDR_BITS);
+		
+		let kind = match desc.ty {
+			MemoryType::CONVENTIONAL => continue,
+			MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => {
+				if start <= stack_ptr && stack_ptr < end {
+					ReservedKind::BootStack
+				} else {
+					ReservedKind::BootServices
+				}
+			},
+			MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => ReservedKind::Loader,
+			MemoryType::ACPI_RECLAIM => ReservedKind::AcpiReclaim,
+			MemoryType::ACPI_NON_VOLATILE => ReservedKind::AcpiNvs,
+			MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => ReservedKind::UefiRuntime,
+			MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => ReservedKind::Mmio,
+			_ => ReservedKind::Firmware,
+		};
+		
+		if kind.reclaim_kind().is_some() {
+			start = start.max(BASE_PAGE_SIZE);
+		}
+		reserve_phys_range(start, end, kind);
+	}
+}
+
+/// Registers the physical extents of the loaded kernel ELF image
+pub fn reserve_kernel_image() {
+	extern "C" {
+		static __kernel_image_start: u8;
+		static __kernel_image_end: u8;
+	}
+	
+	// The image runs relocated, see crate::mem::kaslr
+	unsafe {
+		reserve_phys_range(
+			image_virt_to_phys(&__kernel_image_start as *const u8 as usize),
+			image_virt_to_phys(&__kernel_image_end as *const u8 as usize),
+			ReservedKind::KernelImage,
+		);
+	}
+}
+
+/// Hands all reserved ranges of the kind over to the frame allocator.
+/// 
+/// The caller must make sure nothing refers to the memory anymore
+/// (e.g. ACPICA must not access the ACPI tables anymore).
+pub unsafe fn reclaim(kind: ReclaimKind) {
+	let mut range_idx = 0;
+	while range_idx < RESERVED_COUNT.load(Acquire) {
+		let range = RESERVED_RANGES[range_idx];
+		
+		if range.kind.reclaim_kind() == Some(kind) {
+			super::frame::release_reserved(range.start, range.end);
+			
+			// Order doesn't matter so just swap remove
+			let last_idx = RESERVED_COUNT.load(Acquire) - 1;
+			RESERVED_RANGES[range_idx] = RESERVED_RANGES[last_idx];
+			RESERVED_COUNT.store(last_idx, Release);
+		} else {
+			range_idx += 1;
+		}
+	}
+}
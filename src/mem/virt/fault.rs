@@ -0,0 +1,81 @@
+//! Page fault resolution
+//! 
+//! The #PF entry in [`crate::arch::x86_64::exception`] hands every fault to
+//! [`handle_page_fault`], which looks up the region of the faulting address
+//! and either backs it with memory or reports why the access is invalid.
+
+use crate::arch::x86_64::exception::PageFaultErrCode;
+use crate::mem::VirtAddr;
+use crate::mem::kernel_mem_map::{kernel_image_section, KernelSection, vmalloc_range};
+use crate::mem::virt::{handle_vmalloc_fault, virt_addr_bits};
+use crate::proc::with_current_mem_map;
+
+/// A decoded page fault
+#[derive(Copy, Clone, Debug)]
+pub struct PageFault {
+	/// The faulting linear address (from cr2)
+	pub addr: usize,
+	pub err: PageFaultErrCode,
+	/// Address of the faulting instruction
+	pub rip: usize,
+}
+
+impl PageFault {
+	/// Whether the address is in the lower (user) half of the address space
+	#[inline]
+	pub fn is_user_addr(&self) -> bool {
+		self.addr < (0x1 << (virt_addr_bits() - 1))
+	}
+}
+
+/// Why a page fault could not be resolved
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FaultErr {
+	/// No region covers the address
+	NoRegion,
+	/// The address is in the guard gap next to the region starting at the contained address
+	GuardPage(VirtAddr),
+	/// The region is there but does not allow the access
+	ProtectionViolation,
+	/// The access violates the permissions of a section of the kernel image,
+	/// e.g. a write to its code or an instruction fetch from its data
+	KernelImage(KernelSection),
+	/// A paging structure has a reserved bit set, the page table is corrupted
+	ReservedBit,
+	/// No physical memory left to back the page
+	OutOfMemory,
+	/// A user address faulted while the cpu runs in the kernel's own address space
+	NoAddressSpace,
+}
+
+/// Resolves a page fault, returning `Ok` if the faulting access can be retried
+pub fn handle_page_fault(fault: &PageFault) -> Result<(), FaultErr> {
+	if fault.err.contains(PageFaultErrCode::RESERVED_BIT) {
+		return Err(FaultErr::ReservedBit);
+	}
+	
+	if fault.err.contains(PageFaultErrCode::PRESENT) {
+		if let Some(section) = kernel_image_section(fault.addr) {
+			return Err(FaultErr::KernelImage(section));
+		}
+	}
+	
+	if fault.is_user_addr() {
+		return with_current_mem_map(|map| match map {
+			Some(map) => map.handle_fault(fault),
+			None => Err(FaultErr::NoAddressSpace),
+		});
+	}
+	
+	if fault.err.contains(PageFaultErrCode::USER) {
+		return Err(FaultErr::ProtectionViolation);
+	}
+	
+	// Faulting addresses are always canonical, non-canonical accesses raise #GP instead
+	let addr = VirtAddr::new_truncate(fault.addr);
+	if vmalloc_range().contains(addr) {
+		return handle_vmalloc_fault(addr, fault.err);
+	}
+	
+	Err(FaultErr::NoRegion)
+}
@@ -0,0 +1,141 @@
+//! Mappings of device memory
+//! 
+//! [`ioremap`] maps a physical range with the requested memory type into the
+//! vmalloc area (see [`super::vmalloc`]), surrounded by guard pages like every other
+//! area there. The mapping is torn down again when the returned [`IoMapping`] is dropped.
+
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+use crate::mem::{PhysAddr, PhysRange, VirtAddr};
+use crate::mem::kernel_mem_map::{is_direct_mapped, with_kernel_mem_map};
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+use crate::mem::virt::{CacheMode, PteFlags, VmallocErr};
+use crate::mem::virt::vmalloc::{AreaKind, find_area, release_area, reserve_area, unmap_pages};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum IoRemapErr {
+	/// No free virtual range of the size left
+	OutOfVirtualSpace,
+	/// No physical memory left for the page tables
+	OutOfMemory,
+	/// The range contains RAM (including firmware memory like ACPI NVS), which
+	/// must only be accessed through the (write back) direct map
+	RamRange,
+}
+
+impl From<VmallocErr> for IoRemapErr {
+	#[inline]
+	fn from(err: VmallocErr) -> Self {
+		match err {
+			VmallocErr::OutOfVirtualSpace => Self::OutOfVirtualSpace,
+			VmallocErr::OutOfMemory => Self::OutOfMemory,
+			VmallocErr::InvalidPtr => unreachable!("ioremap used an invalid vmalloc area"),
+		}
+	}
+}
+
+/// A mapping of device memory made by [`ioremap`], unmapped on drop
+pub struct IoMapping {
+	/// Virtual address of the first mapped byte (not necessarily page aligned)
+	virt_addr: VirtAddr,
+	phys_addr: PhysAddr,
+	size: usize,
+}
+
+unsafe impl Send for IoMapping {}
+unsafe impl Sync for IoMapping {}
+
+impl IoMapping {
+	#[inline]
+	pub fn as_ptr<T>(&self) -> *mut T {
+		self.virt_addr.as_mut_ptr()
+	}
+	
+	#[inline]
+	pub fn phys_addr(&self) -> PhysAddr {
+		self.phys_addr
+	}
+	
+	#[inline]
+	pub fn size(&self) -> usize {
+		self.size
+	}
+	
+	/// Reads the value at the byte offset with a single access
+	#[inline]
+	pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+		assert!(offset + size_of::<T>() <= self.size, "MMIO read at {:#x} out of bounds", offset);
+		(self.virt_addr + offset).as_ptr::<T>().read_volatile()
+	}
+	
+	/// Writes the value at the byte offset with a single access
+	#[inline]
+	pub unsafe fn write<T: Copy>(&self, offset: usize, val: T) {
+		assert!(offset + size_of::<T>() <= self.size, "MMIO write at {:#x} out of bounds", offset);
+		(self.virt_addr + offset).as_mut_ptr::<T>().write_volatile(val);
+	}
+	
+	/// Keeps the mapping forever and returns its address,
+	/// it can be unmapped with [`iounmap`] later on
+	#[inline]
+	pub fn leak(self) -> NonNull<u8> {
+		let ptr = self.as_ptr::<u8>();
+		core::mem::forget(self);
+		unsafe { NonNull::new_unchecked(ptr) }
+	}
+}
+
+impl Drop for IoMapping {
+	fn drop(&mut self) {
+		unsafe {
+			iounmap(NonNull::new_unchecked(self.as_ptr()));
+		}
+	}
+}
+
+/// Maps `size` bytes of device memory at `phys_addr` with the memory type `mode`
+/// 
+/// RAM is refused, it is only ever accessed through the direct map.
+pub fn ioremap(phys_addr: PhysAddr, size: usize, mode: CacheMode) -> Result<IoMapping, IoRemapErr> {
+	let range = PhysRange::with_size(phys_addr, size.max(1)).align_outward(BASE_PAGE_SIZE);
+	
+	// A second mapping with another memory type would make accesses to the RAM undefined
+	let mut page = range.start;
+	while page < range.end {
+		if is_direct_mapped(page) {
+			return Err(IoRemapErr::RamRange);
+		}
+		page += BASE_PAGE_SIZE;
+	}
+	
+	let page_count = range.size() / BASE_PAGE_SIZE;
+	let start = reserve_area(page_count, AreaKind::Io)?;
+	
+	let flags = mode.pte_flags() | PteFlags::WRITABLE | PteFlags::GLOBAL | PteFlags::NO_EXECUTE;
+	let mapped = with_kernel_mem_map(|map| {
+		map.page_table_mut().map(start.as_usize(), range.start.as_usize(), range.size(), flags)
+	});
+	if let Err(err) = mapped {
+		release_area(start);
+		return Err(VmallocErr::from(err).into());
+	}
+	
+	Ok(IoMapping {
+		virt_addr: start + phys_addr.align_offset(BASE_PAGE_SIZE),
+		phys_addr,
+		size,
+	})
+}
+
+/// Unmaps a mapping made by [`ioremap`] and leaked with [`IoMapping::leak`].
+/// `ptr` may point anywhere into the first page of the mapping.
+pub unsafe fn iounmap(ptr: NonNull<u8>) {
+	let start = VirtAddr::from_ptr(ptr.as_ptr()).align_down(BASE_PAGE_SIZE);
+	let area = find_area(start)
+		.filter(|area| area.kind == AreaKind::Io)
+		.expect("Unmapped memory that was not mapped with ioremap");
+	
+	unmap_pages(area.start, area.page_count, false);
+	release_area(area.start);
+}
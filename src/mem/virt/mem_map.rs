@@ -1,4 +1,877 @@
+//! User address spaces
+//! 
+//! A [`MemMap`] owns the page table of one user address space together with a
+//! sorted list of non-overlapping [`VmArea`]s describing what may be mapped where.
+//! The page table only ever contains pages of these areas, and they are mapped
+//! lazily by the page fault handler (see [`MemMap::handle_fault`]).
+//! 
+//! Anonymous memory can be shared copy-on-write between address spaces (see
+//! [`MemMap::clone_cow`]). Such frames are mapped read-only everywhere and marked
+//! with [`FrameFlags::COPY_ON_WRITE`], the first write to one of them copies it,
+//! unless the writer turns out to be the only one left referencing it.
+//! 
+//! The upper half and the first root entry of every user page table are shared
+//! with the kernel's page table, so user areas live between the end of the first
+//! root entry and the end of the lower half (see [`user_range`]).
 
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ops::BitOr;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::*;
+
+use fallo::alloc::FallibleAlloc;
+use fallo::stdalloc::Layout;
+
+use crate::arch::x86_64::exception::PageFaultErrCode;
+use crate::mem::{Phys, PhysAddr, VirtAddr, VirtRange};
+use crate::mem::heap::KernelHeap;
+use crate::mem::kernel_mem_map::{phys_to_virt, with_kernel_mem_map};
+use crate::mem::phys::{alloc_frames, frame_desc, frame_desc_of, FrameDesc, FrameFlags, free_frames, FrameOwner};
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BASE_PAGE_SIZE, BasePage, BuckBlock};
+use crate::mem::virt::{FaultErr, MapErr, PageFault, PageTable, PteFlags, root_entry_size, SharedObj, user_copy_fixup, virt_addr_bits};
+
+/// Max number of areas per address space
+const MAX_VM_AREAS: usize = 256;
+
+/// The part of the lower half available to user areas.
+/// 
+/// The first root entry is shared with the kernel, which keeps the rest of the
+/// lower half free (see [`crate::mem::kernel_mem_map::init_kernel_mem_map`]).
+/// The last page is left out so sysret can never return to a non-canonical address.
+#[inline]
+pub fn user_range() -> VirtRange {
+	VirtRange::new(VirtAddr::new(root_entry_size()), VirtAddr::new((0x1 << (virt_addr_bits() - 1)) - BASE_PAGE_SIZE))
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MemMapErr {
+	/// The range is empty, not page aligned or not inside of [`user_range`]
+	InvalidRange,
+	/// Part of the range is already covered by an area
+	Overlap,
+	/// The address space has [`MAX_VM_AREAS`] areas already
+	TooManyAreas,
+	OutOfMemory,
+}
+
+impl From<MapErr> for MemMapErr {
+	#[inline]
+	fn from(err: MapErr) -> Self {
+		match err {
+			MapErr::OutOfMemory => Self::OutOfMemory,
+			_ => unreachable!("Failed to map user pages: {:?}", err),
+		}
+	}
+}
+
+/// Access rights of an area
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(transparent)]
+pub struct VmProt(pub u8);
+
+impl VmProt {
+	pub const NONE: Self = Self(0);
+	pub const READ: Self = Self(0x1 << 0);
+	/// Note: Writable pages are always readable as well
+	pub const WRITE: Self = Self(0x1 << 1);
+	/// Note: Executable pages are always readable as well
+	pub const EXEC: Self = Self(0x1 << 2);
+	
+	#[inline]
+	pub const fn contains(self, other: Self) -> bool {
+		(self.0 & other.0) == other.0
+	}
+	
+	/// The page table flags of pages with these rights.
+	/// Inaccessible pages are mapped kernel only, so user accesses still fault.
+	pub fn pte_flags(self) -> PteFlags {
+		let mut flags = PteFlags::EMPTY;
+		if self != Self::NONE {
+			flags = flags | PteFlags::USER;
+		}
+		if self.contains(Self::WRITE) {
+			flags = flags | PteFlags::WRITABLE;
+		}
+		if !self.contains(Self::EXEC) {
+			flags = flags | PteFlags::NO_EXECUTE;
+		}
+		flags
+	}
+}
+
+impl BitOr for VmProt {
+	type Output = Self;
+	
+	#[inline]
+	fn bitor(self, rhs: Self) -> Self {
+		Self(self.0 | rhs.0)
+	}
+}
+
+/// Where the pages of an area come from
+#[derive(Clone)]
+pub enum Backing {
+	/// Private zeroed memory, allocated on first access
+	Anonymous,
+	/// A fixed physical range starting at the address, e.g. a framebuffer
+	Physical(PhysAddr),
+	/// The pages of a shared object, starting at the page offset
+	Shared {obj: SharedObj, page_offset: usize},
+}
+
+impl Backing {
+	/// The backing of the part of an area starting `page_count` pages into it
+	fn advance(&self, page_count: usize) -> Self {
+		match self {
+			Self::Anonymous => Self::Anonymous,
+			Self::Physical(base) => Self::Physical(*base + (page_count * BASE_PAGE_SIZE)),
+			Self::Shared {obj, page_offset} => Self::Shared {obj: obj.clone(), page_offset: page_offset + page_count},
+		}
+	}
+	
+	/// Whether an area with this backing of `page_count` pages can be merged with a following one
+	fn continues_into(&self, page_count: usize, next: &Self) -> bool {
+		match (self, next) {
+			(Self::Anonymous, Self::Anonymous) => true,
+			(Self::Physical(base), Self::Physical(next_base)) => *base + (page_count * BASE_PAGE_SIZE) == *next_base,
+			(Self::Shared {obj, page_offset}, Self::Shared {obj: next_obj, page_offset: next_offset}) => {
+				obj.ptr_eq(next_obj) && page_offset + page_count == *next_offset
+			},
+			_ => false,
+		}
+	}
+}
+
+/// A page aligned range of an address space with uniform rights and backing
+#[derive(Clone)]
+pub struct VmArea {
+	pub range: VirtRange,
+	pub prot: VmProt,
+	pub backing: Backing,
+}
+
+impl VmArea {
+	#[inline]
+	pub fn page_count(&self) -> usize {
+		self.range.size() / BASE_PAGE_SIZE
+	}
+}
+
+/// A user address space, see the module docs
 pub struct MemMap {
+	locked: AtomicBool,
+	inner: UnsafeCell<MemMapInner>,
+}
+
+unsafe impl Send for MemMap {}
+unsafe impl Sync for MemMap {}
+
+struct MemMapInner {
+	page_table: PageTable,
+	areas: VmAreas,
+}
+
+/// Areas sorted by address in a buffer of `MAX_VM_AREAS` slots, of which `count` are used
+struct VmAreas {
+	ptr: NonNull<VmArea>,
+	count: usize,
+}
+
+impl MemMap {
+	/// Creates an address space without any areas
+	pub fn new() -> Result<Self, MemMapErr> {
+		let areas = KernelHeap.alloc(areas_layout())
+			.map_err(|_| MemMapErr::OutOfMemory)?
+			.cast::<VmArea>();
+		
+		let page_table = match with_kernel_mem_map(|map| PageTable::new_user(map.page_table())) {
+			Ok(page_table) => page_table,
+			Err(err) => {
+				unsafe {
+					KernelHeap.dealloc(areas.cast(), areas_layout());
+				}
+				return Err(err.into());
+			},
+		};
+		
+		Ok(Self {
+			locked: AtomicBool::new(false),
+			inner: UnsafeCell::new(MemMapInner {
+				page_table,
+				areas: VmAreas {ptr: areas, count: 0},
+			}),
+		})
+	}
+	
+	/// Loads the page table of the address space into cr3
+	pub unsafe fn activate(&self) {
+		self.with_inner(|inner| inner.page_table.activate());
+	}
+	
+	/// Calls `f` with the areas of the address space, sorted by address
+	pub fn with_areas<R>(&self, f: impl FnOnce(&[VmArea]) -> R) -> R {
+		self.with_inner(|inner| f(inner.areas.as_slice()))
+	}
+	
+	/// The lowest free range of the size in [`user_range`]
+	pub fn find_free_range(&self, size: usize) -> Option<VirtRange> {
+		if size == 0 || (size & (BASE_PAGE_SIZE - 1)) != 0 {
+			return None;
+		}
+		self.with_inner(|inner| inner.areas.find_free(user_range(), size))
+	}
+	
+	/// Creates an area covering the range, which must not overlap any other area.
+	/// The area is merged with compatible neighbours.
+	pub fn map_area(&self, range: VirtRange, prot: VmProt, backing: Backing) -> Result<(), MemMapErr> {
+		check_user_range(range)?;
+		
+		self.with_inner(|inner| {
+			inner.areas.insert_sorted(VmArea {range, prot, backing})?;
+			inner.areas.merge();
+			Ok(())
+		})
+	}
+	
+	/// Removes everything in the range from the address space, splitting areas
+	/// that are only partially covered. Unused parts of the range are skipped.
+	pub fn unmap_range(&self, range: VirtRange) -> Result<(), MemMapErr> {
+		check_user_range(range)?;
+		
+		self.with_inner(|inner| {
+			inner.areas.split(range.start)?;
+			inner.areas.split(range.end)?;
+			
+			while let Some(idx) = inner.areas.as_slice().iter().position(|area| range.contains(area.range.start)) {
+				let area = inner.areas.remove(idx);
+				inner.unmap_pages(&area, area.range);
+			}
+			Ok(())
+		})
+	}
+	
+	/// Changes the rights of everything in the range, splitting areas that are
+	/// only partially covered. Unused parts of the range are skipped.
+	pub fn protect_range(&self, range: VirtRange, prot: VmProt) -> Result<(), MemMapErr> {
+		check_user_range(range)?;
+		
+		self.with_inner(|inner| {
+			inner.areas.split(range.start)?;
+			inner.areas.split(range.end)?;
+			
+			for idx in 0..inner.areas.count {
+				let area = unsafe { &mut *inner.areas.ptr.as_ptr().add(idx) };
+				if range.contains(area.range.start) && area.prot != prot {
+					area.prot = prot;
+					inner.protect_pages(area)?;
+				}
+			}
+			
+			inner.areas.merge();
+			Ok(())
+		})
+	}
+	
+	/// Duplicates the address space. Anonymous memory is shared copy-on-write
+	/// between both (see the module docs), all other areas share their pages.
+	pub fn clone_cow(&self) -> Result<MemMap, MemMapErr> {
+		let mut clone = MemMap::new()?;
+		let clone_inner = clone.inner.get_mut();
+		
+		self.with_inner(|inner| {
+			for idx in 0..inner.areas.count {
+				let area = inner.areas.as_slice()[idx].clone();
+				let (start, end) = (area.range.start.as_usize(), area.range.end.as_usize());
+				let is_anonymous_area = matches!(area.backing, Backing::Anonymous);
+				clone_inner.areas.insert(idx, area)?;
+				
+				let mut addr = start;
+				while let Some((virt_addr, translation)) = inner.page_table.next_mapping(addr, end) {
+					addr = virt_addr + BASE_PAGE_SIZE;
+					
+					let mut flags = translation.flags.without(PteFlags::ACCESSED | PteFlags::DIRTY);
+					let desc = frame_desc(translation.phys_addr >> BASE_PAGE_ADDR_BITS);
+					let is_anonymous = is_anonymous_area && desc.is_some();
+					
+					// Note: The flag alone is harmless, see is_shared_cow
+					if let (true, Some(desc)) = (is_anonymous, desc) {
+						desc.set_flags(FrameFlags::COPY_ON_WRITE);
+						
+						if flags.contains(PteFlags::WRITABLE) {
+							flags = flags.without(PteFlags::WRITABLE);
+							inner.page_table.protect(virt_addr, BASE_PAGE_SIZE, flags)?;
+						}
+					}
+					
+					// Note: Dropping the clone on error releases everything mapped into it so far
+					clone_inner.page_table.map(virt_addr, translation.phys_addr, BASE_PAGE_SIZE, flags)?;
+					if let Some(desc) = desc {
+						if is_anonymous {
+							desc.get();
+						}
+						desc.inc_map_count();
+					}
+				}
+			}
+			Ok(())
+		})?;
+		
+		Ok(clone)
+	}
+	
+	/// Splits the area containing the address in two at the address.
+	/// Does nothing if an area starts at the address or no area contains it.
+	pub fn split_area(&self, addr: VirtAddr) -> Result<(), MemMapErr> {
+		if !addr.is_aligned(BASE_PAGE_SIZE) {
+			return Err(MemMapErr::InvalidRange);
+		}
+		self.with_inner(|inner| inner.areas.split(addr))
+	}
+	
+	/// Merges all adjacent areas with the same rights and continuous backing
+	pub fn merge_areas(&self) {
+		self.with_inner(|inner| inner.areas.merge());
+	}
+	
+	/// Resolves a fault on a user address in this address space by mapping the
+	/// page from the backing of its area, if the area allows the access
+	pub fn handle_fault(&self, fault: &PageFault) -> Result<(), FaultErr> {
+		let addr = VirtAddr::new_truncate(fault.addr);
+		let page = addr.align_down(BASE_PAGE_SIZE);
+		
+		self.with_inner(|inner| {
+			let area = inner.areas.as_slice().iter()
+				.find(|area| area.range.contains(addr))
+				.cloned()
+				.ok_or(FaultErr::NoRegion)?;
+			
+			let denied = area.prot == VmProt::NONE
+				|| (fault.err.contains(PageFaultErrCode::WRITE) && !area.prot.contains(VmProt::WRITE))
+				|| (fault.err.contains(PageFaultErrCode::INSTRUCTION_FETCH) && !area.prot.contains(VmProt::EXEC))
+				// Kernel code never runs from user pages
+				|| (fault.err.contains(PageFaultErrCode::INSTRUCTION_FETCH) && !fault.err.contains(PageFaultErrCode::USER));
+			if denied {
+				return Err(FaultErr::ProtectionViolation);
+			}
+			
+			if fault.err.contains(PageFaultErrCode::PRESENT) {
+				// The kernel only accesses user pages through the user copy routines,
+				// anything else is an SMAP violation that retrying would never get past
+				let from_user = fault.err.contains(PageFaultErrCode::USER);
+				if !from_user && user_copy_fixup(fault.rip).is_none() {
+					return Err(FaultErr::ProtectionViolation);
+				}
+				
+				// Pages are mapped with the rights of their area, except for copy-on-write pages
+				return match (&area.backing, fault.err.contains(PageFaultErrCode::WRITE)) {
+					(Backing::Anonymous, true) => inner.resolve_cow(page, &area, from_user),
+					_ => Err(FaultErr::ProtectionViolation),
+				};
+			}
+			
+			let page_idx = (page - area.range.start) / BASE_PAGE_SIZE;
+			let flags = area.prot.pte_flags();
+			
+			match &area.backing {
+				Backing::Anonymous => {
+					let block = alloc_frames(0).ok_or(FaultErr::OutOfMemory)?;
+					let desc = frame_desc_of(&block);
+					desc.set_owner(FrameOwner::User);
+					unsafe {
+						ptr::write_bytes(block.ptr().to_virt().as_ptr() as *mut u8, 0, BASE_PAGE_SIZE);
+					}
+					
+					let phys_addr = block.ptr().addr().as_usize();
+					if let Err(err) = inner.page_table.map(page.as_usize(), phys_addr, BASE_PAGE_SIZE, flags) {
+						unsafe {
+							free_frames(block);
+						}
+						return Err(map_fault_err(err));
+					}
+					desc.inc_map_count();
+				},
+				Backing::Physical(base) => {
+					let phys_addr = *base + (page_idx * BASE_PAGE_SIZE);
+					inner.page_table.map(page.as_usize(), phys_addr.as_usize(), BASE_PAGE_SIZE, flags)
+						.map_err(map_fault_err)?;
+				},
+				Backing::Shared {obj, page_offset} => {
+					let phys_addr = obj.frame(page_offset + page_idx).ok_or(FaultErr::OutOfMemory)?;
+					inner.page_table.map(page.as_usize(), phys_addr.as_usize(), BASE_PAGE_SIZE, flags)
+						.map_err(map_fault_err)?;
+					
+					if let Some(desc) = frame_desc(phys_addr.frame_number()) {
+						desc.inc_map_count();
+					}
+				},
+			}
+			Ok(())
+		})
+	}
+	
+	fn with_inner<R>(&self, f: impl FnOnce(&mut MemMapInner) -> R) -> R {
+		while self.locked.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+			unsafe {
+				asm!("pause", options(nomem, nostack));
+			}
+		}
+		
+		let ret = f(unsafe { &mut *self.inner.get() });
+		
+		self.locked.store(false, Release);
+		ret
+	}
+}
+
+impl Drop for MemMap {
+	fn drop(&mut self) {
+		let inner = self.inner.get_mut();
+		
+		while inner.areas.count > 0 {
+			let area = inner.areas.remove(inner.areas.count - 1);
+			inner.unmap_pages(&area, area.range);
+		}
+		
+		// The page table frees its own tables when dropped after this
+		unsafe {
+			KernelHeap.dealloc(inner.areas.ptr.cast(), areas_layout());
+		}
+	}
+}
+
+impl VmAreas {
+	#[inline]
+	fn as_slice(&self) -> &[VmArea] {
+		unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.count) }
+	}
+	
+	fn insert(&mut self, idx: usize, area: VmArea) -> Result<(), MemMapErr> {
+		if self.count == MAX_VM_AREAS {
+			return Err(MemMapErr::TooManyAreas);
+		}
+		
+		unsafe {
+			let slot = self.ptr.as_ptr().add(idx);
+			ptr::copy(slot, slot.add(1), self.count - idx);
+			slot.write(area);
+		}
+		self.count += 1;
+		Ok(())
+	}
+	
+	/// Inserts the area at its place, it must not overlap any other area
+	fn insert_sorted(&mut self, area: VmArea) -> Result<(), MemMapErr> {
+		let idx = self.as_slice().iter()
+			.position(|other| other.range.start >= area.range.end)
+			.unwrap_or(self.count);
+		
+		if idx > 0 && self.as_slice()[idx - 1].range.end > area.range.start {
+			return Err(MemMapErr::Overlap);
+		}
+		self.insert(idx, area)
+	}
+	
+	fn remove(&mut self, idx: usize) -> VmArea {
+		assert!(idx < self.count, "Removed a nonexistent area");
+		
+		unsafe {
+			let slot = self.ptr.as_ptr().add(idx);
+			let area = slot.read();
+			ptr::copy(slot.add(1), slot, self.count - idx - 1);
+			self.count -= 1;
+			area
+		}
+	}
+	
+	/// Splits the area containing the address in two at the address, if any
+	fn split(&mut self, addr: VirtAddr) -> Result<(), MemMapErr> {
+		let idx = match self.as_slice().iter().position(|area| area.range.start < addr && addr < area.range.end) {
+			Some(idx) => idx,
+			None => return Ok(()),
+		};
+		
+		let area = &self.as_slice()[idx];
+		let head_pages = (addr - area.range.start) / BASE_PAGE_SIZE;
+		let tail = VmArea {
+			range: VirtRange::new(addr, area.range.end),
+			prot: area.prot,
+			backing: area.backing.advance(head_pages),
+		};
+		
+		self.insert(idx + 1, tail)?;
+		unsafe {
+			(*self.ptr.as_ptr().add(idx)).range.end = addr;
+		}
+		Ok(())
+	}
+	
+	/// Merges all adjacent areas with the same rights and continuous backing
+	fn merge(&mut self) {
+		let mut idx = 0;
+		while idx + 1 < self.count {
+			let (area, next) = (&self.as_slice()[idx], &self.as_slice()[idx + 1]);
+			
+			if area.range.end == next.range.start
+				&& area.prot == next.prot
+				&& area.backing.continues_into(area.page_count(), &next.backing) {
+				let next = self.remove(idx + 1);
+				unsafe {
+					(*self.ptr.as_ptr().add(idx)).range.end = next.range.end;
+				}
+			} else {
+				idx += 1;
+			}
+		}
+	}
+	
+	/// The lowest free range of the size inside of `within`
+	fn find_free(&self, within: VirtRange, size: usize) -> Option<VirtRange> {
+		let mut candidate = within.start;
+		for area in self.as_slice() {
+			if area.range.end <= candidate {
+				continue;
+			}
+			if area.range.start >= candidate && area.range.start - candidate >= size {
+				break;
+			}
+			candidate = area.range.end;
+		}
+		
+		if within.end >= candidate && within.end - candidate >= size {
+			Some(VirtRange::with_size(candidate, size))
+		} else {
+			None
+		}
+	}
+}
+
+impl MemMapInner {
+	/// Applies the rights of the area to all of its mapped pages,
+	/// keeping copy-on-write pages read-only
+	fn protect_pages(&mut self, area: &VmArea) -> Result<(), MapErr> {
+		let end = area.range.end.as_usize();
+		
+		let mut addr = area.range.start.as_usize();
+		while let Some((virt_addr, translation)) = self.page_table.next_mapping(addr, end) {
+			addr = virt_addr + BASE_PAGE_SIZE;
+			
+			let mut flags = area.prot.pte_flags();
+			let desc = frame_desc(translation.phys_addr >> BASE_PAGE_ADDR_BITS);
+			if let (Backing::Anonymous, Some(desc)) = (&area.backing, desc) {
+				if is_shared_cow(desc) {
+					flags = flags.without(PteFlags::WRITABLE);
+				}
+			}
+			
+			self.page_table.protect(virt_addr, BASE_PAGE_SIZE, flags)?;
+		}
+		Ok(())
+	}
+	
+	/// Makes the copy-on-write page of the anonymous area writable,
+	/// copying its frame if other address spaces still use it
+	fn resolve_cow(&mut self, page: VirtAddr, area: &VmArea, from_user: bool) -> Result<(), FaultErr> {
+		let translation = self.page_table.translate(page.as_usize())
+			.ok_or(FaultErr::ProtectionViolation)?;
+		let flags = area.prot.pte_flags();
+		
+		if translation.flags.contains(PteFlags::WRITABLE) {
+			// Already resolved, a user write came from a stale tlb entry. The kernel's
+			// user copies fail instead, so a fault that is not about the pte can't loop.
+			return if from_user { Ok(()) } else { Err(FaultErr::ProtectionViolation) };
+		}
+		
+		let old_desc = frame_desc(translation.phys_addr >> BASE_PAGE_ADDR_BITS)
+			.ok_or(FaultErr::ProtectionViolation)?;
+		if !old_desc.flags().contains(FrameFlags::COPY_ON_WRITE) {
+			return Err(FaultErr::ProtectionViolation);
+		}
+		
+		if !is_shared_cow(old_desc) {
+			// Everybody else already copied the frame or is gone, so just take it over
+			self.page_table.protect(page.as_usize(), BASE_PAGE_SIZE, flags).map_err(map_fault_err)?;
+			return Ok(());
+		}
+		
+		let block = alloc_frames(0).ok_or(FaultErr::OutOfMemory)?;
+		let new_desc = frame_desc_of(&block);
+		new_desc.set_owner(FrameOwner::User);
+		unsafe {
+			ptr::copy_nonoverlapping(phys_to_virt(translation.phys_addr) as *const u8, block.ptr().to_virt().as_ptr() as *mut u8, BASE_PAGE_SIZE);
+		}
+		
+		self.page_table.remap(page.as_usize(), block.ptr().addr().as_usize(), flags)
+			.expect("Failed to remap copy-on-write page");
+		new_desc.inc_map_count();
+		
+		old_desc.dec_map_count();
+		if old_desc.put() {
+			// The other users dropped the frame in the meantime
+			unsafe {
+				free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(translation.phys_addr as *mut BasePage)), 0));
+			}
+		}
+		Ok(())
+	}
+	
+	/// Unmaps all mapped pages of the area in the range and releases their frames
+	fn unmap_pages(&mut self, area: &VmArea, range: VirtRange) {
+		let end = range.end.as_usize();
+		let mut addr = range.start.as_usize();
+		while let Some((virt_addr, translation)) = self.page_table.next_mapping(addr, end) {
+			addr = virt_addr + BASE_PAGE_SIZE;
+			
+			let phys_addr = translation.phys_addr;
+			self.page_table.unmap(virt_addr, BASE_PAGE_SIZE)
+				.expect("Failed to unmap user page");
+			
+			let desc = match frame_desc(phys_addr >> BASE_PAGE_ADDR_BITS) {
+				Some(desc) => desc,
+				None => continue,
+			};
+			match area.backing {
+				Backing::Anonymous => {
+					desc.dec_map_count();
+					if desc.put() {
+						unsafe {
+							free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(phys_addr as *mut BasePage)), 0));
+						}
+					}
+				},
+				// The shared object owns its frames
+				Backing::Shared {..} => {
+					desc.dec_map_count();
+				},
+				Backing::Physical(_) => {},
+			}
+		}
+	}
+}
+
+/// Whether the frame is copy-on-write and still referenced by other address spaces.
+/// Clears the copy-on-write flag of frames that are not shared anymore.
+fn is_shared_cow(desc: &FrameDesc) -> bool {
+	if !desc.flags().contains(FrameFlags::COPY_ON_WRITE) {
+		return false;
+	}
+	
+	if desc.refcount() > 1 {
+		true
+	} else {
+		desc.clear_flags(FrameFlags::COPY_ON_WRITE);
+		false
+	}
+}
+
+#[inline]
+fn areas_layout() -> Layout {
+	Layout::from_size_align(MAX_VM_AREAS * size_of::<VmArea>(), BASE_PAGE_SIZE).unwrap()
+}
+
+fn check_user_range(range: VirtRange) -> Result<(), MemMapErr> {
+	let user_range = user_range();
+	
+	if range.is_empty()
+		|| !range.start.is_aligned(BASE_PAGE_SIZE) || !range.end.is_aligned(BASE_PAGE_SIZE)
+		|| range.start < user_range.start || range.end > user_range.end {
+		return Err(MemMapErr::InvalidRange);
+	}
+	Ok(())
+}
+
+#[inline]
+fn map_fault_err(err: MapErr) -> FaultErr {
+	match err {
+		MapErr::OutOfMemory => FaultErr::OutOfMemory,
+		_ => unreachable!("Failed to map user page: {:?}", err),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	const TEST_BASE: usize = 0x80_0000_0000;
+	
+	/// Area list backed by the host heap
+	struct TestAreas {
+		areas: VmAreas,
+		_buf: Vec<VmArea>,
+	}
+	
+	impl TestAreas {
+		fn new() -> Self {
+			let mut buf = Vec::with_capacity(MAX_VM_AREAS);
+			Self {
+				areas: VmAreas {ptr: NonNull::new(buf.as_mut_ptr()).unwrap(), count: 0},
+				_buf: buf,
+			}
+		}
+		
+		/// Start and end pages (relative to `TEST_BASE`) and rights of all areas
+		fn layout(&self) -> Vec<(usize, usize, VmProt)> {
+			self.areas.as_slice().iter()
+				.map(|area| (page_of(area.range.start), page_of(area.range.end), area.prot))
+				.collect()
+		}
+	}
+	
+	impl Drop for TestAreas {
+		fn drop(&mut self) {
+			while self.areas.count > 0 {
+				self.areas.remove(self.areas.count - 1);
+			}
+		}
+	}
+	
+	fn pages(start_page: usize, end_page: usize) -> VirtRange {
+		VirtRange::new(VirtAddr::new(TEST_BASE + start_page * BASE_PAGE_SIZE), VirtAddr::new(TEST_BASE + end_page * BASE_PAGE_SIZE))
+	}
+	
+	fn page_of(addr: VirtAddr) -> usize {
+		(addr.as_usize() - TEST_BASE) / BASE_PAGE_SIZE
+	}
+	
+	fn anon(start_page: usize, end_page: usize, prot: VmProt) -> VmArea {
+		VmArea {range: pages(start_page, end_page), prot, backing: Backing::Anonymous}
+	}
+	
+	const RW: VmProt = VmProt(VmProt::READ.0 | VmProt::WRITE.0);
+	
+	#[test]
+	fn insert_keeps_areas_sorted() {
+		let mut test = TestAreas::new();
+		
+		test.areas.insert_sorted(anon(10, 12, VmProt::READ)).unwrap();
+		test.areas.insert_sorted(anon(0, 2, VmProt::READ)).unwrap();
+		test.areas.insert_sorted(anon(4, 6, VmProt::READ)).unwrap();
+		
+		assert_eq!(test.layout(), [(0, 2, VmProt::READ), (4, 6, VmProt::READ), (10, 12, VmProt::READ)]);
+	}
+	
+	#[test]
+	fn insert_rejects_overlap() {
+		let mut test = TestAreas::new();
+		test.areas.insert_sorted(anon(4, 8, VmProt::READ)).unwrap();
+		
+		for &(start_page, end_page) in [(4, 8), (2, 5), (7, 9), (5, 6), (0, 12)].iter() {
+			assert_eq!(test.areas.insert_sorted(anon(start_page, end_page, VmProt::READ)), Err(MemMapErr::Overlap));
+		}
+		
+		// Touching is fine
+		test.areas.insert_sorted(anon(2, 4, VmProt::READ)).unwrap();
+		test.areas.insert_sorted(anon(8, 9, VmProt::READ)).unwrap();
+		assert_eq!(test.areas.count, 3);
+	}
+	
+	#[test]
+	fn insert_limit() {
+		let mut test = TestAreas::new();
+		for idx in 0..MAX_VM_AREAS {
+			test.areas.insert_sorted(anon(3 * idx, 3 * idx + 2, VmProt::READ)).unwrap();
+		}
+		
+		assert_eq!(test.areas.insert_sorted(anon(3 * MAX_VM_AREAS, 3 * MAX_VM_AREAS + 2, VmProt::READ)), Err(MemMapErr::TooManyAreas));
+		// Splitting needs a free slot as well
+		assert_eq!(test.areas.split(pages(1, 2).start), Err(MemMapErr::TooManyAreas));
+		assert_eq!(test.areas.count, MAX_VM_AREAS);
+	}
+	
+	#[test]
+	fn merge_adjacent_areas() {
+		let mut test = TestAreas::new();
+		test.areas.insert_sorted(anon(0, 2, RW)).unwrap();
+		test.areas.insert_sorted(anon(4, 6, RW)).unwrap();
+		test.areas.insert_sorted(anon(2, 4, RW)).unwrap();
+		// Different rights, a gap and different backings are kept apart
+		test.areas.insert_sorted(anon(6, 7, VmProt::READ)).unwrap();
+		test.areas.insert_sorted(anon(8, 9, VmProt::READ)).unwrap();
+		test.areas.insert_sorted(VmArea {range: pages(9, 10), prot: VmProt::READ, backing: Backing::Physical(PhysAddr::new(0x1000))}).unwrap();
+		
+		test.areas.merge();
+		assert_eq!(test.layout(), [(0, 6, RW), (6, 7, VmProt::READ), (8, 9, VmProt::READ), (9, 10, VmProt::READ)]);
+	}
+	
+	#[test]
+	fn merge_physical_areas_only_if_continuous() {
+		let mut test = TestAreas::new();
+		let physical = |start_page: usize, end_page: usize, phys_addr: usize| VmArea {
+			range: pages(start_page, end_page),
+			prot: RW,
+			backing: Backing::Physical(PhysAddr::new(phys_addr)),
+		};
+		test.areas.insert_sorted(physical(0, 2, 0x10_0000)).unwrap();
+		test.areas.insert_sorted(physical(2, 3, 0x10_2000)).unwrap();
+		test.areas.insert_sorted(physical(3, 4, 0x20_0000)).unwrap();
+		
+		test.areas.merge();
+		assert_eq!(test.layout(), [(0, 3, RW), (3, 4, RW)]);
+	}
+	
+	#[test]
+	fn split_partially_covered_areas() {
+		let mut test = TestAreas::new();
+		test.areas.insert_sorted(anon(0, 8, RW)).unwrap();
+		
+		// Splitting at an area boundary or outside of any area does nothing
+		test.areas.split(pages(0, 8).start).unwrap();
+		test.areas.split(pages(0, 8).end).unwrap();
+		test.areas.split(pages(10, 11).start).unwrap();
+		assert_eq!(test.layout(), [(0, 8, RW)]);
+		
+		// Unmapping pages 2..5 splits off both ends
+		let range = pages(2, 5);
+		test.areas.split(range.start).unwrap();
+		test.areas.split(range.end).unwrap();
+		assert_eq!(test.layout(), [(0, 2, RW), (2, 5, RW), (5, 8, RW)]);
+		
+		while let Some(idx) = test.areas.as_slice().iter().position(|area| range.contains(area.range.start)) {
+			test.areas.remove(idx);
+		}
+		assert_eq!(test.layout(), [(0, 2, RW), (5, 8, RW)]);
+		
+		// Filling the hole again merges everything back together
+		test.areas.insert_sorted(anon(2, 5, RW)).unwrap();
+		test.areas.merge();
+		assert_eq!(test.layout(), [(0, 8, RW)]);
+	}
+	
+	#[test]
+	fn split_advances_backing() {
+		let mut test = TestAreas::new();
+		test.areas.insert_sorted(VmArea {range: pages(0, 4), prot: RW, backing: Backing::Physical(PhysAddr::new(0x10_0000))}).unwrap();
+		
+		test.areas.split(pages(3, 4).start).unwrap();
+		match test.areas.as_slice()[1].backing {
+			Backing::Physical(base) => assert_eq!(base, PhysAddr::new(0x10_3000)),
+			_ => panic!("Split changed the backing"),
+		}
+	}
 	
+	#[test]
+	fn find_free_ranges() {
+		let mut test = TestAreas::new();
+		let within = pages(0, 16);
+		let size = |page_count: usize| page_count * BASE_PAGE_SIZE;
+		
+		assert_eq!(test.areas.find_free(within, size(16)), Some(pages(0, 16)));
+		assert_eq!(test.areas.find_free(within, size(17)), None);
+		
+		test.areas.insert_sorted(anon(0, 2, RW)).unwrap();
+		test.areas.insert_sorted(anon(4, 5, RW)).unwrap();
+		test.areas.insert_sorted(anon(8, 14, RW)).unwrap();
+		
+		// The lowest gap that is big enough wins
+		assert_eq!(test.areas.find_free(within, size(1)), Some(pages(2, 3)));
+		assert_eq!(test.areas.find_free(within, size(2)), Some(pages(2, 4)));
+		assert_eq!(test.areas.find_free(within, size(3)), Some(pages(5, 8)));
+		assert_eq!(test.areas.find_free(within, size(4)), None);
+		
+		// Areas outside of the searched range don't matter
+		assert_eq!(test.areas.find_free(pages(14, 20), size(6)), Some(pages(14, 20)));
+		assert_eq!(test.areas.find_free(pages(1, 10), size(3)), Some(pages(5, 8)));
+	}
 }
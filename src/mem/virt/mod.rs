@@ -1,5 +1,15 @@
+pub use fault::*;
+pub use ioremap::*;
 pub use mem_map::*;
+pub use page_table::*;
+pub use shared_obj::*;
+pub use user_access::*;
+pub use vmalloc::*;
 
+mod fault;
+mod ioremap;
 mod mem_map;
-
-
+mod page_table;
+mod shared_obj;
+mod user_access;
+mod vmalloc;
@@ -1,4 +1,681 @@
+//! x86-64 four- and five-level page tables
+//! 
+//! Levels are numbered from the leaves up: level 0 is the page table (4 KiB pages),
+//! level 1 the page directory (2 MiB pages), level 2 the page directory pointer table
+//! (1 GiB pages), level 3 the PML4 and level 4 the PML5 (with LA57 only).
+//! 
+//! Whether five-level paging is used is decided once at boot by [`init_paging_mode`],
+//! all page tables created afterwards use that many levels.
+//! 
+//! Intermediate tables are allocated from the physical frame allocator on demand and
+//! freed again as soon as they become empty on unmap. Intermediate entries are always
+//! present, writable and user accessible, the actual permissions are in the leaves.
+//! 
+//! User page tables share the tables below the root with the kernel's page table
+//! (see [`PageTable::new_user`]), so tables directly below a root are never freed on
+//! unmap, only when a user page table is dropped.
+//! 
+//! Tables are accessed through [`phys_to_virt`], so page tables can be built
+//! both before and after the switch to the kernel's own address space.
 
+use core::arch::asm;
+use core::ops::BitOr;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::*;
+
+use crate::arch::x86_64::cpuid::{has_1gib_pages, has_la57};
+use crate::arch::x86_64::paging::{is_la57_active, read_cr3, switch_paging_mode, write_cr3};
+use crate::mem::Phys;
+use crate::mem::kaslr::image_virt_to_phys;
+use crate::mem::kernel_mem_map::phys_to_virt;
+use crate::mem::phys::{alloc_frames_in, frame_desc_of, free_frames, FrameOwner};
+use crate::mem::phys::buck::{BASE_PAGE_ADDR_BITS, BASE_PAGE_SIZE, BasePage, BuckBlock};
+
+/// Number of entries in a table of any level
+pub const PAGE_TABLE_ENTRIES: usize = 512;
+/// Number of virtual address bits translated by each level
+const LEVEL_ADDR_BITS: usize = 9;
+/// Highest possible level of a root table (the PML5)
+const MAX_ROOT_LEVEL: usize = 4;
+
+/// Number of paging levels used by the kernel, see [`init_paging_mode`]
+static PAGING_LEVELS: AtomicUsize = AtomicUsize::new(4);
+
+/// Physical address bits of an entry
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Page size bit of huge page entries
+const PTE_HUGE: u64 = 0x1 << 7;
+/// PAT bit of huge page entries
+const PTE_HUGE_PAT: u64 = 0x1 << 12;
+
+/// Entry flags of a mapping
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(transparent)]
+pub struct PteFlags(pub u64);
+
+impl PteFlags {
+	pub const EMPTY: Self = Self(0);
+	pub const PRESENT: Self = Self(0x1 << 0);
+	pub const WRITABLE: Self = Self(0x1 << 1);
+	pub const USER: Self = Self(0x1 << 2);
+	/// PWT, selects the PAT entry together with [`Self::CACHE_DISABLE`] and [`Self::PAT`]
+	pub const WRITE_THROUGH: Self = Self(0x1 << 3);
+	/// PCD, selects the PAT entry together with [`Self::WRITE_THROUGH`] and [`Self::PAT`]
+	pub const CACHE_DISABLE: Self = Self(0x1 << 4);
+	pub const ACCESSED: Self = Self(0x1 << 5);
+	pub const DIRTY: Self = Self(0x1 << 6);
+	/// Selects the upper half of the PAT.
+	/// Note: This is always given at its 4 KiB page position (bit 7),
+	/// it is moved to bit 12 for huge pages automatically.
+	pub const PAT: Self = Self(0x1 << 7);
+	pub const GLOBAL: Self = Self(0x1 << 8);
+	pub const NO_EXECUTE: Self = Self(0x1 << 63);
+	
+	/// All flags that can be part of a mapping
+	const ALL: Self = Self(0x1ff | (0x1 << 63));
+	
+	#[inline]
+	pub const fn contains(self, other: Self) -> bool {
+		(self.0 & other.0) == other.0
+	}
+	
+	#[inline]
+	pub const fn without(self, other: Self) -> Self {
+		Self(self.0 & !other.0)
+	}
+	
+	/// The raw entry bits of these flags for a leaf entry of the level
+	#[inline]
+	const fn to_leaf_bits(self, level: usize) -> u64 {
+		let bits = (self.0 & Self::ALL.0) | Self::PRESENT.0;
+		
+		if level == 0 {
+			bits
+		} else if (bits & Self::PAT.0) != 0 {
+			(bits & !Self::PAT.0) | PTE_HUGE_PAT | PTE_HUGE
+		} else {
+			bits | PTE_HUGE
+		}
+	}
+	
+	/// The flags of a raw leaf entry of the level
+	#[inline]
+	const fn from_leaf_bits(bits: u64, level: usize) -> Self {
+		if level == 0 {
+			Self(bits & Self::ALL.0)
+		} else if (bits & PTE_HUGE_PAT) != 0 {
+			Self((bits & Self::ALL.0 & !PTE_HUGE) | Self::PAT.0)
+		} else {
+			Self(bits & Self::ALL.0 & !PTE_HUGE)
+		}
+	}
+}
+
+impl BitOr for PteFlags {
+	type Output = Self;
+	
+	#[inline]
+	fn bitor(self, rhs: Self) -> Self {
+		Self(self.0 | rhs.0)
+	}
+}
+
+/// Memory type of a mapping, see [`crate::arch::x86_64::paging::init_pat`]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CacheMode {
+	WriteBack,
+	WriteThrough,
+	/// Strong uncacheable, for device registers
+	Uncached,
+	/// Uncached but with combined writes, for framebuffers
+	WriteCombining,
+}
+
+impl CacheMode {
+	/// The flags selecting the PAT entry of the memory type
+	#[inline]
+	pub const fn pte_flags(self) -> PteFlags {
+		match self {
+			Self::WriteBack => PteFlags::EMPTY,
+			Self::WriteThrough => PteFlags::WRITE_THROUGH,
+			Self::Uncached => PteFlags(PteFlags::WRITE_THROUGH.0 | PteFlags::CACHE_DISABLE.0),
+			Self::WriteCombining => PteFlags::PAT,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PageSize {
+	Size4KiB,
+	Size2MiB,
+	Size1GiB,
+}
+
+impl PageSize {
+	#[inline]
+	pub const fn size(self) -> usize {
+		level_size(self.level())
+	}
+	
+	/// The table level whose entries map pages of this size
+	#[inline]
+	const fn level(self) -> usize {
+		match self {
+			Self::Size4KiB => 0,
+			Self::Size2MiB => 1,
+			Self::Size1GiB => 2,
+		}
+	}
+	
+	#[inline]
+	const fn of_level(level: usize) -> Self {
+		match level {
+			0 => Self::Size4KiB,
+			1 => Self::Size2MiB,
+			_ => Self::Size1GiB,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MapErr {
+	/// An address or the size is not aligned to 4 KiB (or is zero)
+	Misaligned,
+	/// The range is not canonical or crosses the canonical hole
+	NonCanonical,
+	/// Part of the range is already mapped
+	AlreadyMapped,
+	/// No physical memory left for an intermediate table
+	OutOfMemory,
+	/// The page is not mapped (with the expected page size)
+	NotMapped,
+}
+
+/// The result of [`PageTable::translate`]
+#[derive(Copy, Clone, Debug)]
+pub struct Translation {
+	/// Physical address the virtual address translates to
+	pub phys_addr: usize,
+	/// Size of the page containing the address
+	pub page_size: PageSize,
+	pub flags: PteFlags,
+}
+
+/// A single table of any level
+#[repr(C, align(4096))]
+struct TableFrame([u64; PAGE_TABLE_ENTRIES]);
+
+/// Decides between four- and five-level paging. Five-level paging is used
+/// whenever the cpu supports it, unless `force_4_level` is set.
+/// Must be called once on the bootstrap processor before any page table is created.
+pub fn init_paging_mode(force_4_level: bool) {
+	let levels = if has_la57() && !force_4_level { 5 } else { 4 };
+	PAGING_LEVELS.store(levels, SeqCst);
+}
+
+/// Number of paging levels used by the kernel (4 or 5)
+#[inline]
+pub fn paging_levels() -> usize {
+	PAGING_LEVELS.load(Relaxed)
+}
+
+/// Size of the memory covered by a single root table entry (512 GiB or 256 TiB)
+#[inline]
+pub fn root_entry_size() -> usize {
+	level_size(paging_levels() - 1)
+}
+
+/// Number of significant virtual address bits (48 or 57)
+#[inline]
+pub fn virt_addr_bits() -> usize {
+	BASE_PAGE_ADDR_BITS + paging_levels() * LEVEL_ADDR_BITS
+}
+
+/// An x86-64 page table hierarchy rooted at a PML4 or PML5
 pub struct PageTable {
+	root: Phys<NonNull<TableFrame>>,
+	/// Level of the root table, 3 for a PML4 and 4 for a PML5
+	root_level: usize,
+	/// Physical address of the kernel's root table for a user page table, see [`Self::new_user`]
+	kernel_root_addr: Option<usize>,
+}
+
+impl PageTable {
+	/// Creates a page table without any mappings,
+	/// using the paging mode decided by [`init_paging_mode`]
+	pub fn new() -> Result<Self, MapErr> {
+		Ok(Self {
+			// Note: The root must be reachable from compat mode for switch_paging_mode
+			root: alloc_table(0xffff_ffff)?,
+			root_level: paging_levels() - 1,
+			kernel_root_addr: None,
+		})
+	}
+	
+	/// Creates a page table for a user address space that shares the upper half
+	/// and the first root entry (the identity mapped boot stack) of `kernel` by
+	/// pointing its root entries to the same tables. The rest of the lower half
+	/// is left empty for the user.
+	/// 
+	/// Only mappings in tables that already exist below the root of `kernel` are
+	/// shared, see [`Self::populate_upper_half`]. `kernel` must outlive the
+	/// returned page table, which frees only its own tables on drop.
+	pub fn new_user(kernel: &PageTable) -> Result<Self, MapErr> {
+		let mut table = Self::new()?;
+		table.kernel_root_addr = Some(kernel.root_addr());
+		
+		unsafe {
+			let root = table_ptr(table.root_addr());
+			let kernel_root = table_ptr(kernel.root_addr());
+			(*root).0[0] = (*kernel_root).0[0];
+			(*root).0[(PAGE_TABLE_ENTRIES / 2)..].copy_from_slice(&(*kernel_root).0[(PAGE_TABLE_ENTRIES / 2)..]);
+		}
+		Ok(table)
+	}
+	
+	/// Whether the root entry at `idx` points to a table or page
+	#[inline]
+	pub fn is_root_entry_present(&self, idx: usize) -> bool {
+		unsafe {
+			((*table_ptr(self.root_addr())).0[idx] & PteFlags::PRESENT.0) != 0
+		}
+	}
 	
+	/// Allocates the tables of all empty root entries of the upper half, so
+	/// every later kernel mapping shows up in all user page tables as well
+	pub fn populate_upper_half(&mut self) -> Result<(), MapErr> {
+		let root = table_ptr(self.root_addr());
+		
+		for idx in (PAGE_TABLE_ENTRIES / 2)..PAGE_TABLE_ENTRIES {
+			unsafe {
+				if ((*root).0[idx] & PteFlags::PRESENT.0) == 0 {
+					let new_table = alloc_table(usize::MAX)?;
+					(*root).0[idx] = (new_table.ptr().as_ptr() as u64) | (PteFlags::PRESENT | PteFlags::WRITABLE | PteFlags::USER).0;
+				}
+			}
+		}
+		Ok(())
+	}
+	
+	/// Physical address of the root table, as loaded into cr3
+	#[inline]
+	pub fn root_addr(&self) -> usize {
+		self.root.ptr().as_ptr() as usize
+	}
+	
+	/// Whether this is the page table currently loaded on this cpu
+	#[inline]
+	pub fn is_active(&self) -> bool {
+		(read_cr3() & (PTE_ADDR_MASK as usize)) == self.root_addr()
+	}
+	
+	/// Loads this page table into cr3, switching between four- and
+	/// five-level paging if needed (see [`switch_paging_mode`] for the
+	/// requirements in that case)
+	pub unsafe fn activate(&self) {
+		let la57 = (self.root_level == MAX_ROOT_LEVEL);
+		
+		if la57 == is_la57_active() {
+			write_cr3(self.root_addr());
+		} else {
+			// It has to run identity mapped
+			let switch_paging_mode: unsafe extern "sysv64" fn(u64, u64) =
+				core::mem::transmute(image_virt_to_phys(switch_paging_mode as usize));
+			switch_paging_mode(self.root_addr() as u64, la57 as u64);
+		}
+	}
+	
+	/// Maps `size` bytes at `virt_addr` to `phys_addr`, using the biggest pages
+	/// the alignment of both addresses allows.
+	/// 
+	/// Nothing in the range must be mapped yet. On error nothing is mapped.
+	pub fn map(&mut self, virt_addr: usize, phys_addr: usize, size: usize, flags: PteFlags) -> Result<(), MapErr> {
+		check_range(virt_addr, size)?;
+		if (phys_addr & (BASE_PAGE_SIZE - 1)) != 0 {
+			return Err(MapErr::Misaligned);
+		}
+		
+		let mut offset = 0;
+		while offset < size {
+			let page_size = biggest_page_size(virt_addr + offset, phys_addr + offset, size - offset);
+			
+			if let Err(err) = unsafe { self.map_page(virt_addr + offset, phys_addr + offset, page_size, flags) } {
+				// Roll back what has been mapped so far
+				self.unmap(virt_addr, offset).ok();
+				return Err(err);
+			}
+			
+			offset += page_size.size();
+		}
+		Ok(())
+	}
+	
+	/// Removes all mappings in the range (unmapped holes are skipped),
+	/// splitting huge pages that are only partially covered.
+	/// Tables that become empty are freed.
+	pub fn unmap(&mut self, virt_addr: usize, size: usize) -> Result<(), MapErr> {
+		check_range(virt_addr, size)?;
+		let is_active = self.is_active();
+		
+		let mut offset = 0;
+		while offset < size {
+			let addr = virt_addr + offset;
+			let (level, entry) = unsafe { self.find_leaf(addr) };
+			let level_size = level_size(level);
+			
+			unsafe {
+				if (*entry & PteFlags::PRESENT.0) == 0 {
+					// Nothing mapped up to the end of the entry
+					offset += level_size - (addr & (level_size - 1));
+				} else if (addr & (level_size - 1)) != 0 || size - offset < level_size {
+					// Only part of a huge page is unmapped
+					split_huge(entry, level)?;
+				} else {
+					*entry = 0;
+					self.free_empty_tables(addr, level);
+					
+					if is_active {
+						flush_page(addr);
+					}
+					offset += level_size;
+				}
+			}
+		}
+		Ok(())
+	}
+	
+	/// Changes the flags of all mappings in the range (unmapped holes are skipped),
+	/// splitting huge pages that are only partially covered
+	pub fn protect(&mut self, virt_addr: usize, size: usize, flags: PteFlags) -> Result<(), MapErr> {
+		check_range(virt_addr, size)?;
+		let is_active = self.is_active();
+		
+		let mut offset = 0;
+		while offset < size {
+			let addr = virt_addr + offset;
+			let (level, entry) = unsafe { self.find_leaf(addr) };
+			let level_size = level_size(level);
+			
+			unsafe {
+				if (*entry & PteFlags::PRESENT.0) == 0 {
+					offset += level_size - (addr & (level_size - 1));
+				} else if (addr & (level_size - 1)) != 0 || size - offset < level_size {
+					split_huge(entry, level)?;
+				} else {
+					*entry = (*entry & PTE_ADDR_MASK & !(level_size as u64 - 1)) | flags.to_leaf_bits(level);
+					
+					if is_active {
+						flush_page(addr);
+					}
+					offset += level_size;
+				}
+			}
+		}
+		Ok(())
+	}
+	
+	/// Points a mapped 4 KiB page to another frame and changes its flags
+	pub fn remap(&mut self, virt_addr: usize, phys_addr: usize, flags: PteFlags) -> Result<(), MapErr> {
+		check_range(virt_addr, BASE_PAGE_SIZE)?;
+		if (phys_addr & (BASE_PAGE_SIZE - 1)) != 0 {
+			return Err(MapErr::Misaligned);
+		}
+		
+		unsafe {
+			let (level, entry) = self.find_leaf(virt_addr);
+			if level != 0 || (*entry & PteFlags::PRESENT.0) == 0 {
+				return Err(MapErr::NotMapped);
+			}
+			
+			*entry = (phys_addr as u64) | flags.to_leaf_bits(0);
+			if self.is_active() {
+				flush_page(virt_addr);
+			}
+		}
+		Ok(())
+	}
+	
+	/// Looks up the mapping of a virtual address
+	pub fn translate(&self, virt_addr: usize) -> Option<Translation> {
+		if !is_canonical(virt_addr) {
+			return None;
+		}
+		
+		let (level, entry) = unsafe { self.find_leaf(virt_addr) };
+		let entry = unsafe { *entry };
+		if (entry & PteFlags::PRESENT.0) == 0 {
+			return None;
+		}
+		
+		let level_size = level_size(level);
+		Some(Translation {
+			phys_addr: ((entry & PTE_ADDR_MASK & !(level_size as u64 - 1)) as usize) + (virt_addr & (level_size - 1)),
+			page_size: PageSize::of_level(level),
+			flags: PteFlags::from_leaf_bits(entry, level),
+		})
+	}
+	
+	/// Finds the first mapped address in `virt_addr..end`, skipping unmapped
+	/// ranges a whole table at a time. Both ends must be in the same half.
+	pub fn next_mapping(&self, virt_addr: usize, end: usize) -> Option<(usize, Translation)> {
+		let mut addr = virt_addr;
+		while addr < end {
+			let (level, entry) = unsafe { self.find_leaf(addr) };
+			if unsafe { *entry & PteFlags::PRESENT.0 } != 0 {
+				return Some((addr, self.translate(addr)?));
+			}
+			
+			let level_size = level_size(level);
+			addr = (addr & !(level_size - 1)).checked_add(level_size)?;
+		}
+		None
+	}
+	
+	unsafe fn map_page(&mut self, virt_addr: usize, phys_addr: usize, page_size: PageSize, flags: PteFlags) -> Result<(), MapErr> {
+		let target_level = page_size.level();
+		let mut table = table_ptr(self.root_addr());
+		// The entry pointing to the first table created here and the level of that table
+		let mut first_new: Option<(*mut u64, usize)> = None;
+		
+		for level in ((target_level + 1)..=self.root_level).rev() {
+			let entry = &mut (*table).0[table_idx(virt_addr, level)];
+			
+			if (*entry & PteFlags::PRESENT.0) == 0 {
+				let new_table = match alloc_table(usize::MAX) {
+					Ok(new_table) => new_table,
+					Err(err) => {
+						// The tables created so far only lead to this address, so none of them is used yet
+						if let Some((first_entry, first_level)) = first_new {
+							free_table_tree((*first_entry & PTE_ADDR_MASK) as usize, first_level);
+							*first_entry = 0;
+						}
+						return Err(err);
+					},
+				};
+				*entry = (new_table.ptr().as_ptr() as u64) | (PteFlags::PRESENT | PteFlags::WRITABLE | PteFlags::USER).0;
+				first_new.get_or_insert((entry as *mut u64, level - 1));
+			} else if (*entry & PTE_HUGE) != 0 {
+				return Err(MapErr::AlreadyMapped);
+			}
+			
+			table = table_ptr((*entry & PTE_ADDR_MASK) as usize);
+		}
+		
+		let entry = &mut (*table).0[table_idx(virt_addr, target_level)];
+		if (*entry & PteFlags::PRESENT.0) != 0 {
+			return Err(MapErr::AlreadyMapped);
+		}
+		*entry = (phys_addr as u64) | flags.to_leaf_bits(target_level);
+		
+		Ok(())
+	}
+	
+	/// Walks down to the entry actually deciding about the address,
+	/// which is either a leaf or a non-present entry
+	unsafe fn find_leaf(&self, virt_addr: usize) -> (usize, *mut u64) {
+		let mut table = table_ptr(self.root_addr());
+		let mut level = self.root_level;
+		
+		loop {
+			let entry = &mut (*table).0[table_idx(virt_addr, level)] as *mut u64;
+			
+			if level == 0 || (*entry & PteFlags::PRESENT.0) == 0 || (*entry & PTE_HUGE) != 0 {
+				return (level, entry);
+			}
+			
+			table = table_ptr((*entry & PTE_ADDR_MASK) as usize);
+			level -= 1;
+		}
+	}
+	
+	/// Frees the tables on the path to the address, starting with the
+	/// one containing the just cleared entry of the level, until a table
+	/// that still has entries or a table directly below the root is hit
+	unsafe fn free_empty_tables(&mut self, virt_addr: usize, cleared_level: usize) {
+		// Tables on the path, indexed by level
+		let mut path = [self.root_addr(); MAX_ROOT_LEVEL + 1];
+		for level in ((cleared_level + 1)..=self.root_level).rev() {
+			let entry = (*table_ptr(path[level])).0[table_idx(virt_addr, level)];
+			path[level - 1] = (entry & PTE_ADDR_MASK) as usize;
+		}
+		
+		// Tables directly below the root may be shared with other page tables
+		for level in cleared_level..(self.root_level - 1) {
+			if (*table_ptr(path[level])).0.iter().any(|&entry| entry != 0) {
+				break;
+			}
+			
+			(*table_ptr(path[level + 1])).0[table_idx(virt_addr, level + 1)] = 0;
+			free_table(path[level]);
+		}
+	}
+}
+
+/// Frees all tables including the root, except the ones shared with the
+/// kernel's page table for a user page table.
+/// The frames mapped by the leaves are left alone.
+impl Drop for PageTable {
+	fn drop(&mut self) {
+		debug_assert!(!self.is_active(), "Dropped the active page table");
+		
+		unsafe {
+			let root = table_ptr(self.root_addr());
+			for idx in 0..PAGE_TABLE_ENTRIES {
+				let entry = (*root).0[idx];
+				let is_shared = self.kernel_root_addr
+					.map_or(false, |kernel_root_addr| entry == (*table_ptr(kernel_root_addr)).0[idx]);
+				
+				if (entry & PteFlags::PRESENT.0) != 0 && (entry & PTE_HUGE) == 0 && !is_shared {
+					free_table_tree((entry & PTE_ADDR_MASK) as usize, self.root_level - 1);
+				}
+			}
+			free_table(self.root_addr());
+		}
+	}
+}
+
+/// Replaces a huge page entry by a table of the next lower level with the same mappings
+unsafe fn split_huge(entry: *mut u64, level: usize) -> Result<(), MapErr> {
+	debug_assert!(level > 0 && (*entry & PTE_HUGE) != 0, "Only huge pages can be split");
+	
+	let flags = PteFlags::from_leaf_bits(*entry, level);
+	let phys_addr = (*entry & PTE_ADDR_MASK & !(level_size(level) as u64 - 1)) as usize;
+	
+	let new_table = alloc_table(usize::MAX)?;
+	let table = table_ptr(new_table.ptr().as_ptr() as usize);
+	for idx in 0..PAGE_TABLE_ENTRIES {
+		(*table).0[idx] = ((phys_addr + idx * level_size(level - 1)) as u64) | flags.to_leaf_bits(level - 1);
+	}
+	
+	// Note: No flush needed as the translations stay the same
+	*entry = (new_table.ptr().as_ptr() as u64) | (PteFlags::PRESENT | PteFlags::WRITABLE | PteFlags::USER).0;
+	Ok(())
+}
+
+/// Allocates a zeroed table at or below `max_phys_addr` from the frame allocator
+fn alloc_table(max_phys_addr: usize) -> Result<Phys<NonNull<TableFrame>>, MapErr> {
+	let block = alloc_frames_in(0, max_phys_addr).ok_or(MapErr::OutOfMemory)?;
+	frame_desc_of(&block).set_owner(FrameOwner::PageTable);
+	
+	unsafe {
+		(*table_ptr(block.ptr().ptr().as_ptr() as usize)).0 = [0; PAGE_TABLE_ENTRIES];
+	}
+	Ok(Phys(block.ptr().ptr().cast()))
+}
+
+unsafe fn free_table(table_addr: usize) {
+	free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(table_addr as *mut BasePage)), 0));
+}
+
+/// Frees the table of the level and all tables below it
+unsafe fn free_table_tree(table_addr: usize, level: usize) {
+	if level > 0 {
+		for &entry in (*table_ptr(table_addr)).0.iter() {
+			if (entry & PteFlags::PRESENT.0) != 0 && (entry & PTE_HUGE) == 0 {
+				free_table_tree((entry & PTE_ADDR_MASK) as usize, level - 1);
+			}
+		}
+	}
+	free_table(table_addr);
+}
+
+/// The table at the physical address as seen by the kernel
+#[inline(always)]
+fn table_ptr(table_addr: usize) -> *mut TableFrame {
+	phys_to_virt(table_addr) as *mut TableFrame
+}
+
+/// Index of the entry for the address in a table of the level
+#[inline(always)]
+const fn table_idx(virt_addr: usize, level: usize) -> usize {
+	(virt_addr >> (BASE_PAGE_ADDR_BITS + level * LEVEL_ADDR_BITS)) & (PAGE_TABLE_ENTRIES - 1)
+}
+
+/// Size of the memory covered by a single entry of the level
+#[inline(always)]
+const fn level_size(level: usize) -> usize {
+	BASE_PAGE_SIZE << (level * LEVEL_ADDR_BITS)
+}
+
+/// The biggest page that can map from both addresses and fits into the size
+#[inline]
+fn biggest_page_size(virt_addr: usize, phys_addr: usize, size: usize) -> PageSize {
+	let fits = |page_size: PageSize| {
+		let mask = page_size.size() - 1;
+		(virt_addr & mask) == 0 && (phys_addr & mask) == 0 && size >= page_size.size()
+	};
+	
+	if fits(PageSize::Size1GiB) && has_1gib_pages() {
+		PageSize::Size1GiB
+	} else if fits(PageSize::Size2MiB) {
+		PageSize::Size2MiB
+	} else {
+		PageSize::Size4KiB
+	}
+}
+
+/// Whether the address is canonical for the current paging mode
+/// (48 bit virtual addresses, or 57 bit with five-level paging)
+#[inline]
+pub fn is_canonical(virt_addr: usize) -> bool {
+	let upper_bits = (virt_addr as isize) >> (virt_addr_bits() - 1);
+	upper_bits == 0 || upper_bits == -1
+}
+
+fn check_range(virt_addr: usize, size: usize) -> Result<(), MapErr> {
+	if size == 0 || ((virt_addr | size) & (BASE_PAGE_SIZE - 1)) != 0 {
+		return Err(MapErr::Misaligned);
+	}
+	
+	let last_addr = virt_addr.checked_add(size - 1).ok_or(MapErr::NonCanonical)?;
+	if !is_canonical(virt_addr) || !is_canonical(last_addr) || ((virt_addr ^ last_addr) >> 63) != 0 {
+		return Err(MapErr::NonCanonical);
+	}
+	Ok(())
+}
+
+#[inline(always)]
+unsafe fn flush_page(virt_addr: usize) {
+	asm!("invlpg [{}]", in(reg) virt_addr, options(nostack, preserves_flags));
 }
@@ -0,0 +1,161 @@
+//! Memory objects that can be mapped into several address spaces at once
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::Ordering::*;
+
+use fallo::alloc::{AllocError, FallibleAlloc};
+use fallo::stdalloc::Layout;
+
+use crate::mem::{Phys, PhysAddr};
+use crate::mem::heap::KernelHeap;
+use crate::mem::phys::{alloc_frames, frame_desc_of, free_frames, FrameFlags, FrameOwner};
+use crate::mem::phys::buck::{BASE_PAGE_SIZE, BasePage, BuckBlock};
+
+/// A refcounted set of pages, backing [`super::Backing::Shared`] areas.
+/// 
+/// Pages are allocated (zeroed) on their first use and stay around until the
+/// last handle to the object is dropped, no matter how often they are mapped.
+pub struct SharedObj {
+	inner: NonNull<SharedObjInner>,
+}
+
+struct SharedObjInner {
+	refcount: AtomicUsize,
+	locked: AtomicBool,
+	page_count: usize,
+	/// Physical addresses of the pages, 0 if not allocated yet
+	frames: UnsafeCell<NonNull<usize>>,
+}
+
+unsafe impl Send for SharedObj {}
+unsafe impl Sync for SharedObj {}
+
+impl SharedObj {
+	/// Creates an object of `size` bytes, rounded up to whole pages
+	pub fn new(size: usize) -> Result<Self, AllocError> {
+		let page_count = ((size + (BASE_PAGE_SIZE - 1)) / BASE_PAGE_SIZE).max(1);
+		
+		let frames_layout = Layout::array::<usize>(page_count).map_err(|_| AllocError)?;
+		let frames = KernelHeap.alloc(frames_layout)?.cast::<usize>();
+		unsafe {
+			ptr::write_bytes(frames.as_ptr(), 0, page_count);
+		}
+		
+		let inner = match KernelHeap.alloc(Layout::new::<SharedObjInner>()) {
+			Ok(inner) => inner.cast::<SharedObjInner>(),
+			Err(err) => {
+				unsafe {
+					KernelHeap.dealloc(frames.cast(), frames_layout);
+				}
+				return Err(err);
+			},
+		};
+		unsafe {
+			inner.as_ptr().write(SharedObjInner {
+				refcount: AtomicUsize::new(1),
+				locked: AtomicBool::new(false),
+				page_count,
+				frames: UnsafeCell::new(frames),
+			});
+		}
+		
+		Ok(Self {inner})
+	}
+	
+	#[inline]
+	pub fn page_count(&self) -> usize {
+		self.inner().page_count
+	}
+	
+	/// Whether both handles refer to the same object
+	#[inline]
+	pub fn ptr_eq(&self, other: &Self) -> bool {
+		self.inner == other.inner
+	}
+	
+	/// The frame of the page, allocating it if needed.
+	/// Returns `None` if the index is out of range or there is no memory left.
+	pub fn frame(&self, page_idx: usize) -> Option<PhysAddr> {
+		let inner = self.inner();
+		if page_idx >= inner.page_count {
+			return None;
+		}
+		
+		inner.lock();
+		let frame = unsafe {
+			let slot = (*inner.frames.get()).as_ptr().add(page_idx);
+			if *slot == 0 {
+				if let Some(block) = alloc_frames(0) {
+					let desc = frame_desc_of(&block);
+					desc.set_owner(FrameOwner::User);
+					desc.set_flags(FrameFlags::SHARED);
+					ptr::write_bytes(block.ptr().to_virt().as_ptr() as *mut u8, 0, BASE_PAGE_SIZE);
+					
+					*slot = block.ptr().addr().as_usize();
+				}
+			}
+			*slot
+		};
+		inner.unlock();
+		
+		if frame != 0 {
+			Some(PhysAddr::new(frame))
+		} else {
+			None
+		}
+	}
+	
+	#[inline]
+	fn inner(&self) -> &SharedObjInner {
+		unsafe { self.inner.as_ref() }
+	}
+}
+
+impl Clone for SharedObj {
+	fn clone(&self) -> Self {
+		self.inner().refcount.fetch_add(1, Relaxed);
+		Self {inner: self.inner}
+	}
+}
+
+impl Drop for SharedObj {
+	fn drop(&mut self) {
+		if self.inner().refcount.fetch_sub(1, Release) != 1 {
+			return;
+		}
+		core::sync::atomic::fence(Acquire);
+		
+		unsafe {
+			let page_count = self.inner().page_count;
+			let frames = *self.inner().frames.get();
+			for page_idx in 0..page_count {
+				let frame = *frames.as_ptr().add(page_idx);
+				if frame != 0 {
+					free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(frame as *mut BasePage)), 0));
+				}
+			}
+			
+			KernelHeap.dealloc(frames.cast(), Layout::array::<usize>(page_count).unwrap());
+			KernelHeap.dealloc(self.inner.cast(), Layout::new::<SharedObjInner>());
+		}
+	}
+}
+
+impl SharedObjInner {
+	#[inline]
+	fn lock(&self) {
+		while self.locked.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+			unsafe {
+				asm!("pause", options(nomem, nostack));
+			}
+		}
+	}
+	
+	#[inline]
+	fn unlock(&self) {
+		self.locked.store(false, Release);
+	}
+}
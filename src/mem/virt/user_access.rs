@@ -0,0 +1,264 @@
+//! Checked accesses to the memory of the current user address space
+//! 
+//! The kernel never dereferences user pointers itself. With SMAP enabled (see
+//! [`enable_user_access_protection`]) doing so faults, only the copy routine here
+//! is allowed to touch user pages by setting RFLAGS.AC around the copy.
+//! 
+//! A page fault in the copy that can't be resolved (e.g. an unmapped page) doesn't
+//! panic the kernel, the #PF handler resumes the copy routine at a fixup
+//! address instead (see [`user_copy_fixup`]) and the copy fails with [`UserAccessErr::Fault`].
+//! 
+//! User copies may fault and resolve faults through the current [`super::MemMap`],
+//! so they must not be done while it is borrowed.
+
+use core::arch::asm;
+use core::marker::PhantomData;
+use core::mem::{MaybeUninit, size_of};
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::*;
+
+use crate::arch::x86_64::paging::enable_smep_smap;
+use crate::mem::virt::user_range;
+
+/// Whether SMAP is enabled, otherwise `stac` and `clac` may not even exist
+pub(crate) static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum UserAccessErr {
+	/// The range is not entirely inside of [`user_range`]
+	InvalidRange,
+	/// A page of the range is not mapped or does not allow the access
+	Fault,
+}
+
+/// Types for which every bit pattern is a valid value,
+/// so they can be copied from user memory as is
+pub unsafe trait Pod: Copy {}
+
+macro_rules! impl_pod {
+	($($ty:ty),*) => {
+		$(unsafe impl Pod for $ty {})*
+	}
+}
+
+impl_pod!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A pointer to a `T` in the current user address space
+#[repr(transparent)]
+pub struct UserPtr<T: Pod> {
+	addr: usize,
+	_marker: PhantomData<*mut T>,
+}
+
+impl<T: Pod> UserPtr<T> {
+	#[inline]
+	pub fn new(addr: usize) -> Self {
+		Self {addr, _marker: PhantomData}
+	}
+	
+	#[inline]
+	pub fn addr(self) -> usize {
+		self.addr
+	}
+	
+	pub fn read(self) -> Result<T, UserAccessErr> {
+		check_user_range(self.addr, size_of::<T>())?;
+		
+		let mut val = MaybeUninit::<T>::uninit();
+		unsafe {
+			copy_user(val.as_mut_ptr() as *mut u8, self.addr as *const u8, size_of::<T>())?;
+			Ok(val.assume_init())
+		}
+	}
+	
+	pub fn write(self, val: T) -> Result<(), UserAccessErr> {
+		check_user_range(self.addr, size_of::<T>())?;
+		
+		unsafe {
+			copy_user(self.addr as *mut u8, &val as *const T as *const u8, size_of::<T>())
+		}
+	}
+}
+
+impl<T: Pod> Clone for UserPtr<T> {
+	#[inline]
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<T: Pod> Copy for UserPtr<T> {}
+
+/// `len` consecutive `T`s in the current user address space
+pub struct UserSlice<T: Pod> {
+	addr: usize,
+	len: usize,
+	_marker: PhantomData<*mut T>,
+}
+
+impl<T: Pod> UserSlice<T> {
+	#[inline]
+	pub fn new(addr: usize, len: usize) -> Self {
+		Self {addr, len, _marker: PhantomData}
+	}
+	
+	#[inline]
+	pub fn addr(self) -> usize {
+		self.addr
+	}
+	
+	#[inline]
+	pub fn len(self) -> usize {
+		self.len
+	}
+	
+	#[inline]
+	pub fn is_empty(self) -> bool {
+		self.len == 0
+	}
+	
+	/// The pointer to the element, `None` if the index is out of bounds
+	#[inline]
+	pub fn get(self, idx: usize) -> Option<UserPtr<T>> {
+		if idx < self.len {
+			Some(UserPtr::new(self.addr.wrapping_add(idx.wrapping_mul(size_of::<T>()))))
+		} else {
+			None
+		}
+	}
+	
+	/// Copies all elements into `dst`, which must have the same length
+	pub fn read_into(self, dst: &mut [T]) -> Result<(), UserAccessErr> {
+		assert_eq!(dst.len(), self.len, "User slice read into a slice of a different length");
+		
+		let size = self.byte_size()?;
+		check_user_range(self.addr, size)?;
+		unsafe {
+			copy_user(dst.as_mut_ptr() as *mut u8, self.addr as *const u8, size)
+		}
+	}
+	
+	/// Overwrites all elements with `src`, which must have the same length
+	pub fn write_from(self, src: &[T]) -> Result<(), UserAccessErr> {
+		assert_eq!(src.len(), self.len, "User slice written from a slice of a different length");
+		
+		let size = self.byte_size()?;
+		check_user_range(self.addr, size)?;
+		unsafe {
+			copy_user(self.addr as *mut u8, src.as_ptr() as *const u8, size)
+		}
+	}
+	
+	#[inline]
+	fn byte_size(self) -> Result<usize, UserAccessErr> {
+		self.len.checked_mul(size_of::<T>()).ok_or(UserAccessErr::InvalidRange)
+	}
+}
+
+impl<T: Pod> Clone for UserSlice<T> {
+	#[inline]
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+
+impl<T: Pod> Copy for UserSlice<T> {}
+
+/// Copies `dst.len()` bytes from the user address `src` into `dst`
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserAccessErr> {
+	check_user_range(src, dst.len())?;
+	unsafe {
+		copy_user(dst.as_mut_ptr(), src as *const u8, dst.len())
+	}
+}
+
+/// Copies `src` to the user address `dst`
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserAccessErr> {
+	check_user_range(dst, src.len())?;
+	unsafe {
+		copy_user(dst as *mut u8, src.as_ptr(), src.len())
+	}
+}
+
+/// Enables SMEP and SMAP on this cpu as far as it supports them.
+/// Must be called on every cpu before it runs user code.
+pub unsafe fn enable_user_access_protection() {
+	let smap_enabled = enable_smep_smap();
+	SMAP_ENABLED.store(smap_enabled, Relaxed);
+}
+
+/// Where to resume after a page fault at `rip` that could not be resolved,
+/// `None` if the faulting instruction is not the user copy
+pub fn user_copy_fixup(rip: usize) -> Option<usize> {
+	extern "C" {
+		static __user_copy_insn: u8;
+		static __user_copy_fixup: u8;
+	}
+	
+	unsafe {
+		if rip == &__user_copy_insn as *const u8 as usize {
+			Some(&__user_copy_fixup as *const u8 as usize)
+		} else {
+			None
+		}
+	}
+}
+
+#[inline]
+fn check_user_range(addr: usize, size: usize) -> Result<(), UserAccessErr> {
+	// Nothing is accessed, so the address doesn't matter
+	if size == 0 {
+		return Ok(());
+	}
+	
+	let end = addr.checked_add(size).ok_or(UserAccessErr::InvalidRange)?;
+	let range = user_range();
+	if addr < range.start.as_usize() || end > range.end.as_usize() {
+		return Err(UserAccessErr::InvalidRange);
+	}
+	Ok(())
+}
+
+#[inline]
+unsafe fn copy_user(dst: *mut u8, src: *const u8, size: usize) -> Result<(), UserAccessErr> {
+	if copy_user_raw(dst, src, size) == 0 {
+		Ok(())
+	} else {
+		Err(UserAccessErr::Fault)
+	}
+}
+
+/// Copies `size` bytes with user accesses allowed and returns the number of bytes
+/// that were not copied, which is only non zero if the copy faulted.
+/// 
+/// A fault can only happen at `__user_copy_insn`, `rep movsb` leaves the remaining
+/// count in rcx then, so the #PF handler just continues at `__user_copy_fixup`.
+/// The #PF entry clears RFLAGS.AC while the handler runs, `iretq` restores it.
+#[naked]
+unsafe extern "sysv64" fn copy_user_raw(dst: *mut u8, src: *const u8, size: usize) -> usize {
+	asm!(
+		"mov rcx, rdx",
+		"cmp byte ptr [rip + {smap_enabled}], 0",
+		"je 2f",
+		"stac",
+		"2:",
+		
+		".global __user_copy_insn",
+		"__user_copy_insn:",
+		"rep movsb",
+		
+		".global __user_copy_fixup",
+		"__user_copy_fixup:",
+		"cmp byte ptr [rip + {smap_enabled}], 0",
+		"je 3f",
+		"clac",
+		"3:",
+		"mov rax, rcx",
+		"ret",
+		
+		smap_enabled = sym SMAP_ENABLED,
+		options(noreturn),
+	);
+}
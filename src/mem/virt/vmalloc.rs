@@ -0,0 +1,467 @@
+//! Virtually contiguous kernel allocations
+//! 
+//! Big kernel buffers don't need physically contiguous memory, so [`vmalloc`] backs
+//! them with individually allocated base pages mapped next to each other in the
+//! vmalloc area of the kernel address space (see [`vmalloc_range`]).
+//! 
+//! Every allocation is surrounded by at least one unmapped guard page, so running
+//! off either end faults instead of silently corrupting the neighbour.
+//! 
+//! Areas made by [`vreserve`] start out unmapped and get their pages on the first
+//! access through the page fault handler (see [`handle_vmalloc_fault`]).
+
+use core::arch::asm;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::Ordering::*;
+
+use crate::mem::{Phys, VirtAddr};
+use crate::mem::kernel_mem_map::{try_with_kernel_mem_map, vmalloc_range, with_kernel_mem_map};
+use crate::mem::phys::{alloc_frames, frame_desc_of, free_frames, FrameOwner};
+use crate::mem::phys::buck::{BASE_PAGE_SIZE, BasePage, BuckBlock};
+use crate::arch::x86_64::exception::PageFaultErrCode;
+use crate::mem::virt::{FaultErr, MapErr, PteFlags};
+
+/// Max number of live allocations
+const MAX_VMALLOC_AREAS: usize = 1024;
+/// Unmapped space between allocations
+const GUARD_SIZE: usize = BASE_PAGE_SIZE;
+
+const VMALLOC_FLAGS: PteFlags = PteFlags(PteFlags::WRITABLE.0 | PteFlags::GLOBAL.0 | PteFlags::NO_EXECUTE.0);
+
+/// Live allocations sorted by address
+static mut VMALLOC_AREAS: [VmallocArea; MAX_VMALLOC_AREAS] = [VmallocArea::EMPTY; MAX_VMALLOC_AREAS];
+static VMALLOC_AREA_COUNT: AtomicUsize = AtomicUsize::new(0);
+static VMALLOC_AREAS_LOCKED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum VmallocErr {
+	/// No free virtual range of the size left (or too many allocations)
+	OutOfVirtualSpace,
+	/// No physical memory left for the pages or page tables
+	OutOfMemory,
+	/// The pointer is not the start of a live allocation
+	InvalidPtr,
+}
+
+impl From<MapErr> for VmallocErr {
+	#[inline]
+	fn from(err: MapErr) -> Self {
+		match err {
+			MapErr::OutOfMemory => Self::OutOfMemory,
+			_ => unreachable!("Failed to map vmalloc pages: {:?}", err),
+		}
+	}
+}
+
+/// A live allocation
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(super) struct VmallocArea {
+	pub(super) start: VirtAddr,
+	pub(super) page_count: usize,
+	pub(super) kind: AreaKind,
+}
+
+/// What backs the pages of an area
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(super) enum AreaKind {
+	/// Frames allocated up front by [`vmalloc`]
+	Backed,
+	/// Frames allocated on first access, see [`vreserve`]
+	DemandPaged,
+	/// Device memory mapped by [`super::ioremap`], the frames are not ours
+	Io,
+}
+
+impl VmallocArea {
+	const EMPTY: Self = Self {start: VirtAddr::ZERO, page_count: 0, kind: AreaKind::Backed};
+	
+	#[inline]
+	fn end(&self) -> VirtAddr {
+		self.start + (self.page_count * BASE_PAGE_SIZE)
+	}
+}
+
+/// Allocates `size` bytes (rounded up to whole pages) of zeroed, virtually
+/// contiguous kernel memory. Free it with [`vfree`].
+pub fn vmalloc(size: usize) -> Result<NonNull<u8>, VmallocErr> {
+	let page_count = page_count_of(size)?;
+	let start = reserve_area(page_count, AreaKind::Backed)?;
+	
+	if let Err(err) = unsafe { map_fresh_pages(start, page_count) } {
+		unsafe {
+			unmap_pages(start, page_count, true);
+		}
+		release_area(start);
+		return Err(err);
+	}
+	
+	Ok(unsafe { NonNull::new_unchecked(start.as_mut_ptr()) })
+}
+
+/// Reserves `size` bytes (rounded up to whole pages) of virtually contiguous kernel
+/// memory without backing it yet. Every page gets a zeroed frame on its first access.
+/// Free it with [`vfree`].
+pub fn vreserve(size: usize) -> Result<NonNull<u8>, VmallocErr> {
+	let start = reserve_area(page_count_of(size)?, AreaKind::DemandPaged)?;
+	Ok(unsafe { NonNull::new_unchecked(start.as_mut_ptr()) })
+}
+
+/// Frees an allocation made by [`vmalloc`], [`vreserve`] or [`vremap`]
+pub unsafe fn vfree(ptr: NonNull<u8>) -> Result<(), VmallocErr> {
+	let start = VirtAddr::from_ptr(ptr.as_ptr());
+	let area = find_area(start)
+		.filter(|area| area.kind != AreaKind::Io)
+		.ok_or(VmallocErr::InvalidPtr)?;
+	
+	unmap_pages(area.start, area.page_count, true);
+	release_area(area.start);
+	Ok(())
+}
+
+/// Resizes an allocation made by [`vmalloc`], [`vreserve`] or [`vremap`] to `new_size` bytes.
+/// 
+/// The allocation is grown or shrunk in place if possible, otherwise its pages are
+/// mapped again at a new address (without copying their contents). New pages are zeroed,
+/// in demand paged allocations they are only mapped on first access.
+/// On error the allocation is left as it was.
+pub unsafe fn vremap(ptr: NonNull<u8>, new_size: usize) -> Result<NonNull<u8>, VmallocErr> {
+	let start = VirtAddr::from_ptr(ptr.as_ptr());
+	let area = find_area(start)
+		.filter(|area| area.kind != AreaKind::Io)
+		.ok_or(VmallocErr::InvalidPtr)?;
+	let new_page_count = page_count_of(new_size)?;
+	
+	if new_page_count <= area.page_count {
+		let freed_pages = area.page_count - new_page_count;
+		if freed_pages > 0 {
+			unmap_pages(start + (new_page_count * BASE_PAGE_SIZE), freed_pages, true);
+			resize_area(start, new_page_count);
+		}
+		return Ok(ptr);
+	}
+	
+	let added_pages = new_page_count - area.page_count;
+	if try_grow_area(start, new_page_count) {
+		if area.kind == AreaKind::DemandPaged {
+			return Ok(ptr);
+		}
+		if let Err(err) = map_fresh_pages(area.end(), added_pages) {
+			unmap_pages(area.end(), added_pages, true);
+			resize_area(start, area.page_count);
+			return Err(err);
+		}
+		return Ok(ptr);
+	}
+	
+	// Move all pages over to a new range that is big enough
+	let new_start = reserve_area(new_page_count, area.kind)?;
+	let moved = with_kernel_mem_map(|map| {
+		for page_idx in 0..area.page_count {
+			let offset = page_idx * BASE_PAGE_SIZE;
+			let phys_addr = match map.page_table().translate((start + offset).as_usize()) {
+				Some(translation) => translation.phys_addr,
+				None if area.kind == AreaKind::DemandPaged => continue,
+				None => panic!("vmalloc page not mapped"),
+			};
+			
+			map.page_table_mut().map((new_start + offset).as_usize(), phys_addr, BASE_PAGE_SIZE, VMALLOC_FLAGS)?;
+		}
+		Ok(())
+	}).map_err(VmallocErr::from)
+		.and_then(|()| if area.kind == AreaKind::DemandPaged {
+			Ok(())
+		} else {
+			map_fresh_pages(new_start + (area.page_count * BASE_PAGE_SIZE), added_pages)
+		});
+	
+	if let Err(err) = moved {
+		// Only the fresh pages belong to the new range, the moved ones are still owned by the old one
+		unmap_pages(new_start, area.page_count, false);
+		unmap_pages(new_start + (area.page_count * BASE_PAGE_SIZE), added_pages, true);
+		release_area(new_start);
+		return Err(err);
+	}
+	
+	unmap_pages(start, area.page_count, false);
+	release_area(start);
+	Ok(NonNull::new_unchecked(new_start.as_mut_ptr()))
+}
+
+/// Maps a zeroed frame at the faulting page of a demand paged area.
+/// Faults anywhere else in the vmalloc area are bugs, they are reported with the
+/// allocation they hit or ran off of.
+/// 
+/// Note: The locks are only tried, waiting for them would hang forever if the
+/// fault came from code holding them already (only the boot cpu is running).
+pub fn handle_vmalloc_fault(addr: VirtAddr, err: PageFaultErrCode) -> Result<(), FaultErr> {
+	let page = addr.align_down(BASE_PAGE_SIZE);
+	
+	let area = try_with_vmalloc_areas(|areas, count| area_of_page(&areas[..*count], page))
+		.unwrap_or_else(|| panic!("Page fault at {:?} while the vmalloc areas are locked, did vmalloc code touch an unmapped page?", addr))?;
+	
+	if err.contains(PageFaultErrCode::PRESENT) || err.contains(PageFaultErrCode::INSTRUCTION_FETCH) {
+		return Err(FaultErr::ProtectionViolation);
+	}
+	if area.kind != AreaKind::DemandPaged {
+		return Err(FaultErr::NoRegion);
+	}
+	
+	let block = alloc_frames(0).ok_or(FaultErr::OutOfMemory)?;
+	frame_desc_of(&block).set_owner(FrameOwner::Vmalloc);
+	unsafe {
+		ptr::write_bytes(block.ptr().to_virt().as_ptr() as *mut u8, 0, BASE_PAGE_SIZE);
+	}
+	
+	let phys_addr = block.ptr().addr().as_usize();
+	let mapped = try_with_kernel_mem_map(|map| {
+		map.page_table_mut().map(page.as_usize(), phys_addr, BASE_PAGE_SIZE, VMALLOC_FLAGS)
+	}).unwrap_or_else(|| panic!("Page fault at {:?} while the kernel address space is locked, did the page table code touch an unmapped page?", addr));
+	
+	match mapped {
+		Ok(()) => Ok(()),
+		// Another cpu faulted on the same page first
+		Err(MapErr::AlreadyMapped) => {
+			unsafe {
+				free_frames(block);
+			}
+			Ok(())
+		},
+		Err(MapErr::OutOfMemory) => {
+			unsafe {
+				free_frames(block);
+			}
+			Err(FaultErr::OutOfMemory)
+		},
+		Err(err) => unreachable!("Failed to map vmalloc page: {:?}", err),
+	}
+}
+
+/// The area containing the page, or the one whose guard gap it is in
+fn area_of_page(areas: &[VmallocArea], page: VirtAddr) -> Result<VmallocArea, FaultErr> {
+	match areas.iter().find(|area| area.start <= page && page < area.end()) {
+		Some(area) => Ok(*area),
+		None => {
+			// Report the closest allocation if the address is in one of its guard gaps
+			let near = |area: &&VmallocArea| (page < area.start && area.start - page <= GUARD_SIZE)
+				|| (page >= area.end() && page - area.end() < GUARD_SIZE);
+			match areas.iter().find(near) {
+				Some(area) => Err(FaultErr::GuardPage(area.start)),
+				None => Err(FaultErr::NoRegion),
+			}
+		},
+	}
+}
+
+/// Backs the pages with newly allocated, zeroed frames.
+/// On error the pages mapped so far stay mapped.
+unsafe fn map_fresh_pages(start: VirtAddr, page_count: usize) -> Result<(), VmallocErr> {
+	for page_idx in 0..page_count {
+		let block = alloc_frames(0).ok_or(VmallocErr::OutOfMemory)?;
+		frame_desc_of(&block).set_owner(FrameOwner::Vmalloc);
+		ptr::write_bytes(block.ptr().to_virt().as_ptr() as *mut u8, 0, BASE_PAGE_SIZE);
+		
+		let phys_addr = block.ptr().addr().as_usize();
+		let mapped = with_kernel_mem_map(|map| {
+			map.page_table_mut().map((start + page_idx * BASE_PAGE_SIZE).as_usize(), phys_addr, BASE_PAGE_SIZE, VMALLOC_FLAGS)
+		});
+		
+		if let Err(err) = mapped {
+			free_frames(block);
+			return Err(err.into());
+		}
+	}
+	Ok(())
+}
+
+/// Unmaps all mapped pages in the range, freeing their frames if `free` is set
+pub(super) unsafe fn unmap_pages(start: VirtAddr, page_count: usize, free: bool) {
+	for page_idx in 0..page_count {
+		let virt_addr = (start + page_idx * BASE_PAGE_SIZE).as_usize();
+		
+		let phys_addr = with_kernel_mem_map(|map| {
+			let translation = map.page_table().translate(virt_addr)?;
+			map.page_table_mut().unmap(virt_addr, BASE_PAGE_SIZE)
+				.expect("Failed to unmap vmalloc page");
+			Some(translation.phys_addr)
+		});
+		
+		if let (Some(phys_addr), true) = (phys_addr, free) {
+			free_frames(BuckBlock::from_raw(Phys(NonNull::new_unchecked(phys_addr as *mut BasePage)), 0));
+		}
+	}
+}
+
+/// Number of pages needed for `size` bytes, at least one
+#[inline]
+fn page_count_of(size: usize) -> Result<usize, VmallocErr> {
+	let rounded_size = size.checked_add(BASE_PAGE_SIZE - 1).ok_or(VmallocErr::OutOfVirtualSpace)?;
+	Ok((rounded_size / BASE_PAGE_SIZE).max(1))
+}
+
+/// Finds the lowest free range of the size with guard gaps on both sides and records it
+pub(super) fn reserve_area(page_count: usize, kind: AreaKind) -> Result<VirtAddr, VmallocErr> {
+	let size = page_count.checked_mul(BASE_PAGE_SIZE).ok_or(VmallocErr::OutOfVirtualSpace)?;
+	let range = vmalloc_range();
+	
+	with_vmalloc_areas(|areas, count| {
+		if *count == MAX_VMALLOC_AREAS {
+			return Err(VmallocErr::OutOfVirtualSpace);
+		}
+		
+		let mut candidate = range.start + GUARD_SIZE;
+		let mut insert_idx = *count;
+		for (idx, area) in areas[..*count].iter().enumerate() {
+			if area.start > candidate && (area.start - candidate) >= size + GUARD_SIZE {
+				insert_idx = idx;
+				break;
+			}
+			candidate = area.end() + GUARD_SIZE;
+		}
+		
+		if insert_idx == *count && (range.end <= candidate || (range.end - candidate) < size + GUARD_SIZE) {
+			return Err(VmallocErr::OutOfVirtualSpace);
+		}
+		
+		areas.copy_within(insert_idx..*count, insert_idx + 1);
+		areas[insert_idx] = VmallocArea {start: candidate, page_count, kind};
+		*count += 1;
+		Ok(candidate)
+	})
+}
+
+pub(super) fn release_area(start: VirtAddr) {
+	with_vmalloc_areas(|areas, count| {
+		let idx = areas[..*count].iter().position(|area| area.start == start)
+			.expect("Released unknown vmalloc area");
+		
+		areas.copy_within((idx + 1)..*count, idx);
+		*count -= 1;
+	});
+}
+
+pub(super) fn find_area(start: VirtAddr) -> Option<VmallocArea> {
+	with_vmalloc_areas(|areas, count| {
+		areas[..*count].iter()
+			.find(|area| area.start == start)
+			.copied()
+	})
+}
+
+/// Shrinks the area or undoes [`try_grow_area`]
+fn resize_area(start: VirtAddr, page_count: usize) {
+	with_vmalloc_areas(|areas, count| {
+		let area = areas[..*count].iter_mut().find(|area| area.start == start)
+			.expect("Resized unknown vmalloc area");
+		area.page_count = page_count;
+	});
+}
+
+/// Grows the area in place if the following area (or the end of the
+/// vmalloc range) is far enough away to keep a guard gap
+fn try_grow_area(start: VirtAddr, page_count: usize) -> bool {
+	let range = vmalloc_range();
+	
+	with_vmalloc_areas(|areas, count| {
+		let idx = match areas[..*count].iter().position(|area| area.start == start) {
+			Some(idx) => idx,
+			None => return false,
+		};
+		let limit = areas[..*count].get(idx + 1)
+			.map(|next| next.start)
+			.unwrap_or(range.end);
+		
+		if (limit - start) / BASE_PAGE_SIZE >= page_count + (GUARD_SIZE / BASE_PAGE_SIZE) {
+			areas[idx].page_count = page_count;
+			true
+		} else {
+			false
+		}
+	})
+}
+
+fn with_vmalloc_areas<R>(f: impl FnOnce(&mut [VmallocArea; MAX_VMALLOC_AREAS], &mut usize) -> R) -> R {
+	while VMALLOC_AREAS_LOCKED.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+		unsafe {
+			asm!("pause", options(nomem, nostack));
+		}
+	}
+	
+	vmalloc_areas_locked(f)
+}
+
+/// Like [`with_vmalloc_areas`], but returns `None` instead of waiting if the areas are locked already
+fn try_with_vmalloc_areas<R>(f: impl FnOnce(&mut [VmallocArea; MAX_VMALLOC_AREAS], &mut usize) -> R) -> Option<R> {
+	if VMALLOC_AREAS_LOCKED.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+		return None;
+	}
+	
+	Some(vmalloc_areas_locked(f))
+}
+
+/// Runs `f` on the areas and releases the lock, which the caller has to hold
+fn vmalloc_areas_locked<R>(f: impl FnOnce(&mut [VmallocArea; MAX_VMALLOC_AREAS], &mut usize) -> R) -> R {
+	let mut count = VMALLOC_AREA_COUNT.load(Relaxed);
+	let ret = f(unsafe { &mut VMALLOC_AREAS }, &mut count);
+	VMALLOC_AREA_COUNT.store(count, Relaxed);
+	
+	VMALLOC_AREAS_LOCKED.store(false, Release);
+	ret
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	const TEST_BASE: usize = 0xffff_c900_0000_0000;
+	
+	fn test_area(page_offset: usize, page_count: usize) -> VmallocArea {
+		VmallocArea {
+			start: VirtAddr::new(TEST_BASE + page_offset * BASE_PAGE_SIZE),
+			page_count,
+			kind: AreaKind::DemandPaged,
+		}
+	}
+	
+	fn page_at(page_offset: usize) -> VirtAddr {
+		VirtAddr::new(TEST_BASE + page_offset * BASE_PAGE_SIZE)
+	}
+	
+	#[test]
+	fn page_counts() {
+		assert_eq!(page_count_of(0), Ok(1));
+		assert_eq!(page_count_of(1), Ok(1));
+		assert_eq!(page_count_of(BASE_PAGE_SIZE), Ok(1));
+		assert_eq!(page_count_of(BASE_PAGE_SIZE + 1), Ok(2));
+		assert_eq!(page_count_of(0x1 << 20), Ok(256));
+		assert_eq!(page_count_of(usize::MAX - (BASE_PAGE_SIZE - 1)), Ok(usize::MAX / BASE_PAGE_SIZE));
+		assert_eq!(page_count_of(usize::MAX), Err(VmallocErr::OutOfVirtualSpace));
+	}
+	
+	#[test]
+	fn area_lookup() {
+		let areas = [test_area(1, 4), test_area(6, 1), test_area(16, 2)];
+		
+		assert_eq!(area_of_page(&areas, page_at(1)), Ok(areas[0]));
+		assert_eq!(area_of_page(&areas, page_at(4)), Ok(areas[0]));
+		assert_eq!(area_of_page(&areas, page_at(6)), Ok(areas[1]));
+		assert_eq!(area_of_page(&areas, page_at(17)), Ok(areas[2]));
+		
+		assert_eq!(area_of_page(&[], page_at(1)), Err(FaultErr::NoRegion));
+	}
+	
+	#[test]
+	fn area_lookup_guard_pages() {
+		let areas = [test_area(1, 4), test_area(16, 2)];
+		
+		// One guard page on each side
+		assert_eq!(area_of_page(&areas, page_at(0)), Err(FaultErr::GuardPage(areas[0].start)));
+		assert_eq!(area_of_page(&areas, page_at(5)), Err(FaultErr::GuardPage(areas[0].start)));
+		assert_eq!(area_of_page(&areas, page_at(15)), Err(FaultErr::GuardPage(areas[1].start)));
+		assert_eq!(area_of_page(&areas, page_at(18)), Err(FaultErr::GuardPage(areas[1].start)));
+		
+		assert_eq!(area_of_page(&areas, page_at(6)), Err(FaultErr::NoRegion));
+		assert_eq!(area_of_page(&areas, page_at(14)), Err(FaultErr::NoRegion));
+		assert_eq!(area_of_page(&areas, page_at(19)), Err(FaultErr::NoRegion));
+	}
+}
@@ -0,0 +1,172 @@
+//! Kernel stacks
+//! 
+//! Every stack is a [`vmalloc`] allocation, so it is backed up front (the stack has to
+//! work in the page fault handler) and the page below it is an unmapped guard page.
+//! Running off the bottom of a stack faults on that page. As the cpu can't push the
+//! #PF frame onto the overflowed stack, this usually ends in a #DF, which runs on its own
+//! stack (see [`crate::arch::x86_64::interrupt::init_double_fault_stack`]) and reports
+//! the owner of the overflowed stack with [`overflowed_stack_owner`].
+
+use core::arch::asm;
+use core::fmt;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::Ordering::*;
+
+use crate::cpu::CpuUid;
+use crate::mem::VirtAddr;
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+use crate::mem::virt::{vfree, vmalloc, VmallocErr};
+use crate::proc::{ThreadId, ThreadKind};
+
+/// Max number of live kernel stacks
+const MAX_KERNEL_STACKS: usize = 512;
+
+/// Live stacks, in no particular order
+static mut KERNEL_STACKS: [StackDesc; MAX_KERNEL_STACKS] = [StackDesc::EMPTY; MAX_KERNEL_STACKS];
+static KERNEL_STACK_COUNT: AtomicUsize = AtomicUsize::new(0);
+static KERNEL_STACKS_LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// What a kernel stack is used by
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum StackOwner {
+	Thread(ThreadId, ThreadKind),
+	/// The #DF handler of the cpu
+	DoubleFault(CpuUid),
+}
+
+impl fmt::Display for StackOwner {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Thread(id, kind) => write!(f, "thread {} ({:?})", id, kind),
+			Self::DoubleFault(cpu) => write!(f, "the double fault handler of cpu {}", cpu.0),
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+struct StackDesc {
+	/// Lowest address of the stack, the guard page is right below
+	bottom: VirtAddr,
+	owner: StackOwner,
+}
+
+impl StackDesc {
+	const EMPTY: Self = Self {bottom: VirtAddr::ZERO, owner: StackOwner::DoubleFault(CpuUid(0))};
+}
+
+/// A kernel stack with a guard page below it, freed on drop
+pub struct KernelStack {
+	bottom: NonNull<u8>,
+	size: usize,
+}
+
+unsafe impl Send for KernelStack {}
+unsafe impl Sync for KernelStack {}
+
+impl KernelStack {
+	/// Allocates a stack of `size` bytes (rounded up to whole pages)
+	pub fn new(size: usize, owner: StackOwner) -> Result<Self, VmallocErr> {
+		let size = ((size + (BASE_PAGE_SIZE - 1)) & !(BASE_PAGE_SIZE - 1)).max(BASE_PAGE_SIZE);
+		let bottom = vmalloc(size)?;
+		
+		let registered = with_kernel_stacks(|stacks, count| {
+			if *count == MAX_KERNEL_STACKS {
+				return false;
+			}
+			stacks[*count] = StackDesc {bottom: VirtAddr::from_ptr(bottom.as_ptr()), owner};
+			*count += 1;
+			true
+		});
+		if !registered {
+			unsafe {
+				vfree(bottom).expect("Failed to free a fresh kernel stack");
+			}
+			return Err(VmallocErr::OutOfVirtualSpace);
+		}
+		
+		Ok(Self {bottom, size})
+	}
+	
+	/// Allocates the kernel stack of a thread, sized for its kind
+	#[inline]
+	pub fn for_thread(id: ThreadId, kind: ThreadKind) -> Result<Self, VmallocErr> {
+		Self::new(kind.kernel_stack_size(), StackOwner::Thread(id, kind))
+	}
+	
+	#[inline]
+	pub fn bottom(&self) -> *const u8 {
+		self.bottom.as_ptr()
+	}
+	
+	/// The initial stack pointer, i.e. the first byte above the stack
+	#[inline]
+	pub fn top(&self) -> *const u8 {
+		self.bottom.as_ptr().wrapping_add(self.size)
+	}
+	
+	#[inline]
+	pub fn size(&self) -> usize {
+		self.size
+	}
+	
+	/// Keeps the stack forever and returns its top
+	#[inline]
+	pub fn leak(self) -> *const u8 {
+		let top = self.top();
+		core::mem::forget(self);
+		top
+	}
+}
+
+impl Drop for KernelStack {
+	fn drop(&mut self) {
+		let bottom = VirtAddr::from_ptr(self.bottom.as_ptr());
+		with_kernel_stacks(|stacks, count| {
+			let idx = stacks[..*count].iter().position(|stack| stack.bottom == bottom)
+				.expect("Dropped an unregistered kernel stack");
+			stacks[idx] = stacks[*count - 1];
+			*count -= 1;
+		});
+		
+		unsafe {
+			vfree(self.bottom).expect("Failed to free a kernel stack");
+		}
+	}
+}
+
+/// The owner of the stack whose guard page contains `addr`, i.e. whose stack overflowed.
+/// 
+/// Called from exception handlers, which may have interrupted a cpu that holds
+/// the lock on the stacks, so this gives up with `None` instead of waiting for it.
+pub fn overflowed_stack_owner(addr: usize) -> Option<StackOwner> {
+	if KERNEL_STACKS_LOCKED.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+		return None;
+	}
+	
+	let count = KERNEL_STACK_COUNT.load(Relaxed);
+	let owner = unsafe { &KERNEL_STACKS[..count] }.iter()
+		.find(|stack| {
+			let bottom = stack.bottom.as_usize();
+			addr < bottom && bottom - addr <= BASE_PAGE_SIZE
+		})
+		.map(|stack| stack.owner);
+	
+	KERNEL_STACKS_LOCKED.store(false, Release);
+	owner
+}
+
+fn with_kernel_stacks<R>(f: impl FnOnce(&mut [StackDesc; MAX_KERNEL_STACKS], &mut usize) -> R) -> R {
+	while KERNEL_STACKS_LOCKED.compare_exchange(false, true, Acquire, Relaxed).is_err() {
+		unsafe {
+			asm!("pause", options(nomem, nostack));
+		}
+	}
+	
+	let mut count = KERNEL_STACK_COUNT.load(Relaxed);
+	let ret = f(unsafe { &mut KERNEL_STACKS }, &mut count);
+	KERNEL_STACK_COUNT.store(count, Relaxed);
+	
+	KERNEL_STACKS_LOCKED.store(false, Release);
+	ret
+}
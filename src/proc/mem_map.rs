@@ -0,0 +1,41 @@
+//! The address space each cpu is currently running in
+
+use core::ptr;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering::*;
+
+use crate::cpu::current_cpu_uid;
+use crate::mem::kernel_mem_map::with_kernel_mem_map;
+use crate::mem::virt::MemMap;
+
+/// Max number of cpus that can run in user address spaces
+const MAX_MEM_MAP_CPUS: usize = 256;
+
+const NO_MEM_MAP: AtomicPtr<MemMap> = AtomicPtr::new(ptr::null_mut());
+
+/// The user address space of every cpu, null while running in the kernel's own one
+static CURRENT_MEM_MAPS: [AtomicPtr<MemMap>; MAX_MEM_MAP_CPUS] = [NO_MEM_MAP; MAX_MEM_MAP_CPUS];
+
+/// Switches the current cpu to the user address space, or back
+/// to the kernel's own address space if `map` is `None`.
+/// 
+/// The map must stay alive until the cpu switched away from it again.
+pub unsafe fn switch_mem_map(map: Option<&MemMap>) {
+	let slot = CURRENT_MEM_MAPS.get(current_cpu_uid().0 as usize)
+		.expect("Cpu uid too high for user address spaces");
+	
+	match map {
+		Some(map) => map.activate(),
+		None => with_kernel_mem_map(|kernel_map| kernel_map.page_table().activate()),
+	}
+	slot.store(map.map_or(ptr::null_mut(), |map| map as *const MemMap as *mut MemMap), Release);
+}
+
+/// Calls `f` with the user address space of the current cpu, if any
+pub fn with_current_mem_map<R>(f: impl FnOnce(Option<&MemMap>) -> R) -> R {
+	let map = CURRENT_MEM_MAPS.get(current_cpu_uid().0 as usize)
+		.map_or(ptr::null_mut(), |slot| slot.load(Acquire));
+	
+	// Safety: Maps stay alive while they are current, see switch_mem_map
+	f(unsafe { map.as_ref() })
+}
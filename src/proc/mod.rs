@@ -0,0 +1,7 @@
+pub use kernel_stack::*;
+pub use mem_map::*;
+pub use thread::*;
+
+mod kernel_stack;
+mod mem_map;
+mod thread;
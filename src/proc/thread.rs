@@ -0,0 +1,69 @@
+//! Thread identities and the per type configuration of their kernel stacks
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize};
+use core::sync::atomic::Ordering::*;
+
+use crate::mem::phys::buck::BASE_PAGE_SIZE;
+
+/// Next id handed out by [`ThreadId::new`]
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Kernel stack sizes of the thread kinds, indexed by [`ThreadKind::idx`]
+static KERNEL_STACK_SIZES: [AtomicUsize; 2] = [
+	AtomicUsize::new(8 * BASE_PAGE_SIZE),
+	AtomicUsize::new(4 * BASE_PAGE_SIZE),
+];
+
+/// System-wide unique id of a thread, ids are never reused
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(transparent)]
+pub struct ThreadId(pub u64);
+
+impl ThreadId {
+	/// Allocates a fresh id
+	#[inline]
+	pub fn new() -> Self {
+		Self(NEXT_THREAD_ID.fetch_add(1, Relaxed))
+	}
+}
+
+impl fmt::Display for ThreadId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "#{}", self.0)
+	}
+}
+
+/// What a thread runs, which decides the size of its kernel stack
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ThreadKind {
+	/// Runs only kernel code, so its whole call stack lives on the kernel stack
+	Kernel,
+	/// Runs user code and only uses its kernel stack for syscalls and interrupts
+	User,
+}
+
+impl ThreadKind {
+	/// Size of the kernel stacks of new threads of this kind
+	#[inline]
+	pub fn kernel_stack_size(self) -> usize {
+		KERNEL_STACK_SIZES[self.idx()].load(Relaxed)
+	}
+	
+	/// Changes the kernel stack size of threads of this kind created from now on.
+	/// The size is rounded up to whole pages.
+	pub fn set_kernel_stack_size(self, size: usize) {
+		assert!(size > 0, "Kernel stacks can't be empty");
+		
+		let size = (size + (BASE_PAGE_SIZE - 1)) & !(BASE_PAGE_SIZE - 1);
+		KERNEL_STACK_SIZES[self.idx()].store(size, Relaxed);
+	}
+	
+	#[inline]
+	const fn idx(self) -> usize {
+		match self {
+			Self::Kernel => 0,
+			Self::User => 1,
+		}
+	}
+}
@@ -36,8 +36,16 @@ use core::arch::asm;
 
 use memoffset::offset_of;
 
+use crate::arch::x86_64::msr::KERNEL_GS_BASE;
+use crate::cpu::current_cpu_uid;
 use crate::tty;
 
+/// Max number of cpus with per-cpu data
+const MAX_CPUS: usize = 256;
+
+/// Per-cpu data of every cpu, indexed by its uid
+static mut PER_CPU_DATA: [PerCpuData; MAX_CPUS] = [PerCpuData::EMPTY; MAX_CPUS];
+
 // Note:
 // - regs: sysv_x86-64-psABI-1.0.pdf, page 24
 
@@ -216,6 +224,7 @@ fn service_syscall(regs: &SyscallSavedRegs) {
 
 #[repr(C)]
 pub struct PerCpuData {
+	/// Top of the kernel stack syscalls run on, i.e. the one of the current thread
 	pub syscall_stack_base: *const u8,
 	/// A place to temporarily save the usermode rsp
 	/// on syscall entry, so that the kernel can overwrite the rsp
@@ -223,6 +232,25 @@ pub struct PerCpuData {
 	pub syscall_temp_um_rsp: u64,
 }
 
+impl PerCpuData {
+	const EMPTY: Self = Self {syscall_stack_base: core::ptr::null(), syscall_temp_um_rsp: 0};
+}
+
+/// Points the kernel gs base of the current cpu at its [`PerCpuData`],
+/// which `swapgs` makes available on syscall entry
+pub unsafe fn init_per_cpu_data() {
+	let data = PER_CPU_DATA.get_mut(current_cpu_uid().0 as usize)
+		.expect("Cpu uid too high for per-cpu data");
+	KERNEL_GS_BASE.write(data as *mut PerCpuData as u64);
+}
+
+/// Makes syscalls on the current cpu run on the stack with the given top
+pub unsafe fn set_syscall_stack(stack_top: *const u8) {
+	let data = PER_CPU_DATA.get_mut(current_cpu_uid().0 as usize)
+		.expect("Cpu uid too high for per-cpu data");
+	data.syscall_stack_base = stack_top;
+}
+
 /*
 // Godbolt stuff:
 /// Usermode syscall entry with 3 of the 5 max args
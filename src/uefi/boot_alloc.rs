@@ -64,7 +64,7 @@ impl Clone for UefiBootAlloc {
 	}
 }
 
-mod table {
+pub(super) mod table {
 	use core::ffi::c_void;
 	
 	use uefi_rs::{Event, Guid, Handle, Status};
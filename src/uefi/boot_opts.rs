@@ -0,0 +1,71 @@
+//! Kernel boot options, passed as space separated uefi load options
+//! (e.g. as arguments in the uefi shell)
+//! 
+//! Known options:
+//! - `no-la57`: Use four-level paging even if the cpu supports five-level paging
+//! - `nokaslr`: Place the kernel and its memory regions at fixed addresses (see [`crate::mem::kaslr`])
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::*;
+
+use uefi_rs::Handle;
+use uefi_rs::proto::loaded_image::LoadedImage;
+use uefi_rs::table::boot::BootServices;
+
+static FORCE_4_LEVEL_PAGING: AtomicBool = AtomicBool::new(false);
+static NO_KASLR: AtomicBool = AtomicBool::new(false);
+
+/// Parses the load options of the kernel image. Must be called before exiting boot services.
+pub fn parse_boot_opts(boot_services: &BootServices, image_handle: Handle) {
+	let loaded_image = match boot_services.open_protocol_exclusive::<LoadedImage>(image_handle) {
+		Ok(loaded_image) => loaded_image,
+		Err(_) => return,
+	};
+	let load_options = match loaded_image.load_options_as_bytes() {
+		Some(load_options) => load_options,
+		None => return,
+	};
+	
+	// Load options are UCS-2, we only care about ascii so simply narrow them
+	let mut opt_buf = [0u8; 64];
+	let mut opt_len = 0;
+	
+	let chars = load_options
+		.chunks_exact(2)
+		.map(|c| u16::from_le_bytes([c[0], c[1]]))
+		.chain(core::iter::once(0));
+	
+	for c in chars {
+		if c == b' ' as u16 || c == 0 {
+			apply_boot_opt(&opt_buf[..opt_len]);
+			opt_len = 0;
+			
+			if c == 0 {
+				break;
+			}
+		} else if opt_len < opt_buf.len() {
+			opt_buf[opt_len] = if c < 0x80 { c as u8 } else { b'?' };
+			opt_len += 1;
+		}
+	}
+}
+
+fn apply_boot_opt(opt: &[u8]) {
+	match opt {
+		b"no-la57" => FORCE_4_LEVEL_PAGING.store(true, SeqCst),
+		b"nokaslr" => NO_KASLR.store(true, SeqCst),
+		_ => {},
+	}
+}
+
+/// Whether five-level paging has been disabled with `no-la57`
+#[inline]
+pub fn force_4_level_paging() -> bool {
+	FORCE_4_LEVEL_PAGING.load(Relaxed)
+}
+
+/// Whether address space layout randomization has been disabled with `nokaslr`
+#[inline]
+pub fn no_kaslr() -> bool {
+	NO_KASLR.load(Relaxed)
+}
@@ -0,0 +1,344 @@
+//! The firmware's memory map, captured when exiting the boot services
+//! 
+//! [`exit_boot_services`] fetches the final UEFI memory map and [`init_mem_map`] turns it
+//! into a [`MemMap`] the kernel owns: one region per run of physically contiguous memory of
+//! the same [`MemRegionKind`], sorted by address. It stays valid for the whole uptime and
+//! is available through [`mem_map`], and can be dumped over serial with [`dump_mem_map`].
+//! 
+//! The early physical memory setup works on the raw descriptors instead,
+//! as it also needs the memory attributes of each descriptor.
+
+use core::ffi::c_void;
+use core::fmt;
+use core::fmt::Write;
+use core::mem::transmute;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::*;
+
+use uefi_rs::{Handle, Status};
+use uefi_rs::table::{Boot, Runtime, SystemTable};
+use uefi_rs::table::boot::{MemoryDescriptor, MemoryMapIter, MemoryType};
+
+use crate::mem::{PhysAddr, PhysRange};
+use crate::mem::phys::buck::BASE_PAGE_ADDR_BITS;
+use crate::tty_writer;
+use crate::uefi::boot_alloc::table::RawBootServicesTable;
+
+/// Max number of regions, plenty as adjacent descriptors of the same kind are merged
+const MAX_MEM_REGIONS: usize = 256;
+/// Max number of calls to ExitBootServices before giving up on a memory map that keeps changing
+const MAX_EXIT_ATTEMPTS: usize = 8;
+
+static mut MEM_MAP: MemMap = MemMap {regions: [MemRegion::EMPTY; MAX_MEM_REGIONS], len: 0};
+static MEM_MAP_READY: AtomicBool = AtomicBool::new(false);
+
+/// What a region of physical memory is used for
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum MemRegionKind {
+	/// Conventional RAM, free for the kernel to use
+	Usable,
+	/// The loader's code and data, i.e. the kernel image and its early allocations
+	Loader,
+	/// Boot services code and data, free once nothing from the boot refers to it anymore
+	BootServices,
+	/// ACPI tables, usable once the kernel is done with them
+	AcpiReclaim,
+	/// ACPI non-volatile storage, must be preserved forever
+	AcpiNvs,
+	/// Memory mapped device registers and port space
+	Mmio,
+	RuntimeCode,
+	RuntimeData,
+	/// Reserved, unusable or of an unknown type
+	Reserved,
+}
+
+impl MemRegionKind {
+	pub fn from_uefi(ty: MemoryType) -> Self {
+		match ty {
+			MemoryType::CONVENTIONAL => Self::Usable,
+			MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => Self::Loader,
+			MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => Self::BootServices,
+			MemoryType::ACPI_RECLAIM => Self::AcpiReclaim,
+			MemoryType::ACPI_NON_VOLATILE => Self::AcpiNvs,
+			MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => Self::Mmio,
+			MemoryType::RUNTIME_SERVICES_CODE => Self::RuntimeCode,
+			MemoryType::RUNTIME_SERVICES_DATA => Self::RuntimeData,
+			_ => Self::Reserved,
+		}
+	}
+}
+
+/// A page aligned range of physical memory of a single kind
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MemRegion {
+	pub range: PhysRange,
+	pub kind: MemRegionKind,
+}
+
+impl MemRegion {
+	const EMPTY: Self = Self {
+		range: PhysRange {start: PhysAddr::ZERO, end: PhysAddr::ZERO},
+		kind: MemRegionKind::Reserved,
+	};
+}
+
+/// The sorted and coalesced regions of the firmware's memory map
+pub struct MemMap {
+	regions: [MemRegion; MAX_MEM_REGIONS],
+	len: usize,
+}
+
+impl MemMap {
+	/// All regions sorted by address, they never overlap
+	#[inline]
+	pub fn regions(&self) -> &[MemRegion] {
+		&self.regions[..self.len]
+	}
+	
+	/// The region containing the physical address, if any
+	pub fn region_of(&self, addr: PhysAddr) -> Option<&MemRegion> {
+		let idx = self.regions().partition_point(|region| region.range.end <= addr);
+		self.regions().get(idx)
+			.filter(|region| region.range.contains(addr))
+	}
+	
+	/// Total size of the regions of the kind
+	pub fn total_size(&self, kind: MemRegionKind) -> usize {
+		self.regions().iter()
+			.filter(|region| region.kind == kind)
+			.map(|region| region.range.size())
+			.sum()
+	}
+	
+	/// Inserts the range at its sorted position, merging it with adjacent regions of the same kind
+	fn insert(&mut self, range: PhysRange, kind: MemRegionKind) {
+		if range.is_empty() {
+			return;
+		}
+		
+		// Descriptors never overlap, so neighbours in address order are the only merge candidates
+		let idx = self.regions().partition_point(|region| region.range.start < range.start);
+		
+		let merges_prev = idx > 0 && self.regions[idx - 1].kind == kind && self.regions[idx - 1].range.end == range.start;
+		let merges_next = idx < self.len && self.regions[idx].kind == kind && self.regions[idx].range.start == range.end;
+		
+		match (merges_prev, merges_next) {
+			(true, true) => {
+				self.regions[idx - 1].range.end = self.regions[idx].range.end;
+				self.regions.copy_within((idx + 1)..self.len, idx);
+				self.len -= 1;
+			},
+			(true, false) => self.regions[idx - 1].range.end = range.end,
+			(false, true) => self.regions[idx].range.start = range.start,
+			(false, false) => {
+				assert!(self.len < MAX_MEM_REGIONS, "Too many memory map regions");
+				self.regions.copy_within(idx..self.len, idx + 1);
+				self.regions[idx] = MemRegion {range, kind};
+				self.len += 1;
+			},
+		}
+	}
+}
+
+impl fmt::Display for MemMap {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for region in self.regions() {
+			writeln!(f, "[{:#014x}..{:#014x}] {:>8} KiB {:?}",
+				region.range.start,
+				region.range.end,
+				region.range.size() >> 10,
+				region.kind,
+			)?;
+		}
+		Ok(())
+	}
+}
+
+/// Exits the boot services with the final memory map, which is written into `mmap_buf`.
+/// 
+/// The memory map key is only accepted if the memory map did not change since it was
+/// fetched, otherwise ExitBootServices fails and the map has to be fetched again. After the
+/// first failed attempt nothing but fetching the memory map and exiting may be done anymore,
+/// so `mmap_buf` must have room for a few more descriptors than the current map has.
+pub fn exit_boot_services<'a>(sys_table: SystemTable<Boot>, image: Handle, mmap_buf: &'a mut [u8]) -> uefi_rs::Result<(SystemTable<Runtime>, MemoryMapIter<'a>)> {
+	let raw_boot_services = unsafe { transmute::<_, &RawBootServicesTable>(sys_table.boot_services()) };
+	
+	for _ in 0..MAX_EXIT_ATTEMPTS {
+		// Safety: Every iteration either returns the iterator or is done with it before the next one
+		let buf = unsafe { &mut *(mmap_buf as *mut [u8]) };
+		let (mmap_key, mmap_iter) = sys_table.boot_services().memory_map(buf)?;
+		
+		let status = unsafe { (raw_boot_services.exit_boot_services)(image, mmap_key) };
+		match status {
+			Status::SUCCESS => {
+				let rt_table = unsafe { SystemTable::<Runtime>::from_ptr(sys_table.as_ptr() as *mut c_void) }
+					.expect("System table vanished");
+				return Ok((rt_table, mmap_iter));
+			},
+			// The memory map changed in between, try again with the current one
+			Status::INVALID_PARAMETER => continue,
+			status => return Err(status.into()),
+		}
+	}
+	Err(Status::INVALID_PARAMETER.into())
+}
+
+/// Captures the final memory map, must be called exactly once right after the boot services were exited
+pub fn init_mem_map<'a>(mmap: impl Iterator<Item = &'a MemoryDescriptor>) {
+	assert!(!MEM_MAP_READY.load(Acquire), "Memory map captured twice");
+	
+	let map = unsafe { &mut MEM_MAP };
+	for desc in mmap {
+		let range = PhysRange::with_size(
+			PhysAddr::new(desc.phys_start as usize),
+			(desc.page_count as usize) << BASE_PAGE_ADDR_BITS,
+		);
+		map.insert(range, MemRegionKind::from_uefi(desc.ty));
+	}
+	
+	MEM_MAP_READY.store(true, Release);
+}
+
+/// The memory map captured by [`init_mem_map`]
+#[inline]
+pub fn mem_map() -> &'static MemMap {
+	assert!(MEM_MAP_READY.load(Acquire), "Memory map not captured yet");
+	unsafe { &MEM_MAP }
+}
+
+/// Writes the memory map to the tty, i.e. the serial port
+pub fn dump_mem_map() {
+	let map = mem_map();
+	let _ = writeln!(tty_writer(), "Memory map ({} regions, {} MiB usable):", map.regions().len(), map.total_size(MemRegionKind::Usable) >> 20);
+	let _ = write!(tty_writer(), "{}", map);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use MemRegionKind::*;
+	
+	fn empty_map() -> MemMap {
+		MemMap {regions: [MemRegion::EMPTY; MAX_MEM_REGIONS], len: 0}
+	}
+	
+	fn range(start: usize, end: usize) -> PhysRange {
+		PhysRange::new(PhysAddr::new(start), PhysAddr::new(end))
+	}
+	
+	fn region(start: usize, end: usize, kind: MemRegionKind) -> MemRegion {
+		MemRegion {range: range(start, end), kind}
+	}
+	
+	#[test]
+	fn insert_sorts_regions() {
+		let mut map = empty_map();
+		map.insert(range(0x5000, 0x6000), Usable);
+		map.insert(range(0x1000, 0x2000), Usable);
+		map.insert(range(0x3000, 0x4000), Usable);
+		
+		assert_eq!(map.regions(), &[
+			region(0x1000, 0x2000, Usable),
+			region(0x3000, 0x4000, Usable),
+			region(0x5000, 0x6000, Usable),
+		]);
+	}
+	
+	#[test]
+	fn insert_ignores_empty_ranges() {
+		let mut map = empty_map();
+		map.insert(range(0x1000, 0x1000), Usable);
+		assert!(map.regions().is_empty());
+	}
+	
+	#[test]
+	fn insert_merges_prev() {
+		let mut map = empty_map();
+		map.insert(range(0x1000, 0x2000), Usable);
+		map.insert(range(0x2000, 0x3000), Usable);
+		assert_eq!(map.regions(), &[region(0x1000, 0x3000, Usable)]);
+	}
+	
+	#[test]
+	fn insert_merges_next() {
+		let mut map = empty_map();
+		map.insert(range(0x2000, 0x3000), Usable);
+		map.insert(range(0x1000, 0x2000), Usable);
+		assert_eq!(map.regions(), &[region(0x1000, 0x3000, Usable)]);
+	}
+	
+	#[test]
+	fn insert_merges_both() {
+		let mut map = empty_map();
+		map.insert(range(0x1000, 0x2000), Usable);
+		map.insert(range(0x3000, 0x4000), Usable);
+		map.insert(range(0x8000, 0x9000), Reserved);
+		map.insert(range(0x2000, 0x3000), Usable);
+		
+		assert_eq!(map.regions(), &[
+			region(0x1000, 0x4000, Usable),
+			region(0x8000, 0x9000, Reserved),
+		]);
+	}
+	
+	#[test]
+	fn insert_keeps_kinds_apart() {
+		let mut map = empty_map();
+		map.insert(range(0x1000, 0x2000), Usable);
+		map.insert(range(0x2000, 0x3000), BootServices);
+		map.insert(range(0x3000, 0x4000), Loader);
+		
+		assert_eq!(map.regions(), &[
+			region(0x1000, 0x2000, Usable),
+			region(0x2000, 0x3000, BootServices),
+			region(0x3000, 0x4000, Loader),
+		]);
+		assert_eq!(map.total_size(Usable), 0x1000);
+	}
+	
+	#[test]
+	fn insert_fills_capacity() {
+		let mut map = empty_map();
+		// Alternating kinds so no two regions merge
+		for i in 0..MAX_MEM_REGIONS {
+			let kind = if i % 2 == 0 { Usable } else { Reserved };
+			map.insert(range(i << 12, (i + 1) << 12), kind);
+		}
+		assert_eq!(map.regions().len(), MAX_MEM_REGIONS);
+		
+		// Merging still works on a full map
+		map.insert(range(MAX_MEM_REGIONS << 12, (MAX_MEM_REGIONS + 1) << 12), Reserved);
+		assert_eq!(map.regions().len(), MAX_MEM_REGIONS);
+	}
+	
+	#[test]
+	#[should_panic(expected = "Too many memory map regions")]
+	fn insert_over_capacity() {
+		let mut map = empty_map();
+		for i in 0..=MAX_MEM_REGIONS {
+			map.insert(range(i << 13, (i << 13) + 0x1000), Usable);
+		}
+	}
+	
+	#[test]
+	fn region_of_boundaries() {
+		let mut map = empty_map();
+		map.insert(range(0x1000, 0x3000), Usable);
+		map.insert(range(0x3000, 0x4000), Reserved);
+		map.insert(range(0x8000, 0x9000), Loader);
+		
+		let kind_of = |addr| map.region_of(PhysAddr::new(addr)).map(|region| region.kind);
+		assert_eq!(kind_of(0x0), None);
+		assert_eq!(kind_of(0xfff), None);
+		assert_eq!(kind_of(0x1000), Some(Usable));
+		assert_eq!(kind_of(0x2fff), Some(Usable));
+		assert_eq!(kind_of(0x3000), Some(Reserved));
+		assert_eq!(kind_of(0x3fff), Some(Reserved));
+		assert_eq!(kind_of(0x4000), None);
+		assert_eq!(kind_of(0x7fff), None);
+		assert_eq!(kind_of(0x8000), Some(Loader));
+		assert_eq!(kind_of(0x8fff), Some(Loader));
+		assert_eq!(kind_of(0x9000), None);
+	}
+}
@@ -1 +1,3 @@
 pub mod boot_alloc;
+pub mod boot_opts;
+pub mod mmap;